@@ -0,0 +1,103 @@
+//! The checks behind the fuzz targets. They are kept apart from libfuzzer so that the chip9 test
+//! suite can replay the corpus on a stable compiler (see tests/fuzz_corpus.rs).
+
+use chip9::state::StateWriter;
+use chip9::{Machine, Quirks, Random, Registers, StepOutcome, Timing};
+
+/// Every run is cut off after this many steps so each input finishes quickly
+const MAX_STEPS: usize = 2000;
+
+/// The number of steps between key changes, a frame at the default instruction rate
+const KEY_INTERVAL: usize = 10;
+
+const PROFILES: [fn() -> Quirks; 5] = [Quirks::cosmac_vip, Quirks::chip48, Quirks::super_chip, Quirks::xo_chip, Quirks::modern];
+
+/// Run an arbitrary ROM with an arbitrary key sequence through Machine::step, checking the
+/// machine invariants after every step and that the final state survives a save state round
+/// trip. Panics if anything is wrong. An input is laid out as:
+///
+/// - one byte of flags: the low three bits pick the quirks profile, bit 3 selects COSMAC VIP
+///   timing and bit 4 the VIP random number generator
+/// - one byte giving the number of key bytes that follow
+/// - the key bytes, applied one every KEY_INTERVAL steps: the low nibble is the key and bit 4 is
+///   set to press it or clear to release it
+/// - the rest of the input, which is loaded as the ROM at 0x200
+pub fn machine_step(data: &[u8]) {
+    let (flags, key_count, rest) = match data {
+        [flags, key_count, rest @ ..] => (*flags, *key_count as usize, rest),
+        _ => return,
+    };
+    let (keys, rom) = rest.split_at(key_count.min(rest.len()));
+
+    let quirks = PROFILES[(flags & 0x7) as usize % PROFILES.len()]();
+    let rng = if flags & 0x10 != 0 { Random::vip(0) } else { Random::new(0) };
+    let mut machine = Machine::of_bytes_with_random(rom.to_vec(), quirks, rng);
+    if flags & 0x8 != 0 {
+        machine.timing = Timing::CosmacVip;
+    }
+
+    for step in 0..MAX_STEPS {
+        if step % KEY_INTERVAL == 0 {
+            if let Some(key) = keys.get(step / KEY_INTERVAL) {
+                machine.set_key(key & 0xF, key & 0x10 != 0);
+            }
+        }
+
+        // Faults are expected from arbitrary ROMs, only panics and broken invariants are bugs
+        let outcome = machine.step();
+
+        if let Err(message) = machine.check_invariants() {
+            panic!("invariant broken at step {}: {}", step, message);
+        }
+
+        if matches!(outcome, Ok(StepOutcome::Halted) | Err(_)) {
+            break;
+        }
+    }
+
+    let state = machine.save_state();
+    // Timing is a setting rather than part of the state, and decides how long a loaded frame is
+    let mut restored = Machine::new();
+    restored.timing = machine.timing;
+    restored.load_state(&state).expect("save state does not load");
+    assert!(restored.save_state() == state, "save state does not round trip");
+}
+
+/// Load a corrupted save state, checking that anything that loads leaves a machine whose
+/// invariants hold and that runs without panicking. Panics if anything is wrong.
+///
+/// A random input would almost never get past the checksum, so the input is spliced into the
+/// payload of a valid state, which is then given a fresh header. The start of the input replaces
+/// the registers at the front of the payload and anything after that replaces the end of the
+/// payload (the display, the plane mask, the frame time and the frame length).
+pub fn load_state(data: &[u8]) {
+    let header = StateWriter::new().finish().len();
+    let mut registers = StateWriter::new();
+    Registers::new().save_state(&mut registers);
+    let registers = registers.finish().len() - header;
+
+    let mut payload = Machine::new().save_state().split_off(header);
+    let (front, back) = data.split_at(data.len().min(registers));
+    let back = &back[back.len().saturating_sub(payload.len() - registers)..];
+    payload[..front.len()].copy_from_slice(front);
+    let end = payload.len();
+    payload[end - back.len()..].copy_from_slice(back);
+
+    let mut state = StateWriter::new();
+    state.bytes(&payload);
+
+    let mut machine = Machine::new();
+    if machine.load_state(&state.finish()).is_err() {
+        return;
+    }
+
+    if let Err(message) = machine.check_invariants() {
+        panic!("invariant broken by a loaded state: {}", message);
+    }
+
+    for _ in 0..10 {
+        if machine.run_frame().is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,948 @@
+use crate::memory::MEMORY_SIZE;
+use crate::opcode::Opcode;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+
+/// Programs are assembled to run from the usual start address
+pub const PROGRAM_START: u16 = 0x200;
+
+/// Macros can invoke other macros, but a macro that keeps expanding forever is an error
+const MAX_MACRO_EXPANSIONS: usize = 10000;
+
+/// An error in the source, with the 1-based line and column of the token it was found at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error<T>(&self, message: String) -> Result<T, AsmError> {
+        Err(AsmError { line: self.line, column: self.column, message })
+    }
+}
+
+/// Split the source into whitespace separated tokens, dropping # comments
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+
+    for (line_idx, line) in source.lines().enumerate() {
+        let mut start = None;
+
+        for (column, c) in line.chars().chain(std::iter::once(' ')).enumerate() {
+            if c == '#' && start.is_none() {
+                break;
+            }
+
+            match (c.is_whitespace(), start) {
+                (false, None) => start = Some(column),
+                (true, Some(token_start)) => {
+                    tokens.push_back(Token {
+                        text: line.chars().skip(token_start).take(column - token_start).collect(),
+                        line: line_idx + 1,
+                        column: token_start + 1,
+                    });
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    tokens
+}
+
+/// Parse a decimal, 0x hex or 0b binary literal with an optional minus sign
+fn parse_number(text: &str) -> Option<i32> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i32::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+/// Parse a register name v0 to vf
+fn parse_register(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+    if digit.len() == 1 { u8::from_str_radix(digit, 16).ok() } else { None }
+}
+
+/// The comparison in a condition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    KeyPressed,
+    KeyNotPressed,
+}
+
+/// The right hand side of a comparison
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    Register(u8),
+    Byte(u8),
+    None,
+}
+
+/// A condition of an if or while, such as `v0 == 5`, `v1 != v2` or `v3 key`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Condition {
+    register: u8,
+    comparison: Comparison,
+    operand: Operand,
+}
+
+impl Condition {
+    fn negate(self) -> Self {
+        let comparison = match self.comparison {
+            Comparison::Equal => Comparison::NotEqual,
+            Comparison::NotEqual => Comparison::Equal,
+            Comparison::KeyPressed => Comparison::KeyNotPressed,
+            Comparison::KeyNotPressed => Comparison::KeyPressed,
+        };
+
+        Self { comparison, ..self }
+    }
+
+    /// The instruction that skips the next instruction if the condition holds
+    fn skip_opcode(self) -> Opcode {
+        let x = self.register;
+
+        match (self.comparison, self.operand) {
+            (Comparison::Equal, Operand::Byte(n)) => Opcode::SeImm(x, n),
+            (Comparison::NotEqual, Operand::Byte(n)) => Opcode::SneImm(x, n),
+            (Comparison::Equal, Operand::Register(y)) => Opcode::SeReg(x, y),
+            (Comparison::NotEqual, Operand::Register(y)) => Opcode::SneReg(x, y),
+            (Comparison::KeyPressed, _) => Opcode::Skp(x),
+            (Comparison::KeyNotPressed, _) => Opcode::Sknp(x),
+            _ => unreachable!("comparisons are parsed with a matching operand"),
+        }
+    }
+}
+
+/// How a reference to a label that has not been defined yet is patched once it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FixupKind {
+    /// The low 12 bits of the opcode at the address
+    Address,
+    /// The 16 bit word at the address (the operand of i := long)
+    Long,
+}
+
+struct Fixup {
+    addr: u16,
+    kind: FixupKind,
+    name: Token,
+}
+
+/// An open control structure, closed by again or end
+enum Block {
+    /// A loop started at start. The jumps out of it made by while are patched at again.
+    Loop { token: Token, start: u16, breaks: Vec<u16> },
+    /// An if ... begin block. The jump at jump is patched to skip the block at else or end.
+    If { token: Token, jump: u16, seen_else: bool },
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    rom: Vec<u8>,
+    pc: usize,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, i32>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+    expansions: usize,
+    /// The last token read, used to report errors at the end of the source
+    last: Token,
+}
+
+/// Assemble Octo source into a ROM to be loaded at 0x200 (for example with
+/// `Machine::of_bytes`). If the program defines a `main` label anywhere other than the start, a
+/// jump to it is placed at 0x200, as Octo does.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let rom = Assembler::new(source, false).run()?;
+
+    match rom.main {
+        Some(main) if main != PROGRAM_START => Ok(Assembler::new(source, true).run()?.rom),
+        _ => Ok(rom.rom),
+    }
+}
+
+/// The result of a single pass over the source
+struct Assembled {
+    rom: Vec<u8>,
+    main: Option<u16>,
+}
+
+impl Assembler {
+
+    fn new(source: &str, jump_to_main: bool) -> Self {
+        let mut assembler = Self {
+            tokens: tokenize(source),
+            rom: Vec::new(),
+            pc: PROGRAM_START as usize,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            expansions: 0,
+            last: Token { text: String::new(), line: 1, column: 1 },
+        };
+
+        if jump_to_main {
+            let main = Token { text: "main".to_string(), line: 1, column: 1 };
+            assembler.fixups.push(Fixup { addr: PROGRAM_START, kind: FixupKind::Address, name: main });
+            assembler.emit(Opcode::Jp(0)).unwrap();
+        }
+
+        assembler
+    }
+
+    fn run(mut self) -> Result<Assembled, AsmError> {
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+
+        if let Some(block) = self.blocks.last() {
+            return match block {
+                Block::Loop { token, .. } => token.error("loop without again".to_string()),
+                Block::If { token, .. } => token.error("begin without end".to_string()),
+            };
+        }
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            let target = match self.labels.get(&fixup.name.text) {
+                Some(target) => *target,
+                None => return fixup.name.error(format!("undefined name '{}'", fixup.name.text)),
+            };
+
+            let idx = (fixup.addr - PROGRAM_START) as usize;
+            match fixup.kind {
+                FixupKind::Address => {
+                    if target > 0xFFF {
+                        return fixup.name.error(format!("'{}' is out of the 12-bit address range", fixup.name.text));
+                    }
+                    self.rom[idx] |= (target >> 8) as u8;
+                    self.rom[idx + 1] = target as u8;
+                }
+                FixupKind::Long => {
+                    self.rom[idx] = (target >> 8) as u8;
+                    self.rom[idx + 1] = target as u8;
+                }
+            }
+        }
+
+        Ok(Assembled { main: self.labels.get("main").copied(), rom: self.rom })
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.last = token.clone();
+                Ok(token)
+            }
+            None => self.last.error("unexpected end of source".to_string()),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if token.text != text {
+            return token.error(format!("expected '{}' but found '{}'", text, token.text));
+        }
+        Ok(token)
+    }
+
+    fn emit8(&mut self, byte: u8) -> Result<(), AsmError> {
+        if self.pc >= MEMORY_SIZE {
+            return self.last.error("program does not fit in memory".to_string());
+        }
+
+        let idx = self.pc - PROGRAM_START as usize;
+        if idx >= self.rom.len() {
+            self.rom.resize(idx + 1, 0);
+        }
+
+        self.rom[idx] = byte;
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn emit16(&mut self, word: u16) -> Result<(), AsmError> {
+        self.emit8((word >> 8) as u8)?;
+        self.emit8(word as u8)
+    }
+
+    fn emit(&mut self, opcode: Opcode) -> Result<(), AsmError> {
+        self.emit16(opcode.encode())
+    }
+
+    /// Patch the jump at addr to continue at the current address
+    fn patch_jump(&mut self, addr: u16, token: &Token) -> Result<(), AsmError> {
+        if self.pc > 0xFFF {
+            return token.error("jump target is out of the 12-bit address range".to_string());
+        }
+
+        let jump = Opcode::Jp(self.pc as u16).encode();
+        let idx = (addr - PROGRAM_START) as usize;
+        self.rom[idx] = (jump >> 8) as u8;
+        self.rom[idx + 1] = jump as u8;
+        Ok(())
+    }
+
+    fn is_name(text: &str) -> bool {
+        text.chars().next().map(|c| c.is_alphabetic() || c == '_').unwrap_or(false)
+            && parse_register(text).is_none()
+    }
+
+    fn define(&mut self, name: &Token) -> Result<(), AsmError> {
+        if !Self::is_name(&name.text) {
+            return name.error(format!("'{}' is not a valid name", name.text));
+        }
+
+        if self.labels.contains_key(&name.text) || self.constants.contains_key(&name.text) || self.aliases.contains_key(&name.text) {
+            return name.error(format!("'{}' is already defined", name.text));
+        }
+
+        Ok(())
+    }
+
+    /// A number literal or constant
+    fn number(&mut self, token: &Token) -> Result<i32, AsmError> {
+        if let Some(value) = parse_number(&token.text) {
+            return Ok(value);
+        }
+
+        if let Some(value) = self.constants.get(&token.text) {
+            return Ok(*value);
+        }
+
+        match self.labels.get(&token.text) {
+            Some(addr) => Ok(*addr as i32),
+            None => token.error(format!("expected a number but found '{}'", token.text)),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, AsmError> {
+        let token = self.next()?;
+        let value = self.number(&token)?;
+
+        if !(-128..=255).contains(&value) {
+            return token.error(format!("{} does not fit in a byte", value));
+        }
+
+        Ok(value as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AsmError> {
+        let token = self.next()?;
+        let value = self.number(&token)?;
+
+        if !(0..=15).contains(&value) {
+            return token.error(format!("{} does not fit in a nibble", value));
+        }
+
+        Ok(value as u8)
+    }
+
+    fn register_of(&self, token: &Token) -> Option<u8> {
+        parse_register(&token.text).or_else(|| self.aliases.get(&token.text).copied())
+    }
+
+    fn register(&mut self) -> Result<u8, AsmError> {
+        let token = self.next()?;
+        match self.register_of(&token) {
+            Some(register) => Ok(register),
+            None => token.error(format!("expected a register but found '{}'", token.text)),
+        }
+    }
+
+    /// An address operand. References to labels that are not defined yet are patched at the end.
+    fn address(&mut self, kind: FixupKind) -> Result<u16, AsmError> {
+        let token = self.next()?;
+
+        if parse_number(&token.text).is_none() && !self.labels.contains_key(&token.text) && !self.constants.contains_key(&token.text) {
+            if !Self::is_name(&token.text) {
+                return token.error(format!("expected an address but found '{}'", token.text));
+            }
+
+            self.fixups.push(Fixup { addr: self.pc as u16, kind, name: token });
+            return Ok(0);
+        }
+
+        let value = self.number(&token)?;
+        let max = if kind == FixupKind::Long { 0xFFFF } else { 0xFFF };
+
+        if !(0..=max).contains(&value) {
+            return token.error(format!("{:#x} is out of the address range", value));
+        }
+
+        Ok(value as u16)
+    }
+
+    fn condition(&mut self) -> Result<Condition, AsmError> {
+        let register = self.register()?;
+        let token = self.next()?;
+
+        let (comparison, operand) = match token.text.as_str() {
+            "key" => (Comparison::KeyPressed, Operand::None),
+            "-key" => (Comparison::KeyNotPressed, Operand::None),
+            "==" | "!=" => {
+                let comparison = if token.text == "==" { Comparison::Equal } else { Comparison::NotEqual };
+                let operand_token = self.tokens.front().cloned();
+                match operand_token.as_ref().and_then(|operand| self.register_of(operand)) {
+                    Some(other) => {
+                        self.next()?;
+                        (comparison, Operand::Register(other))
+                    }
+                    None => (comparison, Operand::Byte(self.byte()?)),
+                }
+            }
+            _ => return token.error(format!("unsupported comparison '{}'", token.text)),
+        };
+
+        Ok(Condition { register, comparison, operand })
+    }
+
+    fn statement(&mut self) -> Result<(), AsmError> {
+        let token = self.next()?;
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.next()?;
+                self.define(&name)?;
+                self.labels.insert(name.text, self.pc as u16);
+            }
+            ":const" => {
+                let name = self.next()?;
+                self.define(&name)?;
+                let value_token = self.next()?;
+                let value = self.number(&value_token)?;
+                self.constants.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                if !Self::is_name(&name.text) || self.labels.contains_key(&name.text) || self.constants.contains_key(&name.text) {
+                    return name.error(format!("'{}' cannot be used as an alias", name.text));
+                }
+                let register = self.register()?;
+                self.aliases.insert(name.text, register);
+            }
+            ":macro" => self.define_macro()?,
+            ":org" => {
+                let addr_token = self.next()?;
+                let addr = self.number(&addr_token)?;
+                if !(PROGRAM_START as i32..MEMORY_SIZE as i32).contains(&addr) {
+                    return addr_token.error(format!("cannot place code at {:#x}", addr));
+                }
+                self.pc = addr as usize;
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit8(byte)?;
+            }
+            ":call" => {
+                let addr = self.address(FixupKind::Address)?;
+                self.emit(Opcode::Call(addr))?;
+            }
+            "clear" => self.emit(Opcode::Cls)?,
+            "return" | ";" => self.emit(Opcode::Ret)?,
+            "exit" => self.emit(Opcode::Exit)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(Opcode::ScrollDown(n))?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(Opcode::ScrollUp(n))?;
+            }
+            "scroll-right" => self.emit(Opcode::ScrollRight)?,
+            "scroll-left" => self.emit(Opcode::ScrollLeft)?,
+            "lores" => self.emit(Opcode::Lores)?,
+            "hires" => self.emit(Opcode::Hires)?,
+            "native" => {
+                let addr = self.address(FixupKind::Address)?;
+                self.emit(Opcode::Sys(addr))?;
+            }
+            "jump" => {
+                let addr = self.address(FixupKind::Address)?;
+                self.emit(Opcode::Jp(addr))?;
+            }
+            "jump0" => {
+                let addr = self.address(FixupKind::Address)?;
+                self.emit(Opcode::JpV0(addr))?;
+            }
+            "loop" => {
+                self.blocks.push(Block::Loop { token, start: self.pc as u16, breaks: Vec::new() });
+            }
+            "while" => {
+                let condition = self.condition()?;
+                let jump = self.pc as u16 + 2;
+                match self.blocks.iter_mut().rev().find(|block| matches!(block, Block::Loop { .. })) {
+                    Some(Block::Loop { breaks, .. }) => breaks.push(jump),
+                    _ => return token.error("while outside of a loop".to_string()),
+                }
+                self.emit(condition.skip_opcode())?;
+                self.emit(Opcode::Jp(0))?;
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, breaks, .. }) => {
+                    if start > 0xFFF {
+                        return token.error("loop is out of the 12-bit address range".to_string());
+                    }
+                    self.emit(Opcode::Jp(start))?;
+                    for jump in breaks {
+                        self.patch_jump(jump, &token)?;
+                    }
+                }
+                _ => return token.error("again without loop".to_string()),
+            },
+            "if" => {
+                let condition = self.condition()?;
+                let form = self.next()?;
+                match form.text.as_str() {
+                    "then" => self.emit(condition.negate().skip_opcode())?,
+                    "begin" => {
+                        self.emit(condition.skip_opcode())?;
+                        self.blocks.push(Block::If { token, jump: self.pc as u16, seen_else: false });
+                        self.emit(Opcode::Jp(0))?;
+                    }
+                    _ => return form.error(format!("expected 'then' or 'begin' but found '{}'", form.text)),
+                }
+            }
+            "else" => {
+                let jump = match self.blocks.last() {
+                    Some(Block::If { jump, seen_else: false, .. }) => *jump,
+                    _ => return token.error("else without if ... begin".to_string()),
+                };
+                let end_jump = self.pc as u16;
+                self.emit(Opcode::Jp(0))?;
+                self.patch_jump(jump, &token)?;
+                self.blocks.pop();
+                self.blocks.push(Block::If { token, jump: end_jump, seen_else: true });
+            }
+            "end" => match self.blocks.pop() {
+                Some(Block::If { jump, .. }) => self.patch_jump(jump, &token)?,
+                _ => return token.error("end without if ... begin".to_string()),
+            },
+            "i" => self.index_statement()?,
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let op = match token.text.as_str() {
+                    "delay" => Opcode::LdDtVx(x),
+                    "buzzer" => Opcode::LdStVx(x),
+                    _ => Opcode::Pitch(x),
+                };
+                self.emit(op)?;
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(Opcode::Bcd(x))?;
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    let op = if token.text == "save" { Opcode::SaveRange(x, y) } else { Opcode::LoadRange(x, y) };
+                    self.emit(op)?;
+                } else {
+                    let op = if token.text == "save" { Opcode::Save(x) } else { Opcode::Load(x) };
+                    self.emit(op)?;
+                }
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.emit(Opcode::SaveFlags(x))?;
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.emit(Opcode::LoadFlags(x))?;
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.emit(Opcode::Drw(x, y, n))?;
+            }
+            "plane" => {
+                let n = self.nibble()?;
+                self.emit(Opcode::Plane(n))?;
+            }
+            "audio" => self.emit(Opcode::Audio)?,
+            _ => {
+                if let Some(x) = self.register_of(&token) {
+                    return self.register_statement(x);
+                }
+
+                if self.macros.contains_key(&token.text) {
+                    return self.expand_macro(&token);
+                }
+
+                if let Some(value) = parse_number(&token.text).or_else(|| self.constants.get(&token.text).copied()) {
+                    if !(-128..=255).contains(&value) {
+                        return token.error(format!("{} does not fit in a byte", value));
+                    }
+                    return self.emit8(value as u8);
+                }
+
+                if token.text.starts_with(':') || !Self::is_name(&token.text) {
+                    return token.error(format!("unsupported statement '{}'", token.text));
+                }
+
+                // Anything else is a call to a subroutine
+                self.tokens.push_front(token);
+                let addr = self.address(FixupKind::Address)?;
+                self.emit(Opcode::Call(addr))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn index_statement(&mut self) -> Result<(), AsmError> {
+        let op = self.next()?;
+
+        match op.text.as_str() {
+            ":=" => match self.peek() {
+                Some("long") => {
+                    self.next()?;
+                    self.emit(Opcode::LdILong)?;
+                    let addr = self.address(FixupKind::Long)?;
+                    self.emit16(addr)?;
+                }
+                Some("hex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.emit(Opcode::LdF(x))?;
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.emit(Opcode::LdHf(x))?;
+                }
+                _ => {
+                    let addr = self.address(FixupKind::Address)?;
+                    self.emit(Opcode::LdI(addr))?;
+                }
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.emit(Opcode::AddI(x))?;
+            }
+            _ => return op.error(format!("unsupported operation on i '{}'", op.text)),
+        }
+
+        Ok(())
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), AsmError> {
+        let op = self.next()?;
+        let operand = self.tokens.front().cloned();
+        let y = operand.as_ref().and_then(|operand| self.register_of(operand));
+
+        // Operations between two registers
+        let math: Option<fn(u8, u8) -> Opcode> = match op.text.as_str() {
+            ":=" => Some(Opcode::LdReg),
+            "|=" => Some(Opcode::Or),
+            "&=" => Some(Opcode::And),
+            "^=" => Some(Opcode::Xor),
+            "+=" => Some(Opcode::AddReg),
+            "-=" => Some(Opcode::Sub),
+            ">>=" => Some(Opcode::Shr),
+            "=-" => Some(Opcode::Subn),
+            "<<=" => Some(Opcode::Shl),
+            _ => None,
+        };
+
+        if let (Some(math), Some(y)) = (math, y) {
+            self.next()?;
+            return self.emit(math(x, y));
+        }
+
+        match op.text.as_str() {
+            ":=" => match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    let mask = self.byte()?;
+                    self.emit(Opcode::Rnd(x, mask))
+                }
+                Some("delay") => {
+                    self.next()?;
+                    self.emit(Opcode::LdVxDt(x))
+                }
+                Some("key") => {
+                    self.next()?;
+                    self.emit(Opcode::LdVxK(x))
+                }
+                _ => {
+                    let n = self.byte()?;
+                    self.emit(Opcode::LdImm(x, n))
+                }
+            },
+            "+=" => {
+                let n = self.byte()?;
+                self.emit(Opcode::AddImm(x, n))
+            }
+            "-=" => {
+                let n = self.byte()?.wrapping_neg();
+                self.emit(Opcode::AddImm(x, n))
+            }
+            _ => op.error(format!("unsupported register operation '{}'", op.text)),
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), AsmError> {
+        let name = self.next()?;
+        self.define(&name)?;
+
+        let mut args = Vec::new();
+        loop {
+            let arg = self.next()?;
+            if arg.text == "{" {
+                break;
+            }
+            args.push(arg.text);
+        }
+
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            body.push(token);
+        }
+
+        self.macros.insert(name.text, Macro { args, body });
+        Ok(())
+    }
+
+    /// Replace a macro invocation with the body of the macro, substituting the arguments
+    fn expand_macro(&mut self, name: &Token) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_MACRO_EXPANSIONS {
+            return name.error(format!("macro '{}' expands forever", name.text));
+        }
+
+        let arg_count = self.macros[&name.text].args.len();
+        let mut values = Vec::new();
+        for _ in 0..arg_count {
+            values.push(self.next()?.text);
+        }
+
+        let definition = &self.macros[&name.text];
+        let expanded: Vec<Token> = definition
+            .body
+            .iter()
+            .map(|token| {
+                let text = match definition.args.iter().position(|arg| *arg == token.text) {
+                    Some(idx) => values[idx].clone(),
+                    None => token.text.clone(),
+                };
+                Token { text, ..token.clone() }
+            })
+            .collect();
+
+        for token in expanded.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::LONG_LOAD_OPCODE;
+    use crate::machine::Machine;
+    use crate::opcode::disassemble;
+
+    fn assert_assembles(source: &str, expected: &[u8]) {
+        assert_eq!(assemble(source), Ok(expected.to_vec()));
+    }
+
+    fn assert_error(source: &str, line: usize, column: usize, message: &str) {
+        assert_eq!(assemble(source), Err(AsmError { line, column, message: message.to_string() }));
+    }
+
+    #[test]
+    fn instructions() {
+        assert_assembles("clear return exit hires lores scroll-down 3 scroll-left", &[0x00, 0xE0, 0x00, 0xEE, 0x00, 0xFD, 0x00, 0xFF, 0x00, 0xFE, 0x00, 0xC3, 0x00, 0xFC]);
+        assert_assembles("v1 := 0x2a v1 += 1 v1 -= 1 v2 := v1 v2 += v3 v2 -= v3 v2 =- v3 v2 >>= v2 v2 <<= v2", &[
+            0x61, 0x2A, 0x71, 0x01, 0x71, 0xFF, 0x82, 0x10, 0x82, 0x34, 0x82, 0x35, 0x82, 0x37, 0x82, 0x26, 0x82, 0x2E,
+        ]);
+        assert_assembles("v0 |= v1 v0 &= v1 v0 ^= v1 vf := random 0b1111 va := delay vb := key", &[
+            0x80, 0x11, 0x80, 0x12, 0x80, 0x13, 0xCF, 0x0F, 0xFA, 0x07, 0xFB, 0x0A,
+        ]);
+        assert_assembles("i := 0x300 i += v1 i := hex v2 i := bighex v3 i := long 0x1234", &[
+            0xA3, 0x00, 0xF1, 0x1E, 0xF2, 0x29, 0xF3, 0x30, 0xF0, 0x00, 0x12, 0x34,
+        ]);
+        assert_assembles("delay := v1 buzzer := v2 pitch := v3 bcd v4 save v5 load v6 save v1 - v2 load v3 - v4", &[
+            0xF1, 0x15, 0xF2, 0x18, 0xF3, 0x3A, 0xF4, 0x33, 0xF5, 0x55, 0xF6, 0x65, 0x51, 0x22, 0x53, 0x43,
+        ]);
+        assert_assembles("saveflags v7 loadflags v7 sprite v1 v2 15 plane 3 audio jump0 0x210 native 0x123", &[
+            0xF7, 0x75, 0xF7, 0x85, 0xD1, 0x2F, 0xF3, 0x01, 0xF0, 0x02, 0xB2, 0x10, 0x01, 0x23,
+        ]);
+    }
+
+    #[test]
+    fn labels_and_data() {
+        let source = "
+            # draw a sprite
+            : main
+                i := smile
+                sprite v0 v0 3
+                draw-more
+                jump main
+            : draw-more ;
+            : smile 0x3C 0b01000010 255
+        ";
+        assert_assembles(source, &[0xA2, 0x0A, 0xD0, 0x03, 0x22, 0x08, 0x12, 0x00, 0x00, 0xEE, 0x3C, 0x42, 0xFF]);
+    }
+
+    #[test]
+    fn jump_to_main() {
+        assert_assembles(": data 1 2 : main jump main", &[0x12, 0x04, 0x01, 0x02, 0x12, 0x04]);
+    }
+
+    #[test]
+    fn const_alias_and_macros() {
+        let source = "
+            :const SPEED 3
+            :alias x v4
+            :macro move reg amount { reg += amount }
+            x := SPEED
+            move x SPEED
+            :byte -1
+        ";
+        assert_assembles(source, &[0x64, 0x03, 0x74, 0x03, 0xFF]);
+    }
+
+    #[test]
+    fn control_flow() {
+        let source = "
+            loop
+                if v0 == 5 then v1 := 1
+                if v0 != v2 then v1 := 2
+                if v3 key then v1 := 3
+                while v0 != 10
+                v0 += 1
+            again
+        ";
+        assert_assembles(source, &[
+            0x40, 0x05, 0x61, 0x01, // if v0 == 5 then
+            0x50, 0x20, 0x61, 0x02, // if v0 != v2 then
+            0xE3, 0xA1, 0x61, 0x03, // if v3 key then
+            0x40, 0x0A, 0x12, 0x14, // while v0 != 10
+            0x70, 0x01, 0x12, 0x00, // v0 += 1 again
+        ]);
+        assert_eq!(assemble("loop while v0 != 10 again").unwrap(), vec![0x40, 0x0A, 0x12, 0x06, 0x12, 0x00]);
+
+        let source = "
+            if v0 == 1 begin
+                v1 := 1
+            else
+                v1 := 2
+            end
+        ";
+        assert_assembles(source, &[0x30, 0x01, 0x12, 0x08, 0x61, 0x01, 0x12, 0x0A, 0x61, 0x02]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_error("v0 := 1\n  v1 := 300", 2, 9, "300 does not fit in a byte");
+        assert_error("jump nowhere", 1, 6, "undefined name 'nowhere'");
+        assert_error(": a\n: a", 2, 3, "'a' is already defined");
+        assert_error("  loop v0 += 1", 1, 3, "loop without again");
+        assert_error("again", 1, 1, "again without loop");
+        assert_error("v0 :=", 1, 4, "unexpected end of source");
+        assert_error("if v0 < 1 then", 1, 7, "unsupported comparison '<'");
+        assert_error("sprite v0 v1 16", 1, 14, "16 does not fit in a nibble");
+        assert_error(":macro forever { forever } forever", 1, 18, "macro 'forever' expands forever");
+        assert_error(":unpack 1 x", 1, 1, "unsupported statement ':unpack'");
+    }
+
+    #[test]
+    fn every_opcode_round_trips() {
+        for opcode in 0..=0xFFFF_u16 {
+            let mut text = disassemble(opcode);
+            let mut expected = opcode.to_be_bytes().to_vec();
+
+            // The long load is the only instruction that is followed by an operand
+            if opcode == LONG_LOAD_OPCODE {
+                text.push_str(" 0x1234");
+                expected.extend_from_slice(&[0x12, 0x34]);
+            }
+
+            assert_eq!(assemble(&text), Ok(expected), "{:04x} disassembled to '{}'", opcode, text);
+        }
+    }
+
+    #[test]
+    fn runs_on_the_machine() {
+        let source = "
+            : main
+                v0 := 0
+                loop
+                    v0 += 3
+                    if v0 != 12 then
+                again
+                exit
+        ";
+        let mut machine = Machine::of_bytes(assemble(source).unwrap());
+        while !machine.halted() {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.cpu.registers.v[0].0, 12);
+    }
+}
@@ -0,0 +1,209 @@
+use crate::cpu::NUM_KEYS;
+use crate::memory::Memory;
+
+/// A display backend is handed the frame buffer once per frame, after the CPU has run
+pub trait Display {
+    fn draw(&mut self, memory: &Memory);
+}
+
+/// An input backend supplies the state of the 16 key hex keypad, polled once at the start of
+/// every frame
+pub trait Input {
+    fn poll(&mut self) -> [bool; NUM_KEYS];
+}
+
+/// An audio backend is told once per frame whether the buzzer should be sounding
+pub trait Audio {
+    fn set_playing(&mut self, playing: bool);
+}
+
+/// Joins separate display, input and audio backends together so they can be mixed and matched.
+/// A backend that handles all three (like a terminal) can be passed to the machine directly.
+pub struct Backends<D, I, A> {
+    pub display: D,
+    pub input: I,
+    pub audio: A,
+}
+
+impl<D: Display, I, A> Display for Backends<D, I, A> {
+    fn draw(&mut self, memory: &Memory) {
+        self.display.draw(memory);
+    }
+}
+
+impl<D, I: Input, A> Input for Backends<D, I, A> {
+    fn poll(&mut self) -> [bool; NUM_KEYS] {
+        self.input.poll()
+    }
+}
+
+impl<D, I, A: Audio> Audio for Backends<D, I, A> {
+    fn set_playing(&mut self, playing: bool) {
+        self.audio.set_playing(playing);
+    }
+}
+
+/// A headless backend that discards the display and audio and never presses a key
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Headless;
+
+impl Display for Headless {
+    fn draw(&mut self, _memory: &Memory) {}
+}
+
+impl Input for Headless {
+    fn poll(&mut self) -> [bool; NUM_KEYS] {
+        [false; NUM_KEYS]
+    }
+}
+
+impl Audio for Headless {
+    fn set_playing(&mut self, _playing: bool) {}
+}
+
+/// A key changing state at the start of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub frame: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+/// An input backend that plays back a fixed list of key changes, one frame per poll
+#[derive(Debug, Clone, Default)]
+pub struct KeyScript {
+    events: Vec<KeyEvent>,
+    next: usize,
+    frame: u64,
+    keys: [bool; NUM_KEYS],
+}
+
+impl KeyScript {
+
+    /// Play back key changes given in frame order
+    pub fn new(events: Vec<KeyEvent>) -> Self {
+        Self { events, ..Self::default() }
+    }
+
+    /// Parse a script with one key change per line, written as the frame number, the key in hex
+    /// and `down` or `up`. Blank lines and anything after a # are ignored.
+    pub fn parse(script: &str) -> Result<Self, String> {
+        let mut events: Vec<KeyEvent> = Vec::new();
+
+        for (idx, line) in script.lines().enumerate() {
+            let error = |message: &str| format!("line {}: {}", idx + 1, message);
+            let fields: Vec<&str> = line.split('#').next().unwrap_or_default().split_whitespace().collect();
+
+            let (frame, key, state) = match fields.as_slice() {
+                [] => continue,
+                [frame, key, state] => (frame, key, state),
+                _ => return Err(error("expected a frame, a key and down or up")),
+            };
+
+            let frame = frame.parse().map_err(|_| error("bad frame"))?;
+            let key = u8::from_str_radix(key, 16).ok().filter(|key| (*key as usize) < NUM_KEYS).ok_or_else(|| error("bad key"))?;
+            let pressed = match *state {
+                "down" => true,
+                "up" => false,
+                _ => return Err(error("expected down or up")),
+            };
+
+            if events.last().is_some_and(|last| last.frame > frame) {
+                return Err(error("key changes are out of order"));
+            }
+
+            events.push(KeyEvent { frame, key, pressed });
+        }
+
+        Ok(Self::new(events))
+    }
+}
+
+impl Input for KeyScript {
+    fn poll(&mut self) -> [bool; NUM_KEYS] {
+        while let Some(event) = self.events.get(self.next).filter(|event| event.frame <= self.frame) {
+            self.keys[event.key as usize] = event.pressed;
+            self.next += 1;
+        }
+
+        self.frame += 1;
+        self.keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::StepOutcome;
+    use crate::machine::Machine;
+
+    /// Records everything the machine hands to it
+    #[derive(Default)]
+    struct Recorder {
+        frames: usize,
+        lit_pixels: usize,
+        playing: Vec<bool>,
+    }
+
+    impl Display for Recorder {
+        fn draw(&mut self, memory: &Memory) {
+            self.frames += 1;
+            self.lit_pixels = memory.frame_buffer.iter().filter(|x| **x != 0).count();
+        }
+    }
+
+    impl Audio for Recorder {
+        fn set_playing(&mut self, playing: bool) {
+            self.playing.push(playing);
+        }
+    }
+
+    struct HoldKey(usize);
+
+    impl Input for HoldKey {
+        fn poll(&mut self) -> [bool; NUM_KEYS] {
+            let mut keys = [false; NUM_KEYS];
+            keys[self.0] = true;
+            keys
+        }
+    }
+
+    #[test]
+    fn update_drives_backends() {
+        // v0 := 5, sound := v0, i := sprite(v0), draw v0 v0 5, skip if key v0 not pressed, exit
+        let program = vec![
+            0x60, 0x05, 0xF0, 0x18, 0xF0, 0x29, 0xD0, 0x05, 0xE0, 0xA1, 0x00, 0xFD,
+        ];
+        let mut machine = Machine::of_bytes(program);
+        let mut backends = Backends {
+            display: Recorder::default(),
+            input: HoldKey(5),
+            audio: Recorder::default(),
+        };
+
+        assert_eq!(machine.update(&mut backends), Ok(StepOutcome::Halted));
+        assert_eq!(backends.display.frames, 1);
+        assert!(backends.display.lit_pixels > 0);
+        assert_eq!(backends.audio.playing, vec![true]);
+        assert!(machine.cpu.registers.keys[5]);
+    }
+
+    #[test]
+    fn key_script() {
+        let mut script = KeyScript::parse("# press 5 for two frames\n1 5 down\n\n3 5 up # release\n3 a down\n").unwrap();
+        let pressed: Vec<Vec<usize>> = (0..4).map(|_| {
+            script.poll().iter().enumerate().filter(|(_, pressed)| **pressed).map(|(key, _)| key).collect()
+        }).collect();
+        assert_eq!(pressed, vec![vec![], vec![5], vec![5], vec![10]]);
+
+        assert_eq!(KeyScript::parse("1 g down").unwrap_err(), "line 1: bad key");
+        assert_eq!(KeyScript::parse("5 1 down\n4 1 up").unwrap_err(), "line 2: key changes are out of order");
+    }
+
+    #[test]
+    fn headless() {
+        let mut machine = Machine::of_bytes(vec![0x12, 0x00]);
+        assert_eq!(machine.update(&mut Headless), Ok(StepOutcome::Executed));
+        assert!(!machine.cpu.registers.keys.iter().any(|key| *key));
+    }
+}
@@ -0,0 +1,30 @@
+use std::env::args;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::exit;
+use chip9::asm::assemble;
+
+/// Assemble an Octo source file into a ROM. The ROM is written next to the source with a .ch8
+/// extension unless an output path is given.
+fn main() -> io::Result<()> {
+    let mut args = args().skip(1);
+    let usage = "usage: chip9-asm source.8o [-o rom.ch8]";
+    let input = args.next().expect(usage);
+
+    let output = match (args.next().as_deref(), args.next()) {
+        (Some("-o"), Some(output)) => output,
+        (None, _) => Path::new(&input).with_extension("ch8").to_string_lossy().into_owned(),
+        _ => panic!("{}", usage),
+    };
+
+    let source = fs::read_to_string(&input)?;
+
+    match assemble(&source) {
+        Ok(rom) => fs::write(output, rom),
+        Err(err) => {
+            eprintln!("{}:{}", input, err);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use std::env::args;
+use std::fs;
+use std::io;
+use chip9::disasm::Disassembly;
+
+/// Print an annotated listing of the code reachable from the start of a ROM, or with --source
+/// print it as Octo source that chip9-asm assembles back to the same ROM
+fn main() -> io::Result<()> {
+    let mut source = false;
+    let mut filepath = None;
+
+    for arg in args().skip(1) {
+        match arg.as_str() {
+            "--source" => source = true,
+            _ => filepath = Some(arg),
+        }
+    }
+
+    let filepath = filepath.expect("usage: chip9-disasm [--source] rom.ch8");
+    let rom = fs::read(filepath)?;
+    let disassembly = Disassembly::new(&rom, 0x200);
+
+    if source {
+        print!("{}", disassembly.source());
+    } else {
+        print!("{}", disassembly);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,316 @@
+mod render;
+mod terminal;
+
+use std::io::{self, BufRead, Read, Write};
+use std::fs::{self, File};
+use std::env::args;
+use std::net::TcpListener;
+use std::process;
+use chip9::debugger::{Command, Debugger};
+use chip9::gdb::GdbServer;
+use chip9::machine::DEFAULT_IPF;
+use chip9::movie::{Movie, Recorder};
+use chip9::phosphor::Phosphor;
+use chip9::rewind::Rewind;
+use chip9::screenshot::{compare_golden, Screenshot};
+use chip9::{Backends, Display, ExecError, Headless, KeyScript, Machine, Quirks, Random, StepOutcome, Timing};
+use render::{parse_color, Palette, Renderer};
+use terminal::{SlotAction, Terminal};
+
+/// Rewind snapshots are taken every this many frames, ten times a second
+const REWIND_INTERVAL: u64 = 6;
+
+/// The number of frames a headless run lasts unless given, ten seconds of emulated time
+const DEFAULT_HEADLESS_FRAMES: u64 = 600;
+
+/// The size of each pixel in a PNG written by a headless run
+const PNG_SCALE: usize = 4;
+
+/// The default cap on the memory used by rewind snapshots, in megabytes
+const DEFAULT_REWIND_MB: usize = 16;
+
+fn from_file(path: &str) -> io::Result<Vec<u8>> {
+    let mut f = File::open(path)?;
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+fn main() -> io::Result<()> {
+    env_logger::init();
+
+    let mut quirks = Quirks::default();
+    let mut quirks_given = false;
+    let mut debug = false;
+    let mut gdb_port = None;
+    let mut seed = None;
+    let mut vip_random = false;
+    let mut record = None;
+    let mut play = None;
+    let mut rewind_mb = DEFAULT_REWIND_MB;
+    let mut renderer = Renderer::HalfBlock;
+    let mut palette = Palette::default();
+    let mut phosphor = None;
+    let mut ipf = DEFAULT_IPF;
+    let mut timing = Timing::Instructions;
+    let mut headless = false;
+    let mut frames = DEFAULT_HEADLESS_FRAMES;
+    let mut keys = None;
+    let mut output = None;
+    let mut expect = None;
+    let mut filepath = None;
+    let mut args = args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--quirks" => {
+                let name = args.next().unwrap_or_default();
+                quirks = Quirks::from_name(&name).unwrap_or_else(|| {
+                    panic!("unknown quirks profile {} (expected vip, chip48, schip, xochip or modern)", name)
+                });
+                quirks_given = true;
+            },
+            "--debug" => debug = true,
+            "--gdb" => {
+                let port = args.next().unwrap_or_default();
+                gdb_port = Some(port.parse::<u16>().unwrap_or_else(|_| panic!("bad gdb port {}", port)));
+            },
+            "--seed" => {
+                let value = args.next().unwrap_or_default();
+                seed = Some(value.parse::<u64>().unwrap_or_else(|_| panic!("bad seed {}", value)));
+            },
+            "--vip-random" => vip_random = true,
+            "--record" => record = args.next(),
+            "--play" => play = args.next(),
+            "--render" => {
+                let name = args.next().unwrap_or_default();
+                renderer = Renderer::from_name(&name).unwrap_or_else(|| {
+                    panic!("unknown renderer {} (expected half, braille or ascii)", name)
+                });
+            },
+            "--fg" | "--bg" => {
+                let name = args.next().unwrap_or_default();
+                let color = parse_color(&name).unwrap_or_else(|| panic!("bad color {} (expected a name or #rrggbb)", name));
+                palette.colors[if arg == "--fg" { 1 } else { 0 }] = color;
+            },
+            "--phosphor" => {
+                let value = args.next().unwrap_or_default();
+                let frames = value.parse::<u8>().unwrap_or_else(|_| panic!("bad phosphor decay {}", value));
+                phosphor = Some(Phosphor::new(frames));
+            },
+            "--ipf" => {
+                let value = args.next().unwrap_or_default();
+                ipf = value.parse::<usize>().ok().filter(|ipf| *ipf > 0).unwrap_or_else(|| panic!("bad instructions per frame {}", value));
+            },
+            "--vip-timing" => timing = Timing::CosmacVip,
+            "--headless" => headless = true,
+            "--frames" => {
+                let value = args.next().unwrap_or_default();
+                frames = value.parse::<u64>().unwrap_or_else(|_| panic!("bad frame count {}", value));
+            },
+            "--keys" => keys = args.next(),
+            "--output" => output = args.next(),
+            "--expect" => expect = args.next(),
+            "--rewind-mb" => {
+                let value = args.next().unwrap_or_default();
+                rewind_mb = value.parse::<usize>().unwrap_or_else(|_| panic!("bad rewind size {}", value));
+            },
+            _ => filepath = Some(arg),
+        }
+    }
+
+    let filepath = filepath.expect("usage: chip9 [--quirks vip|chip48|schip|xochip|modern] [--ipf n] [--vip-timing] [--headless [--frames n] [--keys script] [--output file] [--expect golden]] [--seed n] [--vip-random] [--record movie] [--play movie] [--rewind-mb n] [--render half|braille|ascii] [--fg color] [--bg color] [--phosphor frames] [--debug] [--gdb port] rom.ch8");
+    let data = from_file(&filepath)?;
+    let seed = seed.unwrap_or_else(rand::random);
+    let rng = if vip_random { Random::vip(seed) } else { Random::new(seed) };
+
+    if let Some(path) = play {
+        let movie = Movie::parse(&fs::read_to_string(path)?).map_err(io::Error::other)?;
+        if quirks_given && quirks != movie.quirks {
+            return Err(io::Error::other("--quirks differs from the quirks the movie was recorded with"));
+        }
+        movie.play(&data).map_err(io::Error::other)?;
+        println!("played {} frames with no desync", movie.frames);
+        return Ok(());
+    }
+
+    let mut machine = Machine::of_bytes_with_random(data.clone(), quirks, rng);
+    machine.ipf = ipf;
+    machine.timing = timing;
+
+    if headless {
+        let status = run_headless(&mut machine, frames, keys.as_deref(), output.as_deref(), expect.as_deref())?;
+        process::exit(status);
+    }
+
+    if debug {
+        return debug_repl(Debugger::new(machine));
+    }
+
+    if let Some(port) = gdb_port {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("waiting for gdb on {}", listener.local_addr()?);
+        return GdbServer::new(machine).serve(&listener);
+    }
+
+    let mut recorder = record.as_ref().map(|_| Recorder::new(&data, &machine));
+    let mut rewind = Rewind::new(REWIND_INTERVAL, rewind_mb * 1024 * 1024);
+    let result = run(&mut machine, &mut Terminal::new(renderer, palette, phosphor), &filepath, &mut recorder, &mut rewind);
+
+    // Keep the movie even if the program faulted, since that is what a bug report needs
+    if let (Some(path), Some(recorder)) = (record, recorder) {
+        fs::write(path, recorder.finish(&machine).to_string())?;
+    }
+
+    // The terminal has been dropped and restored, so a fault can now be reported
+    result.map_err(io::Error::other)
+}
+
+/// Run the machine in the terminal until the user quits, the program exits or the program faults.
+/// Save states are kept next to the ROM in one file per slot. Holding the rewind key steps back
+/// through recent snapshots. While a movie is being recorded loading a slot and rewinding are
+/// refused, since the movie could not be played back.
+fn run(machine: &mut Machine, terminal: &mut Terminal, filepath: &str, recorder: &mut Option<Recorder>, rewind: &mut Rewind) -> Result<(), ExecError> {
+    loop {
+        terminal.wait_frame();
+
+        if terminal.quit_requested() {
+            break;
+        }
+
+        match terminal.slot_requested() {
+            Some(SlotAction::Load(_)) if recorder.is_some() => {
+                terminal.show_status("cannot load a slot while recording".to_string());
+            }
+            Some(action) => {
+                let message = slot_action(machine, action, filepath);
+                terminal.show_status(message);
+            }
+            None => {}
+        }
+
+        if terminal.rewind_requested() {
+            if recorder.is_some() {
+                terminal.show_status("cannot rewind while recording".to_string());
+            } else {
+                match rewind.rewind(machine) {
+                    Ok(true) => {}
+                    Ok(false) => terminal.show_status("nothing left to rewind".to_string()),
+                    Err(err) => terminal.show_status(format!("cannot rewind: {}", err)),
+                }
+                terminal.draw(&machine.memory);
+                continue;
+            }
+        }
+
+        let outcome = match recorder {
+            Some(recorder) => recorder.update(machine, terminal)?,
+            None => machine.update(terminal)?,
+        };
+
+        rewind.frame(machine);
+
+        if outcome == StepOutcome::Halted {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the machine without a terminal for a number of frames, feeding it keys from a script, then
+/// write the display and compare it against a golden image. Returns the exit status: 0 if the run
+/// finished (or the program exited), 1 if the program faulted and 2 if the display did not match.
+fn run_headless(machine: &mut Machine, frames: u64, keys: Option<&str>, output: Option<&str>, expect: Option<&str>) -> io::Result<i32> {
+    let script = match keys {
+        Some(path) => KeyScript::parse(&fs::read_to_string(path)?).map_err(io::Error::other)?,
+        None => KeyScript::default(),
+    };
+    let mut backends = Backends { display: Headless, input: script, audio: Headless };
+
+    for _ in 0..frames {
+        match machine.update(&mut backends) {
+            Ok(StepOutcome::Halted) => break,
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("{}", err);
+                return Ok(1);
+            }
+        }
+    }
+
+    let screenshot = Screenshot::of_memory(&machine.memory);
+    match output {
+        Some(path) if path.ends_with(".png") => fs::write(path, screenshot.to_png(PNG_SCALE))?,
+        Some(path) if path.ends_with(".pbm") => fs::write(path, screenshot.to_pbm())?,
+        Some(path) => fs::write(path, screenshot.to_ascii())?,
+        None if expect.is_none() => print!("{}", screenshot.to_ascii()),
+        None => {}
+    }
+
+    if let Some(path) = expect {
+        if let Err(err) = compare_golden(&machine.memory, &fs::read(path)?) {
+            eprintln!("{}", err);
+            return Ok(2);
+        }
+    }
+
+    Ok(0)
+}
+
+/// Save or load a save state slot, returning a message describing what happened
+fn slot_action(machine: &mut Machine, action: SlotAction, filepath: &str) -> String {
+    match action {
+        SlotAction::Save(slot) => match fs::write(slot_path(filepath, slot), machine.save_state()) {
+            Ok(()) => format!("saved slot {}", slot),
+            Err(err) => format!("could not save slot {}: {}", slot, err),
+        },
+        SlotAction::Load(slot) => match fs::read(slot_path(filepath, slot)) {
+            Ok(state) => match machine.load_state(&state) {
+                Ok(()) => format!("loaded slot {}", slot),
+                Err(err) => format!("could not load slot {}: {}", slot, err),
+            },
+            Err(err) => format!("could not load slot {}: {}", slot, err),
+        },
+    }
+}
+
+/// The file a save state slot is kept in
+fn slot_path(filepath: &str, slot: u8) -> String {
+    format!("{}.state{}", filepath, slot)
+}
+
+/// Run the debugger REPL on stdin and stdout until the user quits or stdin is closed
+fn debug_repl(mut debugger: Debugger) -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut last_command = None;
+
+    loop {
+        print!("(chip9) ");
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        // An empty line repeats the last command
+        let command = if line.trim().is_empty() {
+            match last_command {
+                Some(command) => Ok(command),
+                None => continue,
+            }
+        } else {
+            Command::parse(&line)
+        };
+
+        match command {
+            Ok(Command::Quit) => return Ok(()),
+            Ok(command) => {
+                println!("{}", debugger.execute(command));
+                last_command = Some(command);
+            }
+            Err(message) => println!("{}", message),
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use console_engine::Color;
+
+/// The number of brightness levels a fading pixel is drawn with, including full brightness
+pub const SHADES: u8 = 4;
+
+/// The glyphs the ASCII renderer draws each brightness level with, brightest first
+const ASCII_SHADES: [char; SHADES as usize] = ['*', '+', ':', '.'];
+
+/// Encode a pixel for rendering from its plane value and how many shades darker than full
+/// brightness it is. Pixels that are off are always zero.
+pub fn shade(value: u8, dim: u8) -> u8 {
+    if value == 0 { 0 } else { dim << 2 | value }
+}
+
+/// The number of shades darker than full brightness a phosphor brightness is drawn at
+pub fn dim(brightness: u8) -> u8 {
+    let level = (brightness as u16 * SHADES as u16).div_ceil(255) as u8;
+    SHADES - level.max(1)
+}
+
+/// How the frame buffer is drawn with terminal characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Renderer {
+    /// Each pixel is drawn as a block of `*` characters
+    Ascii,
+    /// Each character cell shows two pixels stacked vertically using the half block characters,
+    /// which makes the pixels roughly square
+    HalfBlock,
+    /// Each character cell shows a 2x4 block of pixels as a braille pattern
+    Braille,
+}
+
+/// The braille dot for each pixel in a 2x4 cell, indexed by [y][x]
+const BRAILLE_DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+/// The first braille pattern character, with no dots raised
+const BRAILLE_BASE: u32 = 0x2800;
+
+impl Renderer {
+
+    /// Look up a renderer by the name used on the command line
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ascii" => Some(Renderer::Ascii),
+            "half" => Some(Renderer::HalfBlock),
+            "braille" => Some(Renderer::Braille),
+            _ => None,
+        }
+    }
+
+    /// The width and height in pixels of one character cell
+    pub fn cell_size(self) -> (usize, usize) {
+        match self {
+            Renderer::Ascii => (1, 1),
+            Renderer::HalfBlock => (1, 2),
+            Renderer::Braille => (2, 4),
+        }
+    }
+
+    /// The largest whole number scale at which a display fits in a terminal of the given size in
+    /// characters. Displays that do not fit at all are drawn at scale 1 and cropped.
+    pub fn scale(self, display: (usize, usize), terminal: (usize, usize)) -> usize {
+        let (cell_width, cell_height) = self.cell_size();
+        let scale_x = terminal.0 * cell_width / display.0;
+        let scale_y = terminal.1 * cell_height / display.1;
+        scale_x.min(scale_y).max(1)
+    }
+
+    /// Choose the character for a cell and the shaded pixels to take its colors from as
+    /// (foreground, background). Pixels are given row by row, encoded with shade.
+    pub fn cell(self, pixels: &[u8]) -> (char, u8, u8) {
+        match self {
+            Renderer::Ascii => match pixels[0] {
+                0 => (' ', 0, 0),
+                pixel => (ASCII_SHADES[(pixel >> 2) as usize], pixel, 0),
+            },
+            Renderer::HalfBlock => match (pixels[0], pixels[1]) {
+                (0, 0) => (' ', 0, 0),
+                (top, bottom) if top == bottom => ('█', top, 0),
+                (0, bottom) => ('▄', bottom, 0),
+                (top, bottom) => ('▀', top, bottom),
+            },
+            Renderer::Braille => {
+                let mut dots = 0;
+                let mut counts = [0; 4 * SHADES as usize];
+
+                for (idx, value) in pixels.iter().enumerate() {
+                    if *value != 0 {
+                        dots |= BRAILLE_DOTS[idx / 2][idx % 2];
+                        counts[*value as usize] += 1;
+                    }
+                }
+
+                // A cell can only have one foreground color, so use the most common one
+                let value = (1..counts.len()).max_by_key(|value| (counts[*value], *value)).unwrap_or(1);
+                (char::from_u32(BRAILLE_BASE + dots).unwrap(), if dots == 0 { 0 } else { value as u8 }, 0)
+            }
+        }
+    }
+
+    /// Work out every character cell needed to draw a display of the given size at the given
+    /// scale, calling draw with the cell position, character and shaded pixels. Pixels are read
+    /// with pixel and those past the edge of the display are blank.
+    pub fn render(
+        self,
+        display: (usize, usize),
+        scale: usize,
+        pixel: impl Fn(usize, usize) -> u8,
+        mut draw: impl FnMut(usize, usize, char, u8, u8),
+    ) {
+        let (cell_width, cell_height) = self.cell_size();
+        let (width, height) = (display.0 * scale, display.1 * scale);
+        let mut pixels = vec![0; cell_width * cell_height];
+
+        for cell_y in 0..height.div_ceil(cell_height) {
+            for cell_x in 0..width.div_ceil(cell_width) {
+                for (idx, value) in pixels.iter_mut().enumerate() {
+                    let x = cell_x * cell_width + idx % cell_width;
+                    let y = cell_y * cell_height + idx / cell_width;
+                    *value = if x < width && y < height { pixel(x / scale, y / scale) } else { 0 };
+                }
+
+                let (character, fg, bg) = self.cell(&pixels);
+                draw(cell_x, cell_y, character, fg, bg);
+            }
+        }
+    }
+}
+
+/// The colors the display is drawn in: the background and one color for each combination of
+/// XO-CHIP bit planes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: [Color; 4],
+}
+
+impl Palette {
+
+    /// The color to draw a pixel encoded with shade. Dimmer shades are blended towards the
+    /// background, so they need a terminal with true color support.
+    pub fn color(&self, pixel: u8) -> Color {
+        let (value, dim) = ((pixel & 0x3) as usize, (pixel >> 2) as u16);
+        if dim == 0 || value == 0 {
+            return self.colors[value];
+        }
+
+        let ((r0, g0, b0), (r1, g1, b1)) = (rgb(self.colors[0]), rgb(self.colors[value]));
+        let level = SHADES as u16 - dim;
+        let blend = |from: u8, to: u8| ((from as u16 * dim + to as u16 * level) / SHADES as u16) as u8;
+        Color::Rgb { r: blend(r0, r1), g: blend(g0, g1), b: blend(b0, b1) }
+    }
+}
+
+/// The approximate RGB value of a color, used to blend shades
+fn rgb(color: Color) -> (u8, u8, u8) {
+    match color {
+        Color::Rgb { r, g, b } => (r, g, b),
+        Color::Black => (0, 0, 0),
+        Color::DarkGrey => (0x80, 0x80, 0x80),
+        Color::Red => (0xFF, 0, 0),
+        Color::Green => (0, 0xFF, 0),
+        Color::Yellow => (0xFF, 0xFF, 0),
+        Color::Blue => (0, 0, 0xFF),
+        Color::Magenta => (0xFF, 0, 0xFF),
+        Color::Cyan => (0, 0xFF, 0xFF),
+        Color::Grey => (0xC0, 0xC0, 0xC0),
+        _ => (0xFF, 0xFF, 0xFF),
+    }
+}
+
+impl Default for Palette {
+    /// Plane 1 alone is drawn in the original cyan so plain CHIP-8 games look the same as before
+    fn default() -> Self {
+        Self { colors: [Color::Black, Color::Cyan, Color::Magenta, Color::White] }
+    }
+}
+
+/// Parse a color given on the command line, either by name or as #rrggbb
+pub fn parse_color(name: &str) -> Option<Color> {
+    if let Some(hex) = name.strip_prefix('#') {
+        let rgb = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 6)?;
+        return Some(Color::Rgb { r: (rgb >> 16) as u8, g: (rgb >> 8) as u8, b: rgb as u8 });
+    }
+
+    Some(match name {
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "white" => Color::White,
+        "grey" | "gray" => Color::Grey,
+        "darkgrey" | "darkgray" => Color::DarkGrey,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_block_cells() {
+        assert_eq!(Renderer::HalfBlock.cell(&[0, 0]), (' ', 0, 0));
+        assert_eq!(Renderer::HalfBlock.cell(&[1, 1]), ('█', 1, 0));
+        assert_eq!(Renderer::HalfBlock.cell(&[0, 2]), ('▄', 2, 0));
+        assert_eq!(Renderer::HalfBlock.cell(&[1, 0]), ('▀', 1, 0));
+        assert_eq!(Renderer::HalfBlock.cell(&[1, 3]), ('▀', 1, 3));
+    }
+
+    #[test]
+    fn braille_cells() {
+        assert_eq!(Renderer::Braille.cell(&[0; 8]), ('\u{2800}', 0, 0));
+        assert_eq!(Renderer::Braille.cell(&[1, 0, 0, 0, 0, 0, 0, 1]), ('\u{2881}', 1, 0));
+        assert_eq!(Renderer::Braille.cell(&[2, 2, 1, 0, 0, 0, 0, 0]), ('\u{280b}', 2, 0));
+    }
+
+    #[test]
+    fn scales_to_terminal() {
+        assert_eq!(Renderer::Ascii.scale((64, 32), (128, 64)), 2);
+        assert_eq!(Renderer::HalfBlock.scale((64, 32), (200, 50)), 3);
+        assert_eq!(Renderer::Braille.scale((128, 64), (80, 24)), 1);
+        assert_eq!(Renderer::Ascii.scale((128, 64), (80, 24)), 1);
+    }
+
+    #[test]
+    fn render_covers_display() {
+        let mut cells = Vec::new();
+        let pixel = |x, y| if (x, y) == (0, 0) { 1 } else { 0 };
+        Renderer::HalfBlock.render((64, 32), 2, pixel, |x, y, character, _, _| cells.push((x, y, character)));
+
+        assert_eq!(cells.len(), 128 * 32);
+        assert_eq!(&cells[..3], &[(0, 0, '█'), (1, 0, '█'), (2, 0, ' ')]);
+    }
+
+    #[test]
+    fn shades() {
+        assert_eq!(dim(255), 0);
+        assert_eq!(dim(200), 0);
+        assert_eq!(dim(100), 2);
+        assert_eq!(dim(1), 3);
+        assert_eq!(shade(0, 2), 0);
+        assert_eq!(Renderer::Ascii.cell(&[shade(1, 2)]), (':', shade(1, 2), 0));
+
+        let palette = Palette::default();
+        assert_eq!(palette.color(shade(1, 0)), Color::Cyan);
+        assert_eq!(palette.color(shade(1, 2)), Color::Rgb { r: 0, g: 0x7F, b: 0x7F });
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(parse_color("cyan"), Some(Color::Cyan));
+        assert_eq!(parse_color("#ff8000"), Some(Color::Rgb { r: 0xFF, g: 0x80, b: 0 }));
+        assert_eq!(parse_color("#ff80"), None);
+        assert_eq!(parse_color("mauve"), None);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::render::{dim, shade, Palette, Renderer};
+use chip9::cpu::NUM_KEYS;
+use chip9::phosphor::Phosphor;
+use chip9::{Audio, Display, Input, Memory};
+use console_engine::pixel;
+use console_engine::{ConsoleEngine, KeyCode};
+
+/// Keys on the host keyboard that map onto CHIP-8 keys, in addition to the digits 0-9
+const EXTRA_KEYS: [(char, usize); 4] = [('w', 2), ('s', 8), ('a', 4), ('d', 6)];
+
+/// The number of save state slots, saved with F1-F4 and loaded with F5-F8
+pub const NUM_SLOTS: u8 = 4;
+
+/// How many frames a status message stays on screen
+const STATUS_FRAMES: usize = 120;
+
+/// A save state hotkey pressed by the user, holding the slot number from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotAction {
+    Save(u8),
+    Load(u8),
+}
+
+/// The console_engine terminal backend. The engine both draws and reads the keyboard, so one
+/// backend implements all three traits.
+pub struct Terminal {
+    engine: ConsoleEngine,
+    renderer: Renderer,
+    palette: Palette,
+    /// When set pixels fade out over several frames rather than switching off immediately
+    phosphor: Option<Phosphor>,
+    /// A message drawn over the display and the number of frames left to show it for
+    status: Option<(String, usize)>,
+}
+
+impl Terminal {
+    /// Take over the whole terminal, drawing with the given renderer, colors and optional
+    /// phosphor filter. The terminal is restored when the backend is dropped.
+    pub fn new(renderer: Renderer, palette: Palette, phosphor: Option<Phosphor>) -> Self {
+        Self {
+            engine: ConsoleEngine::init_fill(60).unwrap(),
+            renderer,
+            palette,
+            phosphor,
+            status: None,
+        }
+    }
+
+    /// Wait until it is time to draw the next 60Hz frame, following any change in the size of the
+    /// terminal
+    pub fn wait_frame(&mut self) {
+        self.engine.wait_frame();
+        self.engine.check_resize();
+    }
+
+    /// True if the user has asked to quit
+    pub fn quit_requested(&self) -> bool {
+        self.engine.is_key_pressed(KeyCode::Char('q'))
+    }
+
+    /// True while the rewind key is held
+    pub fn rewind_requested(&self) -> bool {
+        self.engine.is_key_pressed(KeyCode::Char('r')) || self.engine.is_key_held(KeyCode::Char('r'))
+    }
+
+    /// The save state hotkey pressed this frame, if any
+    pub fn slot_requested(&self) -> Option<SlotAction> {
+        (1..=NUM_SLOTS).find_map(|slot| {
+            if self.engine.is_key_pressed(KeyCode::F(slot)) {
+                Some(SlotAction::Save(slot))
+            } else if self.engine.is_key_pressed(KeyCode::F(slot + NUM_SLOTS)) {
+                Some(SlotAction::Load(slot))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Show a message in the corner of the display for the next couple of seconds
+    pub fn show_status(&mut self, message: String) {
+        self.status = Some((message, STATUS_FRAMES));
+    }
+}
+
+impl Display for Terminal {
+    /// The display is drawn at the largest scale that fits the terminal
+    fn draw(&mut self, memory: &Memory) {
+        let palette = self.palette;
+        let display = (memory.width(), memory.height());
+        let terminal = (self.engine.get_width() as usize, self.engine.get_height() as usize);
+        let scale = self.renderer.scale(display, terminal);
+        let engine = &mut self.engine;
+        engine.fill(pixel::pxl_bg(' ', palette.colors[0]));
+
+        let draw = |x: usize, y: usize, character, fg, bg| {
+            engine.set_pxl(x as i32, y as i32, pixel::pxl_fbg(character, palette.color(fg), palette.color(bg)));
+        };
+
+        match &mut self.phosphor {
+            Some(phosphor) => {
+                phosphor.update(memory);
+                let phosphor = &*phosphor;
+                self.renderer.render(display, scale, |x, y| {
+                    let (value, brightness) = phosphor.pixel(x, y);
+                    shade(value, dim(brightness))
+                }, draw);
+            }
+            None => self.renderer.render(display, scale, |x, y| memory.pixel(x, y), draw),
+        }
+
+        if let Some((message, frames)) = &mut self.status {
+            self.engine.print(0, 0, message);
+            *frames -= 1;
+            if *frames == 0 {
+                self.status = None;
+            }
+        }
+
+        self.engine.draw();
+    }
+}
+
+impl Input for Terminal {
+    fn poll(&mut self) -> [bool; NUM_KEYS] {
+        let mut keys = [false; NUM_KEYS];
+
+        for (i, key) in keys.iter_mut().enumerate().take(10) {
+            *key = self.engine.is_key_pressed(KeyCode::Char((b'0' + i as u8) as char));
+        }
+
+        for (key_char, key) in EXTRA_KEYS.iter() {
+            keys[*key] |= self.engine.is_key_pressed(KeyCode::Char(*key_char));
+        }
+
+        keys
+    }
+}
+
+impl Audio for Terminal {
+    /// The terminal can only ring the bell
+    fn set_playing(&mut self, playing: bool) {
+        if playing {
+            print!("\x07");
+        }
+    }
+}
@@ -1,27 +1,35 @@
-use crate::memory::Memory;
+use crate::error::{ExecError, StateError};
+use crate::memory::{Memory, BIG_SPRITE_ADDR, SPRITE_ADDR};
+use crate::opcode::Opcode;
+use crate::quirks::Quirks;
+use crate::random::Random;
+use crate::state::{StateReader, StateWriter};
 use log::trace;
-use rand::prelude::*;
-use std::convert::TryInto;
 use std::num::Wrapping;
 
 /// Size of an instruction (CHIP-8 uses fixed width opcodes)
 pub const INSTRUCTION_SIZE: u16 = 0x2;
 
-/// If opcode has the form _XN_ or _XR_ then the first register can be extracted with this mask
-pub const REGISTER_MASK: u16 = 0x0F00;
+/// The XO-CHIP long load opcode, which is followed by a 16-bit address
+pub const LONG_LOAD_OPCODE: u16 = 0xF000;
 
-/// If the opcode has the form _XR_ the second register can be extracted with this mask
-pub const REGISTER_TWO_MASK: u16 = 0x00F0;
+/// The number of key registers
+pub const NUM_KEYS: usize = 16;
 
-/// If opcodes have the form __II then the immediate value can be extracted with this mask
-pub const DATA_MASK: u16 = 0x00FF;
+/// The extra COSMAC VIP machine cycles taken when a skip instruction skips
+pub const VIP_SKIP_CYCLES: u32 = 4;
 
-/// If the opcode immediate contaisn only a single nibble of data (the final nibble of the opcode)
-/// we extract it with this mask
-pub const NIBBLE_DATA_MASK: u16 = 0x000F;
+/// The COSMAC VIP machine cycles taken by 00E0, which clears the display a byte at a time
+pub const VIP_CLEAR_CYCLES: u32 = 24;
 
-/// The number of key registers
-pub const NUM_KEYS: usize = 16;
+/// The extra COSMAC VIP machine cycles FX55 and FX65 take for each register saved or loaded
+pub const VIP_CYCLES_PER_REGISTER: u32 = 14;
+
+/// The extra COSMAC VIP machine cycles DXYN takes for each row of the sprite
+pub const VIP_CYCLES_PER_SPRITE_ROW: u32 = 16;
+
+/// How a save state records that the CPU is not waiting for a key
+const NO_KEY_WAIT: u8 = 0xFF;
 
 #[derive(Debug)]
 pub struct Registers {
@@ -46,7 +54,7 @@ pub struct Registers {
     pub sound: Wrapping<u8>,
 
     /// Used to generate random values for the masked random command
-    pub rng: ThreadRng,
+    pub rng: Random,
 
     /// True if a given key is currently pressed
     pub keys: [bool; NUM_KEYS],
@@ -54,1024 +62,460 @@ pub struct Registers {
     /// If we are waiting for a key then this is Some of the register to write the key to
     /// otherwise None
     pub wait_for_key: Option<usize>,
-}
 
-pub struct OpTables {
-    pub main_op_table: [Instruction; 16],
-    pub math_op_table: [Instruction; 9],
-    pub load_op_table: [Instruction; 0x66],
-}
+    /// The SUPER-CHIP RPL user flags, saved and restored from V registers by FX75 and FX85
+    pub rpl: [Wrapping<u8>; 16],
 
-impl Registers {
-    /// Increment the PC by a given amount
-    pub fn inc_pc(&mut self, val: u16) {
-        self.pc += Wrapping(val);
-    }
+    /// Set once the program has executed the SUPER-CHIP exit instruction
+    pub halted: bool,
 
-    /// Push a u16 to the stack in big-endian format
-    pub fn stack_push16(&mut self, value: u16) {
-        let lower_part = Wrapping((value & 0x00FF) as u8);
-        let upper_part = Wrapping(((value & 0xFF00) >> 8) as u8);
-        self.stack[self.stack_idx] = upper_part;
-        self.stack[self.stack_idx + 1] = lower_part;
-        self.stack_idx += 2;
-    }
+    /// The XO-CHIP 1-bit audio pattern buffer, played back while the sound timer is non-zero
+    pub audio_pattern: [u8; 16],
 
-    /// Pop a u16 from the stack
-    /// TODO: Since stack is only ever used for retcodes I could just keep them as usize or u16's
-    pub fn stack_pop16(&mut self) -> u16 {
-        self.stack_idx -= 2;
-        let upper_part = self.stack[self.stack_idx];
-        let lower_part = self.stack[self.stack_idx + 1];
-
-        ((upper_part.0 as u16) << 8) | (lower_part.0 as u16)
-    }
+    /// The XO-CHIP playback rate of the audio pattern. 4000 * 2^((pitch - 64) / 48) bits per second
+    pub pitch: Wrapping<u8>,
 }
 
-#[derive(Clone)]
-pub struct Instruction {
-    /// Rough description of the opcode from the first byte
-    pub desc: String,
-    /// Execute the opcode, with the change in state being reflected in registers and memory
-    pub execute:
-        fn(registers: &mut Registers, memory: &mut Memory, data: u16, op_tables: &OpTables),
-    /// Granular description of the opcode that requires the opcode data (not just the first byte)
-    pub to_string: fn(data: u16, op_tables: &OpTables) -> String,
-}
+impl Registers {
 
-impl Instruction {
-    /// The zero opcode can be either clear display, ret, or machine call (Call an instruction
-    /// written in machine code) depending on parameters. We merge these all into one opcode
-    /// execution.
-    fn mcall_display_or_flow(
-        registers: &mut Registers,
-        memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        match data {
-            0xE0 => {
-                memory.clear_display();
-                registers.inc_pc(2);
-            },
-            0xEE => {
-                trace!("ret");
-                let new_pc = registers.stack_pop16();
-                registers.pc = Wrapping(new_pc);
-            }
-            _ => panic!("machine code routes are unsupported {:x}", data),
+    /// Registers in their power on state, with the PC at the start of the program
+    pub fn new() -> Self {
+        Self {
+            pc: Wrapping(0x200),
+            v: [Wrapping(0); 16],
+            i: Wrapping(0),
+            stack: [Wrapping(0); 256],
+            stack_idx: 0,
+            delay: Wrapping(0),
+            sound: Wrapping(0),
+            rng: Random::from_entropy(),
+            keys: [false; NUM_KEYS],
+            wait_for_key: None,
+            rpl: [Wrapping(0); 16],
+            halted: false,
+            audio_pattern: [0; 16],
+            pitch: Wrapping(64),
         }
     }
 
-    fn mcall_display_or_flow_to_string(data: u16, _op_table: &OpTables) -> String {
-        match data {
-            0xE0 => format!("clear_display"),
-            0xEE => format!("return"),
-            _ => format!("mcall {:x}", data),
+    /// Write every register, including the state of the random number generator, to a save state
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.v.iter().for_each(|v| writer.u8(v.0));
+        writer.u16(self.i.0);
+        writer.u16(self.pc.0);
+        self.stack.iter().for_each(|byte| writer.u8(byte.0));
+        writer.u16(self.stack_idx as u16);
+        writer.u8(self.delay.0);
+        writer.u8(self.sound.0);
+        self.keys.iter().for_each(|key| writer.bool(*key));
+        writer.u8(self.wait_for_key.map_or(NO_KEY_WAIT, |register| register as u8));
+        self.rpl.iter().for_each(|flag| writer.u8(flag.0));
+        writer.bool(self.halted);
+        writer.bytes(&self.audio_pattern);
+        writer.u8(self.pitch.0);
+        self.rng.save_state(writer);
+    }
+
+    /// Read back registers written by save_state. States from before version 2 do not hold the
+    /// random number generator, so the registers returned have a new one from the operating
+    /// system. Machine::load_state swaps in the machine's current generator instead.
+    pub fn load_state(reader: &mut StateReader) -> Result<Self, StateError> {
+        let mut registers = Self::new();
+
+        for v in registers.v.iter_mut() {
+            *v = Wrapping(reader.u8()?);
         }
-    }
-
-    /// Goto changes the PC pointer to the fixed location
-    fn goto(registers: &mut Registers, _memory: &mut Memory, data: u16, _op_tables: &OpTables) {
-        registers.pc = Wrapping(data);
-    }
-
-    fn goto_to_string(data: u16, _op_table: &OpTables) -> String {
-        format!("goto {:x}", data)
-    }
-
-    /// Call pushes a return address and then changes I to the given location
-    fn call(registers: &mut Registers, _memory: &mut Memory, data: u16, _op_tables: &OpTables) {
-        trace!("call instr");
-        // First save the current PC + 2
-        registers.stack_push16(registers.pc.0 + INSTRUCTION_SIZE);
-
-        // Jump to the immediate
-        registers.pc = Wrapping(data);
-    }
-
-    fn call_to_string(data: u16, _op_table: &OpTables) -> String {
-        format!("call {:x}", data)
-    }
-
-    /// Extract the register from the opcode when the instruction has the form _R__
-    fn register_from_data(data: u16) -> u8 {
-        ((data & REGISTER_MASK) >> 8) as u8
-    }
-
-    /// Extract the register from the opcode when the register has the form __R_
-    fn register_two_from_data(data: u16) -> u8 {
-        ((data & REGISTER_TWO_MASK) >> 4) as u8
-    }
-
-    /// Extract the immediate from the opcode when the instruction has the form __II
-    fn immediate_from_data(data: u16) -> u8 {
-        (data & DATA_MASK) as u8
-    }
-
-    /// Extract both the register and immediate for instructions in the form _RII
-    fn register_and_immediate_from_data(data: u16) -> (usize, u8) {
-        (
-            Self::register_from_data(data) as usize,
-            Self::immediate_from_data(data),
-        )
-    }
-
-    /// Extract two registers from and opcode in the form _RV_
-    fn two_registers_from_data(data: u16) -> (usize, usize) {
-        (
-            Self::register_from_data(data) as usize,
-            Self::register_two_from_data(data) as usize,
-        )
-    }
-
-    /// Checks if a register and an immediate value are equal. If they are equal then we
-    /// skip the next instruction, otherwise we run the next instruction.
-    fn reg_equal(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register, data) = Self::register_and_immediate_from_data(data);
-        trace!("eq v{:x} {:x}", register, data);
-        registers.inc_pc(if registers.v[register as usize] == Wrapping(data) {
-            4
-        } else {
-            2
-        });
-    }
-
-    fn reg_equal_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register, data) = Self::register_and_immediate_from_data(data);
-        format!("eq v{} {}", register, data)
-    }
-
-    /// Checks if a register and an immediate are not equal. If they are not equal then skip the
-    /// next instruction, otherwise run the next instruction.
-    fn reg_not_equal(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register, data) = Self::register_and_immediate_from_data(data);
-        registers.inc_pc(if registers.v[register as usize] != Wrapping(data) {
-            4
-        } else {
-            2
-        });
-    }
-
-    fn reg_not_equal_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register, data) = Self::register_and_immediate_from_data(data);
-        format!("neq v{} {}", register, data)
-    }
-
-    /// Checks if two registers are equal. If they are then skip the next instruction, otherwise
-    /// run it.
-    fn two_reg_equal(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        trace!("eq v{:x} v{:x}", register1, register2);
-        registers.inc_pc(if registers.v[register1] == registers.v[register2] {
-            4
-        } else {
-            2
-        });
-    }
-
-    fn two_reg_equal_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        format!("eq v{} v{}", register1, register2)
-    }
-
-    /// Load an immediate into a register
-    fn load_immediate(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register, data) = Self::register_and_immediate_from_data(data);
-        registers.v[register] = Wrapping(data);
-        registers.inc_pc(2);
-    }
-
-    fn load_immediate_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register, data) = Self::register_and_immediate_from_data(data);
-        format!("ld v{} {}", register, data)
-    }
-
-    /// Same as load immediate but add it to the register rather than add
-    fn add_immediate(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register, data) = Self::register_and_immediate_from_data(data);
-        registers.v[register] = registers.v[register] + Wrapping(data);
-        registers.inc_pc(2);
-    }
-
-    fn add_immediate_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register, data) = Self::register_and_immediate_from_data(data);
-        format!("add v{} {}", register, data)
-    }
-
-    /// The math or bitops instruction picks a opcode from the math_opcode table
-    /// using the final nibble as it's value
-    fn math_or_bitop(
-        registers: &mut Registers,
-        memory: &mut Memory,
-        data: u16,
-        op_tables: &OpTables,
-    ) {
-        let math_opcode = data & NIBBLE_DATA_MASK;
-        (op_tables.math_op_table[math_opcode as usize].execute)(registers, memory, data, op_tables);
-    }
-
-    fn math_or_bitop_to_string(data: u16, op_table: &OpTables) -> String {
-        let math_opcode = data & NIBBLE_DATA_MASK;
-        (op_table.math_op_table[math_opcode as usize].to_string)(data, op_table)
-    }
-
-    /// Test if two registers are not equal. If they are not equal then skip the next instruction,
-    /// otherwise run it.
-    fn two_registers_not_equal(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        registers.inc_pc(if registers.v[register1] != registers.v[register2] {
-            4
-        } else {
-            2
-        });
-    }
-
-    fn two_registers_not_equal_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        format!("neq v{} v{}", register1, register2)
-    }
-
-    /// Set the I register to an immediate value
-    fn set_i(registers: &mut Registers, _memory: &mut Memory, data: u16, _op_tables: &OpTables) {
-        registers.i = Wrapping(data);
-        registers.inc_pc(2);
-    }
-
-    fn set_i_to_string(data: u16, _op_table: &OpTables) -> String {
-        format!("ld i {:x}", data)
-    }
-
-    /// Jump to an immediate value plus the value of V[0]
-    fn jump_immediate_plus_register(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        registers.pc = Wrapping(registers.v[0].0 as u16) + Wrapping(data);
-    }
-
-    fn jump_immediate_plus_register_to_string(data: u16, _op_table: &OpTables) -> String {
-        format!("jump v0 + {}", data)
-    }
-
-    /// The masked random instruction generates a random value between 0 and 255, masks it with an
-    /// immediate (& imm) and then places it in a specified register.
-    fn masked_random(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register, mask) = Self::register_and_immediate_from_data(data);
-        let rval: u8 = registers.rng.gen::<u8>();
-        registers.v[register].0 = rval & mask;
-        registers.inc_pc(2);
-    }
-
-    fn masked_random_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register, mask) = Self::register_and_immediate_from_data(data);
-        format!("rand v{} {}", register, mask)
-    }
-
-    /// Draw a sprite from memory to the framebuffer (which is stored in the Memory structure).
-    /// 0x0F00 is the X position, 0x00F0 is the Y position and 0x000F is the depth of the sprite.
-    /// The sprite is drawn downward using the data at i, incrementing y by 1 for every pixel down
-    /// it goes.
-    fn draw_sprite(
-        registers: &mut Registers,
-        memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        let d = data & NIBBLE_DATA_MASK;
-        registers.v[0xF] = Wrapping(memory.draw_sprite(registers.v[register1].0 as usize, registers.v[register2].0 as usize, d as usize, registers.i.0 as usize));
-        registers.inc_pc(2);
-    }
-
-    fn draw_sprite_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        let imm = data & NIBBLE_DATA_MASK;
-        format!("draw v{} v{} {}", register1, register2, imm)
-    }
-
-    /// If the final byte = 0x9E then skip the next instruction if key[register[data & 0x0F00]] is
-    /// pressed.
-    /// If the final byte = 0xA1 then skip the next instruction if key[register[data & 0x0F00]] is
-    /// not pressed
-    fn key_op(registers: &mut Registers, _memory: &mut Memory, data: u16, _op_tables: &OpTables) {
-
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        let rval = registers.v[register1];
-        let pressed = registers.keys[rval.0 as usize];
-        let code = data & 0x00FF;
-
-        match code {
-            0x9E => {
-                if pressed {
-                    registers.inc_pc(4);
-                } else {
-                    registers.inc_pc(2);
-                }
-            },
-            0xA1 => {
-                if pressed {
-                    registers.inc_pc(2);
-                } else {
-                    registers.inc_pc(4);
-                }
-            },
-            _ => panic!("unexpected keyop {}", code)
-        };
-    }
-
-    fn key_op_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        let code = data & 0x00FF;
-
-        match code {
-            0x9E => format!("eq Key(V{}), 1", register1),
-            0xA1 => format!("neq Key(V{}), 1", register1),
-            _ => panic!("invalid op: {}", code)
+        registers.i = Wrapping(reader.u16()?);
+        registers.pc = Wrapping(reader.u16()?);
+        for byte in registers.stack.iter_mut() {
+            *byte = Wrapping(reader.u8()?);
         }
-    }
-
-    fn load_or_store(
-        registers: &mut Registers,
-        memory: &mut Memory,
-        data: u16,
-        op_tables: &OpTables,
-    ) {
-        let opcode_mask = data & 0x00FF;
-        (op_tables.load_op_table[opcode_mask as usize].execute)(registers, memory, data, op_tables);
-    }
-
-    fn load_or_store_to_string(data: u16, op_tables: &OpTables) -> String {
-        let opcode_mask = data & 0x00FF;
-        (op_tables.load_op_table[opcode_mask as usize].to_string)(data, op_tables)
-    }
-
-    fn mv_register(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        registers.v[register1] = registers.v[register2];
-        registers.inc_pc(2);
-    }
-
-    fn mv_register_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        format!("mv v{:x} v{:x}", register1, register2)
-    }
-
-    fn or_register(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        registers.v[register1] |= registers.v[register2];
-        registers.inc_pc(2);
-    }
-
-    fn or_register_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        format!("or v{:x} v{:x}", register1, register2)
-    }
-
-    fn and_register(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        registers.v[register1] &= registers.v[register2];
-        registers.inc_pc(2);
-    }
-
-    fn and_register_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        format!("and v{:x} v{:x}", register1, register2)
-    }
-
-    fn xor_register(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        registers.v[register1] ^= registers.v[register2];
-        registers.inc_pc(2);
-    }
-
-    fn xor_register_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        format!("xor v{:x} v{:x}", register1, register2)
-    }
-
-    fn add_register(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        let result = registers.v[register1] + registers.v[register2];
-
-        registers.v[0xF] = if result < registers.v[register1] {
-            Wrapping(1)
-        } else {
-            Wrapping(0)
-        };
-
-        registers.v[register1] = result;
 
-        registers.inc_pc(2);
-    }
-
-    fn add_register_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        format!("add v{:x} v{:x}", register1, register2)
-    }
-
-    fn sub_register(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        let result = registers.v[register1] - registers.v[register2];
-
-        registers.v[0xF] = if result > registers.v[register1] {
-            Wrapping(1)
-        } else {
-            Wrapping(0)
-        };
-
-        registers.v[register1] = result;
-
-        registers.inc_pc(2);
-    }
-
-    fn sub_register_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        format!("sub v{:x} v{:x}", register1, register2)
-    }
-
-    fn shr_register(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        registers.v[0xF].0 = registers.v[register1].0 & 0x1;
-        registers.v[register1].0 >>= 1;
-        registers.inc_pc(2);
-    }
-
-    fn shr_register_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        format!("shr v{:x}", register1)
-    }
-
-    fn shl_register(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        registers.v[0xF].0 = registers.v[register1].0 & (0x1 << 7);
-        registers.v[register1].0 <<= 1;
-        registers.inc_pc(2);
-    }
-
-    fn shl_register_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        format!("shl v{:x}", register1)
-    }
-
-    fn rev_sub_register(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        let result = registers.v[register2] - registers.v[register1];
+        // The stack is addressed in bytes and always holds whole return addresses
+        registers.stack_idx = reader.u16()? as usize;
+        if registers.stack_idx > registers.stack.len() || registers.stack_idx % 2 != 0 {
+            return Err(StateError::InvalidValue { field: "stack index" });
+        }
 
-        registers.v[0xF] = if result > registers.v[register2] {
-            Wrapping(1)
-        } else {
-            Wrapping(0)
+        registers.delay = Wrapping(reader.u8()?);
+        registers.sound = Wrapping(reader.u8()?);
+        for key in registers.keys.iter_mut() {
+            *key = reader.bool("key")?;
+        }
+        registers.wait_for_key = match reader.u8()? {
+            NO_KEY_WAIT => None,
+            register if (register as usize) < registers.v.len() => Some(register as usize),
+            _ => return Err(StateError::InvalidValue { field: "key wait register" }),
         };
+        for flag in registers.rpl.iter_mut() {
+            *flag = Wrapping(reader.u8()?);
+        }
+        registers.halted = reader.bool("halted flag")?;
+        let audio_pattern = reader.bytes(registers.audio_pattern.len())?;
+        registers.audio_pattern.copy_from_slice(audio_pattern);
+        registers.pitch = Wrapping(reader.u8()?);
+        if reader.version() >= 2 {
+            registers.rng = Random::load_state(reader)?;
+        }
 
-        registers.v[register1] = result;
-
-        registers.inc_pc(2);
-    }
-
-    fn rev_sub_register_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, register2) = Self::two_registers_from_data(data);
-        format!("rsub v{:x} v{:x}", register1, register2)
-    }
-
-    fn invalid_op(
-        _registers: &mut Registers,
-        _memory: &mut Memory,
-        _data: u16,
-        _op_tables: &OpTables,
-    ) {
-        panic!("invalid");
-    }
-
-    fn invalid_op_to_string(_data: u16, _op_table: &OpTables) -> String {
-        format!("invalid")
-    }
-
-    fn get_delay(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        registers.v[register1] = registers.delay;
-        registers.inc_pc(2);
-    }
-
-    fn get_delay_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        format!("V{} = get_delay()", register1)
-    }
-
-    fn set_delay(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        registers.delay = registers.v[register1];
-        registers.inc_pc(2);
-    }
-
-    fn set_delay_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        format!("mv delay, V{}", register1)
-    }
-
-    fn set_sound(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        registers.sound = registers.v[register1];
-        registers.inc_pc(2);
+        Ok(registers)
     }
+}
 
-    fn set_sound_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        format!("mv sound, V{}", register1)
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    fn wait_for_key(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        registers.wait_for_key = Some(register1);
-        registers.inc_pc(2);
+impl Registers {
+    /// Increment the PC by a given amount
+    pub fn inc_pc(&mut self, val: u16) {
+        self.pc += Wrapping(val);
     }
 
-    fn wait_for_key_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        format!("V{} = wait_key ()", register1)
-    }
+    /// Push a u16 to the stack in big-endian format, failing if the stack is full
+    pub fn stack_push16(&mut self, value: u16) -> Result<(), ExecError> {
+        if self.stack_idx + 2 > self.stack.len() {
+            return Err(ExecError::StackOverflow { pc: self.pc.0 });
+        }
 
-    fn add_vx_i(registers: &mut Registers, _memory: &mut Memory, data: u16, _op_tables: &OpTables) {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        registers.i += Wrapping(registers.v[register1].0 as u16);
-        registers.inc_pc(2);
+        let lower_part = Wrapping((value & 0x00FF) as u8);
+        let upper_part = Wrapping(((value & 0xFF00) >> 8) as u8);
+        self.stack[self.stack_idx] = upper_part;
+        self.stack[self.stack_idx + 1] = lower_part;
+        self.stack_idx += 2;
+        Ok(())
     }
 
-    fn add_vx_i_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        format!("add I, V{}", register1)
-    }
+    /// Pop a u16 from the stack, failing if the stack is empty
+    /// TODO: Since stack is only ever used for retcodes I could just keep them as usize or u16's
+    pub fn stack_pop16(&mut self) -> Result<u16, ExecError> {
+        if self.stack_idx < 2 {
+            return Err(ExecError::StackUnderflow { pc: self.pc.0 });
+        }
 
-    fn set_i_sprite_addr(
-        registers: &mut Registers,
-        _memory: &mut Memory,
-        data: u16,
-        _op_tables: &OpTables,
-    ) {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        registers.i.0 = 0x4000 + ((registers.v[register1].0 & 0x0F) as u16 * 5);
-        registers.inc_pc(2);
-    }
+        self.stack_idx -= 2;
+        let upper_part = self.stack[self.stack_idx];
+        let lower_part = self.stack[self.stack_idx + 1];
 
-    fn set_i_sprite_addr_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        format!("mv I, sprite_addr(V{})", register1)
+        Ok(((upper_part.0 as u16) << 8) | (lower_part.0 as u16))
     }
+}
 
-    fn bcd_vx(registers: &mut Registers, memory: &mut Memory, data: u16, _op_tables: &OpTables) {
-        let (register1, _) = Self::two_registers_from_data(data);
-        let mut tmp = registers.v[register1];
-
-        // Least significant digit
-        memory.set((registers.i + Wrapping(2)).0 as usize, tmp % Wrapping(10));
-        tmp /= Wrapping(10);
+/// The result of a successful step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// An instruction was executed
+    Executed,
+    /// Nothing was executed because the program is waiting for a key press
+    WaitingForKey,
+    /// Nothing was executed because the program has exited
+    Halted,
+}
 
-        // Middle digit
-        memory.set((registers.i + Wrapping(1)).0 as usize, tmp % Wrapping(10));
-        tmp /= Wrapping(10);
+/// The CPU holds the current program registers and the quirks profile that decides how
+/// ambiguous instructions behave
+pub struct Cpu {
+    pub registers: Registers,
+    pub quirks: Quirks,
+}
 
-        // Most significant digit
-        memory.set(registers.i.0 as usize, tmp % Wrapping(10));
+impl Cpu {
 
-        registers.i += Wrapping(3);
-        registers.inc_pc(2);
+    /// Create a fresh CPU instance with 0 / false set for all registers and PC set to 0x200 (the
+    /// typical ROM start location)
+    pub fn new() -> Self {
+        Self::with_quirks(Quirks::default())
     }
 
-    fn bcd_vx_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        format!("bcd v{}", register1)
+    /// Create a fresh CPU instance that executes ambiguous instructions using the given quirks.
+    /// The random number generator is seeded from the operating system.
+    pub fn with_quirks(quirks: Quirks) -> Self {
+        Self::with_random(quirks, Random::from_entropy())
     }
 
-    fn reg_dump(registers: &mut Registers, memory: &mut Memory, data: u16, _op_tables: &OpTables) {
-        let (register1, _) = Self::two_registers_from_data(data);
-        for i in 0..(register1 as usize + 1) {
-            memory.set(registers.i.0 as usize, registers.v[i]);
-            registers.i += Wrapping(1);
+    /// Create a fresh CPU instance with the given quirks and random number generator, so runs of
+    /// a program that uses CXNN can be reproduced
+    pub fn with_random(quirks: Quirks, rng: Random) -> Self {
+        Self {
+            registers: Registers { rng, ..Registers::new() },
+            quirks,
         }
-        registers.inc_pc(2);
-    }
-
-    fn reg_dump_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        format!("reg_dump v0, v{}", register1)
     }
 
-    fn reg_load(registers: &mut Registers, memory: &mut Memory, data: u16, _op_tables: &OpTables) {
-        let (register1, _) = Self::two_registers_from_data(data);
-        for i in 0..(register1 as usize + 1) {
-            registers.v[i] = memory.get(registers.i.0 as usize);
-            registers.i += Wrapping(1);
+    /// Execute the instruction at the PC. Nothing is executed if the program has exited or is
+    /// waiting for a key press.
+    pub fn step(&mut self, memory: &mut Memory) -> Result<StepOutcome, ExecError> {
+        if self.registers.halted {
+            return Ok(StepOutcome::Halted);
         }
-        registers.inc_pc(2);
-    }
-
-    fn reg_load_to_string(data: u16, _op_table: &OpTables) -> String {
-        let (register1, _register2) = Self::two_registers_from_data(data);
-        format!("reg_load v0, v{}", register1)
-    }
-
-    pub fn load_op_table() -> [Self; 0x66] {
-        let mut load_op_table: [Self; 0x66] = (0..0x66)
-            .map(|_x| Self {
-                desc: format!("invalid"),
-                execute: Self::invalid_op,
-                to_string: Self::invalid_op_to_string,
-            })
-            .collect::<Vec<Self>>()
-            .try_into()
-            .unwrap_or_else(|_v| panic!("load table wrong length"));
-
-        load_op_table[0x07] = Self {
-            desc: format!("mv Vx, delay"),
-            execute: Self::get_delay,
-            to_string: Self::get_delay_to_string,
-        };
-
-        load_op_table[0x0A] = Self {
-            desc: format!("mv Vx, key"),
-            execute: Self::wait_for_key,
-            to_string: Self::wait_for_key_to_string,
-        };
-
-        load_op_table[0x15] = Self {
-            desc: format!("mv delay, Vx"),
-            execute: Self::set_delay,
-            to_string: Self::set_delay_to_string,
-        };
-
-        load_op_table[0x18] = Self {
-            desc: format!("mv sound, Vx"),
-            execute: Self::set_sound,
-            to_string: Self::set_sound_to_string,
-        };
-
-        load_op_table[0x1E] = Self {
-            desc: format!("add I, Vx"),
-            execute: Self::add_vx_i,
-            to_string: Self::add_vx_i_to_string,
-        };
-
-        load_op_table[0x29] = Self {
-            desc: format!("mv I, sprite_addr[Vx]"),
-            execute: Self::set_i_sprite_addr,
-            to_string: Self::set_i_sprite_addr_to_string,
-        };
 
-        load_op_table[0x33] = Self {
-            desc: format!("mv I, bcd Vx"),
-            execute: Self::bcd_vx,
-            to_string: Self::bcd_vx_to_string,
-        };
+        if self.registers.wait_for_key.is_some() {
+            return Ok(StepOutcome::WaitingForKey);
+        }
 
-        load_op_table[0x55] = Self {
-            desc: format!("red_dump"),
-            execute: Self::reg_dump,
-            to_string: Self::reg_dump_to_string,
-        };
+        let pc = self.registers.pc.0;
+        let next_opcode = memory.get16(pc as usize)?.0;
+        let opcode = Opcode::decode(next_opcode).map_err(|err| ExecError::InvalidOpcode { pc, opcode: err.opcode })?;
 
-        load_op_table[0x65] = Self {
-            desc: format!("reg_load"),
-            execute: Self::reg_load,
-            to_string: Self::reg_load_to_string,
-        };
+        trace!("PC: {:x} OPCODE: {:04x} {}", pc, next_opcode, opcode);
+        self.execute(opcode, memory)?;
 
-        load_op_table
+        Ok(StepOutcome::Executed)
     }
 
-    pub fn math_op_table() -> [Self; 9] {
-        let mv = Self {
-            desc: format!("mv X Y"),
-            execute: Self::mv_register,
-            to_string: Self::mv_register_to_string,
-        };
-
-        let or = Self {
-            desc: format!("or X Y"),
-            execute: Self::or_register,
-            to_string: Self::or_register_to_string,
-        };
-
-        let and = Self {
-            desc: format!("xor X Y"),
-            execute: Self::and_register,
-            to_string: Self::and_register_to_string,
-        };
+    /// Execute a decoded instruction, with the change in state being reflected in the registers
+    /// and memory. Instructions that do not move the PC themselves move it on to the next one.
+    fn execute(&mut self, opcode: Opcode, memory: &mut Memory) -> Result<(), ExecError> {
+        let registers = &mut self.registers;
+        let quirks = &self.quirks;
 
-        let xor = Self {
-            desc: format!("xor X Y"),
-            execute: Self::xor_register,
-            to_string: Self::xor_register_to_string,
-        };
+        match opcode {
+            Opcode::Cls => memory.clear_display(),
+            Opcode::Ret => {
+                trace!("ret");
+                registers.pc = Wrapping(registers.stack_pop16()?);
+                return Ok(());
+            }
+            Opcode::Sys(addr) => return Err(ExecError::UnsupportedMachineCall { pc: registers.pc.0, addr }),
+            Opcode::ScrollDown(n) => memory.scroll(0, n as isize),
+            Opcode::ScrollUp(n) => memory.scroll(0, -(n as isize)),
+            Opcode::ScrollRight => memory.scroll(4, 0),
+            Opcode::ScrollLeft => memory.scroll(-4, 0),
+            Opcode::Exit => {
+                // The PC is left on the exit instruction so the machine stays halted
+                registers.halted = true;
+                return Ok(());
+            }
+            Opcode::Lores => memory.set_hires(false),
+            Opcode::Hires => memory.set_hires(true),
+            Opcode::Jp(addr) => {
+                registers.pc = Wrapping(addr);
+                return Ok(());
+            }
+            Opcode::Call(addr) => {
+                trace!("call instr");
+                // Save the address of the next instruction, then jump to the subroutine
+                registers.stack_push16(registers.pc.0.wrapping_add(INSTRUCTION_SIZE))?;
+                registers.pc = Wrapping(addr);
+                return Ok(());
+            }
+            Opcode::SeImm(x, nn) => {
+                trace!("eq v{:x} {:x}", x, nn);
+                return Self::skip_if(registers, memory, registers.v[x as usize] == Wrapping(nn));
+            }
+            Opcode::SneImm(x, nn) => {
+                return Self::skip_if(registers, memory, registers.v[x as usize] != Wrapping(nn));
+            }
+            Opcode::SeReg(x, y) => {
+                trace!("eq v{:x} v{:x}", x, y);
+                return Self::skip_if(registers, memory, registers.v[x as usize] == registers.v[y as usize]);
+            }
+            Opcode::SneReg(x, y) => {
+                return Self::skip_if(registers, memory, registers.v[x as usize] != registers.v[y as usize]);
+            }
+            Opcode::SaveRange(x, y) => {
+                // I is left unchanged
+                for (offset, register) in Self::register_range(x, y).enumerate() {
+                    memory.set(registers.i.0 as usize + offset, registers.v[register])?;
+                }
+            }
+            Opcode::LoadRange(x, y) => {
+                for (offset, register) in Self::register_range(x, y).enumerate() {
+                    registers.v[register] = memory.get(registers.i.0 as usize + offset)?;
+                }
+            }
+            Opcode::LdImm(x, nn) => registers.v[x as usize] = Wrapping(nn),
+            Opcode::AddImm(x, nn) => registers.v[x as usize] += Wrapping(nn),
+            Opcode::LdReg(x, y) => registers.v[x as usize] = registers.v[y as usize],
+            Opcode::Or(x, y) | Opcode::And(x, y) | Opcode::Xor(x, y) => {
+                let (vx, vy) = (registers.v[x as usize], registers.v[y as usize]);
+                registers.v[x as usize] = match opcode {
+                    Opcode::Or(..) => vx | vy,
+                    Opcode::And(..) => vx & vy,
+                    _ => vx ^ vy,
+                };
+
+                if quirks.logic_resets_vf {
+                    registers.v[0xF] = Wrapping(0);
+                }
+            }
+            Opcode::AddReg(x, y) => {
+                let (result, carry) = registers.v[x as usize].0.overflowing_add(registers.v[y as usize].0);
+                Self::set_with_flag(registers, x, result, carry as u8);
+            }
+            Opcode::Sub(x, y) => {
+                // VF is set when there is no borrow
+                let (result, borrow) = registers.v[x as usize].0.overflowing_sub(registers.v[y as usize].0);
+                Self::set_with_flag(registers, x, result, !borrow as u8);
+            }
+            Opcode::Subn(x, y) => {
+                let (result, borrow) = registers.v[y as usize].0.overflowing_sub(registers.v[x as usize].0);
+                Self::set_with_flag(registers, x, result, !borrow as u8);
+            }
+            Opcode::Shr(x, y) => {
+                let source = registers.v[if quirks.shift_uses_vy { y } else { x } as usize].0;
+                Self::set_with_flag(registers, x, source >> 1, source & 0x1);
+            }
+            Opcode::Shl(x, y) => {
+                let source = registers.v[if quirks.shift_uses_vy { y } else { x } as usize].0;
+                Self::set_with_flag(registers, x, source << 1, source >> 7);
+            }
+            Opcode::LdI(addr) => registers.i = Wrapping(addr),
+            Opcode::JpV0(addr) => {
+                let register = if quirks.jump_uses_vx { (addr >> 8) as usize } else { 0 };
+                registers.pc = Wrapping(registers.v[register].0 as u16) + Wrapping(addr);
+                return Ok(());
+            }
+            Opcode::Rnd(x, mask) => registers.v[x as usize].0 = registers.rng.next_byte(memory)? & mask,
+            Opcode::Drw(x, y, n) => {
+                let (vx, vy) = (registers.v[x as usize].0 as usize, registers.v[y as usize].0 as usize);
+                registers.v[0xF] = Wrapping(memory.draw_sprite(vx, vy, n as usize, registers.i.0 as usize, quirks.clip_sprites)?);
+            }
+            Opcode::Skp(x) | Opcode::Sknp(x) => {
+                // Only the low nibble of the register selects a key, as on the COSMAC VIP
+                let pressed = registers.keys[(registers.v[x as usize].0 & 0x0F) as usize];
+                return Self::skip_if(registers, memory, pressed == matches!(opcode, Opcode::Skp(_)));
+            }
+            Opcode::LdILong => {
+                // The address is in the word after the opcode, making this a four byte instruction
+                registers.i = memory.get16((registers.pc + Wrapping(INSTRUCTION_SIZE)).0 as usize)?;
+                registers.inc_pc(2 * INSTRUCTION_SIZE);
+                return Ok(());
+            }
+            Opcode::Plane(n) => memory.planes = n & 0x3,
+            Opcode::Audio => {
+                for i in 0..registers.audio_pattern.len() {
+                    registers.audio_pattern[i] = memory.get(registers.i.0 as usize + i)?.0;
+                }
+            }
+            Opcode::LdVxDt(x) => registers.v[x as usize] = registers.delay,
+            Opcode::LdVxK(x) => registers.wait_for_key = Some(x as usize),
+            Opcode::LdDtVx(x) => registers.delay = registers.v[x as usize],
+            Opcode::LdStVx(x) => registers.sound = registers.v[x as usize],
+            Opcode::AddI(x) => registers.i += Wrapping(registers.v[x as usize].0 as u16),
+            Opcode::LdF(x) => {
+                registers.i.0 = SPRITE_ADDR as u16 + ((registers.v[x as usize].0 & 0x0F) as u16 * 5);
+            }
+            Opcode::LdHf(x) => {
+                registers.i.0 = BIG_SPRITE_ADDR as u16 + ((registers.v[x as usize].0 & 0x0F) as u16 * 10);
+            }
+            Opcode::Bcd(x) => {
+                let mut tmp = registers.v[x as usize];
 
-        let add = Self {
-            desc: format!("add X Y"),
-            execute: Self::add_register,
-            to_string: Self::add_register_to_string,
-        };
+                // Least significant digit
+                memory.set(registers.i.0 as usize + 2, tmp % Wrapping(10))?;
+                tmp /= Wrapping(10);
 
-        let sub = Self {
-            desc: format!("sub X Y"),
-            execute: Self::sub_register,
-            to_string: Self::sub_register_to_string,
-        };
+                // Middle digit
+                memory.set(registers.i.0 as usize + 1, tmp % Wrapping(10))?;
+                tmp /= Wrapping(10);
 
-        let shr = Self {
-            desc: format!("shr X Y"),
-            execute: Self::shr_register,
-            to_string: Self::shr_register_to_string,
-        };
+                // Most significant digit
+                memory.set(registers.i.0 as usize, tmp % Wrapping(10))?;
+            }
+            Opcode::Pitch(x) => registers.pitch = registers.v[x as usize],
+            Opcode::Save(x) => {
+                for i in 0..=x as usize {
+                    memory.set(registers.i.0 as usize + i, registers.v[i])?;
+                }
 
-        let rsub = Self {
-            desc: format!("rsub X Y"),
-            execute: Self::rev_sub_register,
-            to_string: Self::rev_sub_register_to_string,
-        };
+                if quirks.load_store_increments_i {
+                    registers.i += Wrapping(x as u16 + 1);
+                }
+            }
+            Opcode::Load(x) => {
+                for i in 0..=x as usize {
+                    registers.v[i] = memory.get(registers.i.0 as usize + i)?;
+                }
 
-        let shl = Self {
-            desc: format!("shl X Y"),
-            execute: Self::shl_register,
-            to_string: Self::shl_register_to_string,
-        };
+                if quirks.load_store_increments_i {
+                    registers.i += Wrapping(x as u16 + 1);
+                }
+            }
+            Opcode::SaveFlags(x) => registers.rpl[..=x as usize].copy_from_slice(&registers.v[..=x as usize]),
+            Opcode::LoadFlags(x) => registers.v[..=x as usize].copy_from_slice(&registers.rpl[..=x as usize]),
+        }
 
-        [mv, or, and, xor, add, sub, shr, rsub, shl]
+        registers.inc_pc(INSTRUCTION_SIZE);
+        Ok(())
     }
 
-    pub fn main_op_table() -> [Self; 16] {
-        let mcall_instruction = Self {
-            desc: format!("call XXX"),
-            execute: Self::mcall_display_or_flow,
-            to_string: Self::mcall_display_or_flow_to_string,
-        };
-
-        let goto_instruction = Self {
-            desc: format!("goto NNN"),
-            execute: Self::goto,
-            to_string: Self::goto_to_string,
-        };
-
-        let call_instruction = Self {
-            desc: format!("call NNN"),
-            execute: Self::call,
-            to_string: Self::call_to_string,
-        };
-
-        let reg_eq = Self {
-            desc: format!("eq vX II"),
-            execute: Self::reg_equal,
-            to_string: Self::reg_equal_to_string,
-        };
-
-        let reg_neq = Self {
-            desc: format!("neq vX II"),
-            execute: Self::reg_not_equal,
-            to_string: Self::reg_not_equal_to_string,
-        };
-
-        let two_reg_eq = Self {
-            desc: format!("eq Vx Vy"),
-            execute: Self::two_reg_equal,
-            to_string: Self::two_reg_equal_to_string,
-        };
-
-        let load_immediate = Self {
-            desc: format!("ld Vx II"),
-            execute: Self::load_immediate,
-            to_string: Self::load_immediate_to_string,
-        };
-
-        let add_immediate = Self {
-            desc: format!("add Vx II"),
-            execute: Self::add_immediate,
-            to_string: Self::add_immediate_to_string,
-        };
-
-        let math_or_bitop = Self {
-            desc: format!("math or bitop"),
-            execute: Self::math_or_bitop,
-            to_string: Self::math_or_bitop_to_string,
-        };
-
-        let two_reg_not_equal = Self {
-            desc: format!("neq Vx Vy"),
-            execute: Self::two_registers_not_equal,
-            to_string: Self::two_registers_not_equal_to_string,
-        };
-
-        let set_i = Self {
-            desc: format!("ld I, NNN"),
-            execute: Self::set_i,
-            to_string: Self::set_i_to_string,
-        };
-
-        let jump_imm_plus_register = Self {
-            desc: format!("jmp III + Vx"),
-            execute: Self::jump_immediate_plus_register,
-            to_string: Self::jump_immediate_plus_register_to_string,
-        };
-
-        let masked_random = Self {
-            desc: format!("rand Vx & II"),
-            execute: Self::masked_random,
-            to_string: Self::masked_random_to_string,
-        };
-
-        let draw_sprite = Self {
-            desc: format!("draw_sprite"),
-            execute: Self::draw_sprite,
-            to_string: Self::draw_sprite_to_string,
-        };
-
-        let key_op = Self {
-            desc: format!("key"),
-            execute: Self::key_op,
-            to_string: Self::key_op_to_string,
-        };
-
-        let load_or_store = Self {
-            desc: format!("load or store"),
-            execute: Self::load_or_store,
-            to_string: Self::load_or_store_to_string,
-        };
-
-        [
-            mcall_instruction,
-            goto_instruction,
-            call_instruction,
-            reg_eq,
-            reg_neq,
-            two_reg_eq,
-            load_immediate,
-            add_immediate,
-            math_or_bitop,
-            two_reg_not_equal,
-            set_i,
-            jump_imm_plus_register,
-            masked_random,
-            draw_sprite,
-            key_op,
-            load_or_store,
-        ]
+    /// Skip the next instruction if the condition holds, otherwise move on to it. The XO-CHIP
+    /// F000 NNNN long load is four bytes long, so it is skipped over as a whole.
+    fn skip_if(registers: &mut Registers, memory: &Memory, condition: bool) -> Result<(), ExecError> {
+        if condition {
+            let next_opcode = memory.get16((registers.pc + Wrapping(INSTRUCTION_SIZE)).0 as usize)?.0;
+            registers.inc_pc(if next_opcode == LONG_LOAD_OPCODE { 3 } else { 2 } * INSTRUCTION_SIZE);
+        } else {
+            registers.inc_pc(INSTRUCTION_SIZE);
+        }
+        Ok(())
     }
-}
-
-/// The CPU holds the current program registers and the instruction op tables
-pub struct Cpu {
-    pub registers: Registers,
-    pub op_tables: OpTables,
-}
 
-impl Cpu {
+    /// Write the result of an arithmetic instruction to VX and the flag to VF. The flag is written
+    /// last so that it wins if the destination register is VF.
+    fn set_with_flag(registers: &mut Registers, x: u8, result: u8, flag: u8) {
+        registers.v[x as usize].0 = result;
+        registers.v[0xF].0 = flag;
+    }
 
-    /// Create a fresh CPU instance with 0 / false set for all registers and PC set to 0x200 (the
-    /// typical ROM start location)
-    pub fn new() -> Self {
-        Self {
-            registers: Registers {
-                pc: Wrapping(0x200),
-                v: [Wrapping(0); 16],
-                i: Wrapping(0),
-                stack: [Wrapping(0); 256],
-                stack_idx: 0,
-                delay: Wrapping(0),
-                sound: Wrapping(0),
-                rng: rand::thread_rng(),
-                keys: [false; NUM_KEYS],
-                wait_for_key: None,
-            },
-            op_tables: OpTables {
-                main_op_table: Instruction::main_op_table(),
-                math_op_table: Instruction::math_op_table(),
-                load_op_table: Instruction::load_op_table(),
-            },
-        }
+    /// The registers from X to Y inclusive, counting down if Y is less than X
+    fn register_range(x: u8, y: u8) -> impl Iterator<Item = usize> {
+        let (x, y) = (x as usize, y as usize);
+        (0..=x.abs_diff(y)).map(move |offset| if x <= y { x + offset } else { x - offset })
     }
 
-    pub fn step(&mut self, memory: &mut Memory) {
-        let next_opcode = memory.get16(self.registers.pc.0 as usize).0;
-        let op_id = ((next_opcode & 0xF000) >> 12) as usize;
+    /// The approximate number of COSMAC VIP machine cycles taken to execute an opcode. Taken skips
+    /// cost more, as do sprites and register saves and loads, depending on their operands.
+    pub fn cycles(&self, opcode: u16, skipped: bool) -> u32 {
+        let cycles = match Opcode::decode(opcode) {
+            Ok(decoded @ (Opcode::Save(x) | Opcode::Load(x))) => Self::base_cycles(decoded) + VIP_CYCLES_PER_REGISTER * (x as u32 + 1),
+            Ok(decoded @ Opcode::Drw(_, _, n)) => Self::base_cycles(decoded) + VIP_CYCLES_PER_SPRITE_ROW * n as u32,
+            Ok(decoded) => Self::base_cycles(decoded),
+            Err(_) => 0,
+        };
 
-        // TODO: Strip this
-        let instr_tostring = (self.op_tables.main_op_table[op_id].to_string)(
-            next_opcode & 0x0FFF,
-            &self.op_tables,
-        );
+        cycles + if skipped { VIP_SKIP_CYCLES } else { 0 }
+    }
+
+    /// The COSMAC VIP machine cycles an instruction takes before any extra cost that depends on
+    /// the operands. Zero for the instructions the VIP does not have.
+    fn base_cycles(opcode: Opcode) -> u32 {
+        match opcode {
+            Opcode::Cls => VIP_CLEAR_CYCLES,
+            Opcode::Ret | Opcode::Sys(_) | Opcode::Jp(_) | Opcode::Call(_) | Opcode::JpV0(_) => 23,
+            Opcode::LdImm(..) => 6,
+            Opcode::SeImm(..) | Opcode::SneImm(..) | Opcode::AddImm(..) => 10,
+            Opcode::LdVxDt(_) | Opcode::LdVxK(_) | Opcode::LdDtVx(_) | Opcode::LdStVx(_) => 10,
+            Opcode::LdI(_) => 12,
+            Opcode::SeReg(..) | Opcode::SneReg(..) | Opcode::Skp(_) | Opcode::Sknp(_) => 16,
+            Opcode::AddI(_) => 19,
+            Opcode::LdF(_) => 20,
+            Opcode::Drw(..) => 26,
+            Opcode::Rnd(..) => 36,
+            Opcode::LdReg(..) | Opcode::Or(..) | Opcode::And(..) | Opcode::Xor(..) => 44,
+            Opcode::AddReg(..) | Opcode::Sub(..) | Opcode::Shr(..) | Opcode::Subn(..) | Opcode::Shl(..) => 44,
+            Opcode::Save(_) | Opcode::Load(_) => 133,
+            Opcode::Bcd(_) => 204,
+            _ => 0,
+        }
+    }
+}
 
-        trace!("PC: {:x} ID: {:x} DATA: {:x} {}", self.registers.pc, op_id, next_opcode & 0x0FFF, instr_tostring);
-        (self.op_tables.main_op_table[op_id].execute)(
-            &mut self.registers,
-            memory,
-            next_opcode & 0x0FFF,
-            &self.op_tables,
-        );
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[cfg(test)]
 mod instruction_tests {
-    use crate::cpu::Cpu;
+    use crate::cpu::{Cpu, StepOutcome};
     use crate::cpu::Memory;
+    use crate::error::ExecError;
+    use crate::quirks::Quirks;
     use log::info;
     use std::num::Wrapping;
 
@@ -1086,6 +530,12 @@ mod instruction_tests {
         cpu
     }
 
+    fn prepare_cpu_with_quirks(quirks: Quirks) -> Cpu {
+        let mut cpu = Cpu::with_quirks(quirks);
+        cpu.registers.pc.0 = 0x0;
+        cpu
+    }
+
     fn assemble_goto(data: &mut [u8], address: u16) {
         data[0] = (1 << 4) | ((address >> 8) & 0x0F) as u8;
         data[1] = (address & 0x00FF) as u8;
@@ -1169,7 +619,7 @@ mod instruction_tests {
     }
 
     fn assemble_reg_shl(data: &mut [u8], dst: u8, src: u8) {
-        math_bitop_core(data, dst, src, 8);
+        math_bitop_core(data, dst, src, 0xE);
     }
 
     fn assemble_set_i(data: &mut [u8], dst: u16) {
@@ -1184,7 +634,7 @@ mod instruction_tests {
 
     fn assemble_get_delay(data: &mut [u8], reg: u8) {
         data[0] = (0xF << 4) | reg;
-        data[1] = 07;
+        data[1] = 0x07;
     }
 
     fn assemble_set_delay(data: &mut [u8], reg: u8) {
@@ -1207,6 +657,53 @@ mod instruction_tests {
         data[1] = 0x33;
     }
 
+    fn assemble_sys(data: &mut [u8], code: u8) {
+        data[0] = 0x00;
+        data[1] = code;
+    }
+
+    fn assemble_big_sprite_addr(data: &mut [u8], reg: u8) {
+        data[0] = (0xF << 4) | reg;
+        data[1] = 0x30;
+    }
+
+    fn assemble_save_flags(data: &mut [u8], reg: u8) {
+        data[0] = (0xF << 4) | reg;
+        data[1] = 0x75;
+    }
+
+    fn assemble_load_flags(data: &mut [u8], reg: u8) {
+        data[0] = (0xF << 4) | reg;
+        data[1] = 0x85;
+    }
+
+    fn assemble_range(data: &mut [u8], reg: u8, reg2: u8, op: u8) {
+        data[0] = (5 << 4) | (reg & 0x0F);
+        data[1] = (reg2 << 4) | op;
+    }
+
+    fn assemble_long_set_i(data: &mut [u8], address: u16) {
+        data[0] = 0xF0;
+        data[1] = 0x00;
+        data[2] = (address >> 8) as u8;
+        data[3] = (address & 0xFF) as u8;
+    }
+
+    fn assemble_plane(data: &mut [u8], planes: u8) {
+        data[0] = (0xF << 4) | planes;
+        data[1] = 0x01;
+    }
+
+    fn assemble_reg_dump(data: &mut [u8], reg: u8) {
+        data[0] = (0xF << 4) | reg;
+        data[1] = 0x55;
+    }
+
+    fn assemble_reg_load(data: &mut [u8], reg: u8) {
+        data[0] = (0xF << 4) | reg;
+        data[1] = 0x65;
+    }
+
     #[test]
     fn mv() {
         let mut program = [0; 256];
@@ -1214,7 +711,7 @@ mod instruction_tests {
         let mut memory = Memory::of_bytes(&program, 0x0);
         let mut cpu = prepare_cpu();
         cpu.registers.v[0x4].0 = 40;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.v[0x2].0, 40);
         assert_eq!(cpu.registers.v[0x4].0, 40);
@@ -1229,7 +726,7 @@ mod instruction_tests {
         let mut cpu = prepare_cpu();
         cpu.registers.v[0x2].0 = 64;
         cpu.registers.v[0x4].0 = 40;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.v[0x2].0, 40 | 64);
         assert_eq!(cpu.registers.v[0x4].0, 40);
@@ -1244,7 +741,7 @@ mod instruction_tests {
         let mut cpu = prepare_cpu();
         cpu.registers.v[0x2].0 = 64;
         cpu.registers.v[0x4].0 = 40;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.v[0x2].0, 40 & 64);
         assert_eq!(cpu.registers.v[0x4].0, 40);
@@ -1259,7 +756,7 @@ mod instruction_tests {
         let mut cpu = prepare_cpu();
         cpu.registers.v[0x2].0 = 64;
         cpu.registers.v[0x4].0 = 40;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.v[0x2].0, 40 ^ 64);
         assert_eq!(cpu.registers.v[0x4].0, 40);
@@ -1273,7 +770,7 @@ mod instruction_tests {
         let mut memory = Memory::of_bytes(&program, 0x0);
         let mut cpu = prepare_cpu();
         cpu.registers.v[0x2].0 = 64;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.v[0x2].0, 32);
         assert_eq!(cpu.registers.pc.0, 0x002);
@@ -1286,7 +783,7 @@ mod instruction_tests {
         let mut memory = Memory::of_bytes(&program, 0x0);
         let mut cpu = prepare_cpu();
         cpu.registers.v[0x2].0 = 64;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.v[0x2].0, 128);
         assert_eq!(cpu.registers.pc.0, 0x002);
@@ -1301,7 +798,7 @@ mod instruction_tests {
         cpu.registers.v[0x2].0 = 64;
         cpu.registers.v[0x4].0 = 40;
         cpu.registers.v[0xF].0 = 40;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.v[0x2].0, 40 + 64);
         assert_eq!(cpu.registers.v[0x4].0, 40);
@@ -1318,7 +815,7 @@ mod instruction_tests {
         cpu.registers.v[0x2].0 = 128;
         cpu.registers.v[0x4].0 = 128;
         cpu.registers.v[0xF].0 = 40;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.v[0x2], Wrapping(128_u8) + Wrapping(128_u8));
         assert_eq!(cpu.registers.v[0x4].0, 128);
@@ -1335,11 +832,11 @@ mod instruction_tests {
         cpu.registers.v[0x2].0 = 64;
         cpu.registers.v[0x4].0 = 40;
         cpu.registers.v[0xF].0 = 40;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.v[0x2].0, 64 - 40);
         assert_eq!(cpu.registers.v[0x4].0, 40);
-        assert_eq!(cpu.registers.v[0xF].0, 0);
+        assert_eq!(cpu.registers.v[0xF].0, 1);
         assert_eq!(cpu.registers.pc.0, 0x002);
     }
 
@@ -1352,14 +849,72 @@ mod instruction_tests {
         cpu.registers.v[0x2].0 = 64;
         cpu.registers.v[0x4].0 = 128;
         cpu.registers.v[0xF].0 = 40;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.v[0x2], Wrapping(64_u8) + Wrapping(128_u8));
         assert_eq!(cpu.registers.v[0x4].0, 128);
-        assert_eq!(cpu.registers.v[0xF].0, 1);
+        assert_eq!(cpu.registers.v[0xF].0, 0);
         assert_eq!(cpu.registers.pc.0, 0x002);
     }
 
+    #[test]
+    fn sub_reg_flag_wins_over_result() {
+        // vf -= v4 leaves the no borrow flag in VF rather than the difference
+        let mut program = [0; 256];
+        assemble_reg_sub(&mut program, 0xF, 0x4);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.registers.v[0xF].0 = 64;
+        cpu.registers.v[0x4].0 = 40;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.v[0xF].0, 1);
+    }
+
+    #[test]
+    fn sub_reg_equal_does_not_borrow() {
+        let mut program = [0; 256];
+        assemble_reg_sub(&mut program, 0x2, 0x4);
+        assemble_reg_rsub(&mut program[2..], 0x3, 0x4);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.registers.v[0x2].0 = 40;
+        cpu.registers.v[0x3].0 = 40;
+        cpu.registers.v[0x4].0 = 40;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.v[0x2].0, 0);
+        assert_eq!(cpu.registers.v[0xF].0, 1);
+        cpu.registers.v[0xF].0 = 0;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.v[0x3].0, 0);
+        assert_eq!(cpu.registers.v[0xF].0, 1);
+    }
+
+    #[test]
+    fn add_reg_flag_wins_over_result() {
+        // vf += v4 leaves the carry in VF rather than the sum
+        let mut program = [0; 256];
+        assemble_reg_add(&mut program, 0xF, 0x4);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.registers.v[0xF].0 = 200;
+        cpu.registers.v[0x4].0 = 100;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.v[0xF].0, 1);
+    }
+
+    #[test]
+    fn rsub_reg_flag_wins_over_result() {
+        // vf =- v4 leaves the no borrow flag in VF rather than the difference
+        let mut program = [0; 256];
+        assemble_reg_rsub(&mut program, 0xF, 0x4);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.registers.v[0xF].0 = 64;
+        cpu.registers.v[0x4].0 = 40;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.v[0xF].0, 0);
+    }
+
     #[test]
     fn rsub_reg() {
         let mut program = [0; 256];
@@ -1369,11 +924,11 @@ mod instruction_tests {
         cpu.registers.v[0x2].0 = 40;
         cpu.registers.v[0x4].0 = 64;
         cpu.registers.v[0xF].0 = 40;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.v[0x2].0, 64 - 40);
         assert_eq!(cpu.registers.v[0x4].0, 64);
-        assert_eq!(cpu.registers.v[0xF].0, 0);
+        assert_eq!(cpu.registers.v[0xF].0, 1);
         assert_eq!(cpu.registers.pc.0, 0x002);
     }
 
@@ -1386,11 +941,11 @@ mod instruction_tests {
         cpu.registers.v[0x2].0 = 128;
         cpu.registers.v[0x4].0 = 64;
         cpu.registers.v[0xF].0 = 40;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.v[0x2], Wrapping(64_u8) + Wrapping(128_u8));
         assert_eq!(cpu.registers.v[0x4].0, 64);
-        assert_eq!(cpu.registers.v[0xF].0, 1);
+        assert_eq!(cpu.registers.v[0xF].0, 0);
         assert_eq!(cpu.registers.pc.0, 0x002);
     }
 
@@ -1400,7 +955,7 @@ mod instruction_tests {
         assemble_goto(&mut program, 0xAF);
         let mut memory = Memory::of_bytes(&program, 0x0);
         let mut cpu = prepare_cpu();
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert!(cpu.registers.pc == Wrapping(0x00AF));
         assert_eq!(cpu.registers.stack_idx, 0);
@@ -1415,7 +970,7 @@ mod instruction_tests {
         // Mark the stack location we expect to get overwritten to be non-zero
         cpu.registers.stack[0] = Wrapping(0xAA);
         cpu.registers.stack[1] = Wrapping(0xBB);
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.stack_idx, 2);
         assert_eq!(cpu.registers.stack[0], Wrapping(0x00));
@@ -1423,6 +978,18 @@ mod instruction_tests {
         assert_eq!(cpu.registers.pc, Wrapping(0xADE));
     }
 
+    #[test]
+    fn call_at_end_of_memory() {
+        // The return address wraps around to the start of memory like the PC
+        let mut program = [0; 2];
+        assemble_call(&mut program, 0x300);
+        let mut memory = Memory::of_bytes(&program, 0xFFFE);
+        let mut cpu = prepare_cpu();
+        cpu.registers.pc.0 = 0xFFFE;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.stack_pop16(), Ok(0x0000));
+    }
+
     #[test]
     fn reg_eq_imm() {
         let mut program = [0; 256];
@@ -1433,13 +1000,13 @@ mod instruction_tests {
 
         cpu.registers.v[5] = Wrapping(0xFE);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.pc.0, 0x04);
 
         cpu.registers.pc = Wrapping(0);
         cpu.registers.v[5] = Wrapping(0xAE);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.pc.0, 0x02);
     }
 
@@ -1453,13 +1020,13 @@ mod instruction_tests {
 
         cpu.registers.v[5] = Wrapping(0xFE);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.pc.0, 0x02);
 
         cpu.registers.pc = Wrapping(0);
         cpu.registers.v[5] = Wrapping(0xAE);
 
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.pc.0, 0x04);
     }
 
@@ -1471,11 +1038,11 @@ mod instruction_tests {
         let mut cpu = prepare_cpu();
         cpu.registers.v[0x7] = Wrapping(0xFE);
         cpu.registers.v[0xF] = Wrapping(0xAA);
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.pc.0, 2);
         cpu.registers.pc.0 = 0x0;
         cpu.registers.v[0xF] = Wrapping(0xFE);
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.pc.0, 4);
     }
 
@@ -1487,11 +1054,11 @@ mod instruction_tests {
         let mut cpu = prepare_cpu();
         cpu.registers.v[0x7] = Wrapping(0xFE);
         cpu.registers.v[0xF] = Wrapping(0xAA);
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.pc.0, 4);
         cpu.registers.pc.0 = 0x0;
         cpu.registers.v[0xF] = Wrapping(0xFE);
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.pc.0, 2);
     }
 
@@ -1502,7 +1069,7 @@ mod instruction_tests {
 
         let mut memory = Memory::of_bytes(&program, 0x0);
         let mut cpu = prepare_cpu();
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.v[7].0, 0xFE);
         assert_eq!(cpu.registers.pc.0, 0x2);
     }
@@ -1515,10 +1082,10 @@ mod instruction_tests {
 
         let mut memory = Memory::of_bytes(&program, 0x0);
         let mut cpu = prepare_cpu();
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.v[3].0, 0x2);
         assert_eq!(cpu.registers.pc.0, 0x2);
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.v[3].0, 0xA);
 
         assert_eq!(cpu.registers.pc.0, 0x4);
@@ -1531,7 +1098,7 @@ mod instruction_tests {
 
         let mut memory = Memory::of_bytes(&program, 0x0);
         let mut cpu = prepare_cpu();
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.i.0, 0x8FE);
         assert_eq!(cpu.registers.pc.0, 0x2);
     }
@@ -1543,7 +1110,7 @@ mod instruction_tests {
         let mut memory = Memory::of_bytes(&program, 0x0);
         let mut cpu = prepare_cpu();
         cpu.registers.delay.0 = 0x40;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.v[0x3].0, 0x40);
         assert_eq!(cpu.registers.pc.0, 0x2);
     }
@@ -1555,7 +1122,7 @@ mod instruction_tests {
         let mut memory = Memory::of_bytes(&program, 0x0);
         let mut cpu = prepare_cpu();
         cpu.registers.v[0x3].0 = 0x69;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.delay.0, 0x69);
         assert_eq!(cpu.registers.pc.0, 0x2);
     }
@@ -1567,7 +1134,7 @@ mod instruction_tests {
         let mut memory = Memory::of_bytes(&program, 0x0);
         let mut cpu = prepare_cpu();
         cpu.registers.v[0x3].0 = 0x69;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.sound.0, 0x69);
         assert_eq!(cpu.registers.pc.0, 0x2);
     }
@@ -1580,7 +1147,7 @@ mod instruction_tests {
         let mut cpu = prepare_cpu();
         cpu.registers.v[0x3].0 = 0x69;
         cpu.registers.i.0 = 0x40;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.i.0, 0x40 + 0x69);
         assert_eq!(cpu.registers.pc.0, 0x2);
     }
@@ -1593,16 +1160,32 @@ mod instruction_tests {
         let mut cpu = prepare_cpu();
         cpu.registers.v[0x3].0 = 146;
         cpu.registers.i.0 = 0x40;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
 
-        assert_eq!(memory.get(0x40).0, 1);
-        assert_eq!(memory.get(0x41).0, 4);
-        assert_eq!(memory.get(0x42).0, 6);
+        assert_eq!(memory.get(0x40).unwrap().0, 1);
+        assert_eq!(memory.get(0x41).unwrap().0, 4);
+        assert_eq!(memory.get(0x42).unwrap().0, 6);
 
-        assert_eq!(cpu.registers.i.0, 0x40 + 3);
+        assert_eq!(cpu.registers.i.0, 0x40);
         assert_eq!(cpu.registers.pc.0, 0x2);
     }
 
+    #[test]
+    fn bcd_leaves_i_alone() {
+        // No interpreter moves I on FX33, whatever it does for FX55 and FX65
+        for quirks in [Quirks::cosmac_vip(), Quirks::chip48(), Quirks::super_chip(), Quirks::xo_chip(), Quirks::modern()] {
+            let mut program = [0; 256];
+            assemble_bcd(&mut program, 0x3);
+            let mut memory = Memory::of_bytes(&program, 0x0);
+            let mut cpu = prepare_cpu_with_quirks(quirks);
+            cpu.registers.v[0x3].0 = 255;
+            cpu.registers.i.0 = 0x80;
+            cpu.step(&mut memory).unwrap();
+            assert_eq!(cpu.registers.i.0, 0x80);
+            assert_eq!(memory.get(0x80).unwrap().0, 2);
+        }
+    }
+
     #[test]
     fn pc_plus_reg() {
         let mut program = [0; 256];
@@ -1610,7 +1193,7 @@ mod instruction_tests {
         let mut memory = Memory::of_bytes(&program, 0x0);
         let mut cpu = prepare_cpu();
         cpu.registers.v[0].0 = 0xFF;
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         assert_eq!(cpu.registers.pc.0, 0x8FE + 0xFF);
     }
 
@@ -1624,15 +1207,369 @@ mod instruction_tests {
         // Mark the stack location we expect to get overwritten to be non-zero
         cpu.registers.stack[0] = Wrapping(0xAA);
         cpu.registers.stack[1] = Wrapping(0xBB);
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.stack_idx, 2);
         assert_eq!(cpu.registers.stack[0], Wrapping(0x00));
         assert_eq!(cpu.registers.stack[1], Wrapping(0x02));
         assert_eq!(cpu.registers.pc, Wrapping(0x10));
-        cpu.step(&mut memory);
+        cpu.step(&mut memory).unwrap();
         info!("{:?}", cpu.registers);
         assert_eq!(cpu.registers.stack_idx, 0);
         assert_eq!(cpu.registers.pc, Wrapping(0x02));
     }
+
+    #[test]
+    fn shl_sets_carry() {
+        let mut program = [0; 256];
+        assemble_reg_shl(&mut program, 0x2, 0x0);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.registers.v[0x2].0 = 0x81;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.v[0x2].0, 0x02);
+        assert_eq!(cpu.registers.v[0xF].0, 1);
+    }
+
+    #[test]
+    fn shift_uses_vy_quirk() {
+        let mut program = [0; 256];
+        assemble_reg_shr(&mut program, 0x2, 0x4);
+        assemble_reg_shl(&mut program[2..], 0x3, 0x4);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu_with_quirks(Quirks::cosmac_vip());
+        cpu.registers.v[0x2].0 = 0xFF;
+        cpu.registers.v[0x3].0 = 0xFF;
+        cpu.registers.v[0x4].0 = 0x41;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.v[0x2].0, 0x20);
+        assert_eq!(cpu.registers.v[0xF].0, 1);
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.v[0x3].0, 0x82);
+        assert_eq!(cpu.registers.v[0xF].0, 0);
+        assert_eq!(cpu.registers.v[0x4].0, 0x41);
+    }
+
+    #[test]
+    fn shift_flag_wins_over_result() {
+        let mut program = [0; 256];
+        assemble_reg_shr(&mut program, 0xF, 0x0);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.registers.v[0xF].0 = 0x3;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.v[0xF].0, 1);
+    }
+
+    #[test]
+    fn logic_resets_vf_quirk() {
+        let mut program = [0; 256];
+        assemble_reg_or(&mut program, 0x2, 0x4);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+
+        let mut cpu = prepare_cpu();
+        cpu.registers.v[0xF].0 = 0x7;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.v[0xF].0, 0x7);
+
+        let mut cpu = prepare_cpu_with_quirks(Quirks::cosmac_vip());
+        cpu.registers.v[0xF].0 = 0x7;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.v[0xF].0, 0);
+    }
+
+    #[test]
+    fn jump_uses_vx_quirk() {
+        let mut program = [0; 256];
+        assemble_pc_plus_r(&mut program, 0x2FE);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu_with_quirks(Quirks::super_chip());
+        cpu.registers.v[0].0 = 0x10;
+        cpu.registers.v[2].0 = 0x01;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.pc.0, 0x2FE + 0x01);
+    }
+
+    #[test]
+    fn reg_dump_and_load() {
+        let mut program = [0; 256];
+        assemble_reg_dump(&mut program, 0x2);
+        assemble_reg_load(&mut program[2..], 0x2);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.registers.v[0].0 = 1;
+        cpu.registers.v[1].0 = 2;
+        cpu.registers.v[2].0 = 3;
+        cpu.registers.v[3].0 = 4;
+        cpu.registers.i.0 = 0x80;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(memory.get(0x80).unwrap().0, 1);
+        assert_eq!(memory.get(0x82).unwrap().0, 3);
+        assert_eq!(memory.get(0x83).unwrap().0, 0);
+        assert_eq!(cpu.registers.i.0, 0x83);
+
+        cpu.registers.i.0 = 0x81;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.v[0].0, 2);
+        assert_eq!(cpu.registers.v[1].0, 3);
+        assert_eq!(cpu.registers.v[2].0, 0);
+        assert_eq!(cpu.registers.v[3].0, 4);
+        assert_eq!(cpu.registers.i.0, 0x84);
+    }
+
+    #[test]
+    fn load_store_increments_i_quirk() {
+        let mut program = [0; 256];
+        assemble_reg_dump(&mut program, 0x2);
+        assemble_reg_load(&mut program[2..], 0x2);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu_with_quirks(Quirks::super_chip());
+        cpu.registers.i.0 = 0x80;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.i.0, 0x80);
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.i.0, 0x80);
+    }
+
+    #[test]
+    fn resolution_switch() {
+        let mut program = [0; 256];
+        assemble_sys(&mut program, 0xFF);
+        assemble_sys(&mut program[2..], 0xFE);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.step(&mut memory).unwrap();
+        assert!(memory.hires);
+        assert_eq!(memory.width(), 128);
+        assert_eq!(memory.height(), 64);
+        cpu.step(&mut memory).unwrap();
+        assert!(!memory.hires);
+        assert_eq!(cpu.registers.pc.0, 0x4);
+    }
+
+    #[test]
+    fn scroll_down() {
+        let mut program = [0; 256];
+        assemble_sys(&mut program, 0xC3);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        memory.frame_buffer[5] = 1;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(memory.pixel(5, 0), 0);
+        assert_eq!(memory.pixel(5, 3), 1);
+        assert_eq!(cpu.registers.pc.0, 0x2);
+    }
+
+    #[test]
+    fn exit() {
+        let mut program = [0; 256];
+        assemble_sys(&mut program, 0xFD);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.step(&mut memory).unwrap();
+        assert!(cpu.registers.halted);
+        assert_eq!(cpu.registers.pc.0, 0x0);
+    }
+
+    #[test]
+    fn big_sprite_addr() {
+        let mut program = [0; 256];
+        assemble_big_sprite_addr(&mut program, 0x3);
+        let mut memory = Memory::of_bytes(&program, 0x200);
+        let mut cpu = Cpu::new();
+        cpu.registers.v[0x3].0 = 0x1;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(memory.get(cpu.registers.i.0 as usize).unwrap().0, 0x18);
+        assert_eq!(memory.get(cpu.registers.i.0 as usize + 1).unwrap().0, 0x78);
+    }
+
+    #[test]
+    fn save_and_load_flags() {
+        let mut program = [0; 256];
+        assemble_save_flags(&mut program, 0x2);
+        assemble_load_flags(&mut program[2..], 0x3);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.registers.v[0].0 = 1;
+        cpu.registers.v[1].0 = 2;
+        cpu.registers.v[2].0 = 3;
+        cpu.registers.v[3].0 = 4;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.rpl[2].0, 3);
+        assert_eq!(cpu.registers.rpl[3].0, 0);
+        cpu.registers.v[0].0 = 0xFF;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.v[0].0, 1);
+        assert_eq!(cpu.registers.v[3].0, 0);
+        assert_eq!(cpu.registers.pc.0, 0x4);
+    }
+
+    #[test]
+    fn save_and_load_range() {
+        let mut program = [0; 256];
+        assemble_range(&mut program, 0x3, 0x1, 0x2);
+        assemble_range(&mut program[2..], 0x4, 0x6, 0x3);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.registers.v[1].0 = 1;
+        cpu.registers.v[2].0 = 2;
+        cpu.registers.v[3].0 = 3;
+        cpu.registers.i.0 = 0x80;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(memory.get(0x80).unwrap().0, 3);
+        assert_eq!(memory.get(0x81).unwrap().0, 2);
+        assert_eq!(memory.get(0x82).unwrap().0, 1);
+        assert_eq!(cpu.registers.i.0, 0x80);
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.v[4].0, 3);
+        assert_eq!(cpu.registers.v[5].0, 2);
+        assert_eq!(cpu.registers.v[6].0, 1);
+        assert_eq!(cpu.registers.pc.0, 0x4);
+    }
+
+    #[test]
+    fn long_set_i() {
+        let mut program = [0; 256];
+        assemble_long_set_i(&mut program, 0xBEEF);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.i.0, 0xBEEF);
+        assert_eq!(cpu.registers.pc.0, 0x4);
+    }
+
+    #[test]
+    fn skip_long_set_i() {
+        let mut program = [0; 256];
+        assemble_reg_eq_imm(&mut program, 0, 0);
+        assemble_long_set_i(&mut program[2..], 0xBEEF);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.pc.0, 0x6);
+    }
+
+    #[test]
+    fn select_planes() {
+        let mut program = [0; 256];
+        assemble_plane(&mut program, 0x3);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(memory.planes, 0x3);
+        assert_eq!(cpu.registers.pc.0, 0x2);
+    }
+
+    #[test]
+    fn audio_pattern_and_pitch() {
+        let mut program = [0; 256];
+        program[0] = 0xF0;
+        program[1] = 0x02;
+        program[2] = 0xF5;
+        program[3] = 0x3A;
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        for i in 0..16 {
+            memory.set(0x80 + i, Wrapping(i as u8)).unwrap();
+        }
+        cpu.registers.i.0 = 0x80;
+        cpu.registers.v[5].0 = 0x70;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.audio_pattern[15], 15);
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(cpu.registers.pitch.0, 0x70);
+    }
+
+    #[test]
+    fn scroll_up() {
+        let mut program = [0; 256];
+        assemble_sys(&mut program, 0xD2);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        memory.frame_buffer[5 + 64 * 3] = 1;
+        cpu.step(&mut memory).unwrap();
+        assert_eq!(memory.pixel(5, 1), 1);
+        assert_eq!(memory.pixel(5, 3), 0);
+    }
+
+    #[test]
+    fn invalid_opcode() {
+        let mut program = [0; 256];
+        math_bitop_core(&mut program, 0x1, 0x2, 0x9);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        assert_eq!(cpu.step(&mut memory), Err(ExecError::InvalidOpcode { pc: 0x0, opcode: 0x8129 }));
+        assert_eq!(cpu.registers.pc.0, 0x0);
+    }
+
+    #[test]
+    fn unsupported_machine_call() {
+        let mut program = [0; 256];
+        program[0] = 0x01;
+        program[1] = 0x23;
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        assert_eq!(cpu.step(&mut memory), Err(ExecError::UnsupportedMachineCall { pc: 0x0, addr: 0x123 }));
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let mut program = [0; 256];
+        assemble_ret(&mut program);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        assert_eq!(cpu.step(&mut memory), Err(ExecError::StackUnderflow { pc: 0x0 }));
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut program = [0; 256];
+        assemble_call(&mut program, 0x0);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        for _ in 0..(cpu.registers.stack.len() / 2) {
+            cpu.step(&mut memory).unwrap();
+        }
+        assert_eq!(cpu.step(&mut memory), Err(ExecError::StackOverflow { pc: 0x0 }));
+    }
+
+    #[test]
+    fn memory_out_of_bounds() {
+        let mut program = [0; 256];
+        assemble_reg_dump(&mut program, 0x2);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.registers.i.0 = 0xFFFF;
+        assert_eq!(cpu.step(&mut memory), Err(ExecError::MemoryOutOfBounds { addr: 0x10000 }));
+    }
+
+    #[test]
+    fn bcd_out_of_bounds() {
+        // The digits past the end of memory are not wrapped around to the start
+        let mut program = [0; 256];
+        assemble_bcd(&mut program, 0x3);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        cpu.registers.v[0x3].0 = 123;
+        cpu.registers.i.0 = 0xFFFE;
+        assert_eq!(cpu.step(&mut memory), Err(ExecError::MemoryOutOfBounds { addr: 0x10000 }));
+        assert_eq!(memory.get(0x0).unwrap().0, program[0]);
+        assert_eq!(memory.get(0xFFFE).unwrap().0, 0);
+    }
+
+    #[test]
+    fn step_outcomes() {
+        let mut program = [0; 256];
+        program[0] = 0xF3;
+        program[1] = 0x0A;
+        assemble_sys(&mut program[2..], 0xFD);
+        let mut memory = Memory::of_bytes(&program, 0x0);
+        let mut cpu = prepare_cpu();
+        assert_eq!(cpu.step(&mut memory), Ok(StepOutcome::Executed));
+        assert_eq!(cpu.step(&mut memory), Ok(StepOutcome::WaitingForKey));
+        cpu.registers.wait_for_key = None;
+        assert_eq!(cpu.step(&mut memory), Ok(StepOutcome::Executed));
+        assert_eq!(cpu.step(&mut memory), Ok(StepOutcome::Halted));
+        assert_eq!(cpu.registers.pc.0, 0x2);
+    }
 }
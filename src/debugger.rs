@@ -0,0 +1,596 @@
+use crate::cpu::{StepOutcome, INSTRUCTION_SIZE, LONG_LOAD_OPCODE};
+use crate::machine::Machine;
+use crate::memory::{Access, AccessKind, MEMORY_SIZE};
+use crate::opcode;
+use std::collections::BTreeSet;
+use std::fmt::{self, Write};
+
+/// The characters used to print each combination of XO-CHIP bit planes in the ASCII frame
+const FRAME_CHARS: [char; 4] = ['.', '#', '+', '@'];
+
+/// The help text printed by the help command
+pub const HELP: &str = "\
+step [n]          execute n instructions (default 1)
+continue          run until a breakpoint, the program exits or waits for a key
+break [addr]      set a breakpoint at addr, or list breakpoints
+delete [addr]     delete the breakpoint at addr, or every breakpoint
+watch <addr> [r|w|rw]
+                  stop when addr is read and/or written (default rw)
+watch i <start> <end>
+                  stop when I moves into the range start to end (inclusive)
+watch v<x> <value>
+                  stop when register vx takes the value
+watch             list watchpoints
+unwatch           delete every watchpoint
+regs              print the registers, stack and timers
+mem <addr> <len>  dump len bytes of memory starting at addr
+disas <addr> <n>  disassemble n instructions starting at addr
+frame             print the frame buffer
+quit              exit the debugger
+Addresses and values are in hex, counts are in decimal. An empty line repeats the last command.";
+
+/// A condition that stops execution when an instruction makes it true
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    /// A read and/or write of an address through Memory::get or Memory::set
+    Memory { addr: u16, read: bool, write: bool },
+    /// I moving to an address in the inclusive range start..=end
+    Index { start: u16, end: u16 },
+    /// A V register taking the given value
+    Register { register: usize, value: u8 },
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watch::Memory { addr, read: true, write: false } => write!(f, "read {:04x}", addr),
+            Watch::Memory { addr, read: false, write: true } => write!(f, "write {:04x}", addr),
+            Watch::Memory { addr, .. } => write!(f, "access {:04x}", addr),
+            Watch::Index { start, end } => write!(f, "i in {:04x}-{:04x}", start, end),
+            Watch::Register { register, value } => write!(f, "v{:x} == {:02x}", register, value),
+        }
+    }
+}
+
+impl Watch {
+
+    /// Parse the arguments of the watch command
+    fn parse(args: &[&str]) -> Result<Self, String> {
+        let watch = match args {
+            ["i", start, end] => Watch::Index { start: parse_addr(start)?, end: parse_addr(end)? },
+            [register, value] if register.starts_with('v') => Watch::Register {
+                register: parse_register(register)?,
+                value: parse_value(value)?,
+            },
+            [addr] => Watch::Memory { addr: parse_addr(addr)?, read: true, write: true },
+            [addr, "r"] => Watch::Memory { addr: parse_addr(addr)?, read: true, write: false },
+            [addr, "w"] => Watch::Memory { addr: parse_addr(addr)?, read: false, write: true },
+            [addr, "rw"] => Watch::Memory { addr: parse_addr(addr)?, read: true, write: true },
+            _ => return Err(format!("bad watch '{}', try help", args.join(" "))),
+        };
+
+        Ok(watch)
+    }
+
+    /// Check the watch against the state before an instruction and the memory accesses it
+    /// made, returning a description of what happened if it triggered
+    fn check(&self, machine: &Machine, before: &Before, accesses: &[Access]) -> Option<String> {
+        let registers = &machine.cpu.registers;
+
+        match *self {
+            Watch::Memory { addr, read, write } => accesses
+                .iter()
+                .find(|access| {
+                    access.addr == addr as usize && match access.kind {
+                        AccessKind::Read => read,
+                        AccessKind::Write => write,
+                    }
+                })
+                .map(|access| match access.kind {
+                    AccessKind::Read => format!("read {:02x} from {:04x}", access.value, access.addr),
+                    AccessKind::Write => format!("wrote {:02x} to {:04x}", access.value, access.addr),
+                }),
+            Watch::Index { start, end } => {
+                let in_range = |i: u16| start <= i && i <= end;
+                if in_range(registers.i.0) && !in_range(before.i) {
+                    Some(format!("i = {:04x}", registers.i.0))
+                } else {
+                    None
+                }
+            }
+            Watch::Register { register, value } => {
+                if registers.v[register].0 == value && before.v[register] != value {
+                    Some(format!("v{:x} = {:02x}", register, value))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// The state watches are compared against, captured before each instruction
+struct Before {
+    pc: u16,
+    opcode: Option<u16>,
+    i: u16,
+    v: [u8; 16],
+}
+
+/// A debugger command, parsed from a line of the REPL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Step(usize),
+    Continue,
+    Break(Option<u16>),
+    Delete(Option<u16>),
+    Watch(Option<Watch>),
+    Unwatch,
+    Regs,
+    Mem(u16, usize),
+    Disas(u16, usize),
+    Frame,
+    Help,
+    Quit,
+}
+
+impl Command {
+
+    /// Parse a command line, returning a message describing the problem if it is not valid
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().ok_or_else(|| "empty command".to_string())?;
+        let args: Vec<&str> = words.collect();
+
+        let command = match (name, args.as_slice()) {
+            ("step" | "s", []) => Command::Step(1),
+            ("step" | "s", [n]) => Command::Step(parse_count(n)?),
+            ("continue" | "c", []) => Command::Continue,
+            ("break" | "b", []) => Command::Break(None),
+            ("break" | "b", [addr]) => Command::Break(Some(parse_addr(addr)?)),
+            ("delete" | "d", []) => Command::Delete(None),
+            ("delete" | "d", [addr]) => Command::Delete(Some(parse_addr(addr)?)),
+            ("watch" | "w", []) => Command::Watch(None),
+            ("watch" | "w", args) => Command::Watch(Some(Watch::parse(args)?)),
+            ("unwatch", []) => Command::Unwatch,
+            ("regs" | "r", []) => Command::Regs,
+            ("mem" | "m", [addr, len]) => Command::Mem(parse_addr(addr)?, parse_count(len)?),
+            ("disas" | "x", [addr, n]) => Command::Disas(parse_addr(addr)?, parse_count(n)?),
+            ("frame" | "f", []) => Command::Frame,
+            ("help" | "h", []) => Command::Help,
+            ("quit" | "q", []) => Command::Quit,
+            _ => return Err(format!("unknown command or bad arguments '{}', try help", line.trim())),
+        };
+
+        Ok(command)
+    }
+}
+
+/// Parse a hex address with an optional 0x prefix
+fn parse_addr(word: &str) -> Result<u16, String> {
+    let digits = word.trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("bad address '{}'", word))
+}
+
+/// Parse a hex byte value with an optional 0x prefix
+fn parse_value(word: &str) -> Result<u8, String> {
+    let digits = word.trim_start_matches("0x");
+    u8::from_str_radix(digits, 16).map_err(|_| format!("bad value '{}'", word))
+}
+
+/// Parse a register name such as vf
+fn parse_register(word: &str) -> Result<usize, String> {
+    match word.strip_prefix('v').map(|digit| usize::from_str_radix(digit, 16)) {
+        Some(Ok(register)) if register < 16 => Ok(register),
+        _ => Err(format!("bad register '{}'", word)),
+    }
+}
+
+/// Parse a decimal count
+fn parse_count(word: &str) -> Result<usize, String> {
+    word.parse().map_err(|_| format!("bad count '{}'", word))
+}
+
+/// Wraps a machine with the breakpoints and watchpoints of a debugging session. Each command is executed against
+/// the machine and returns the text to show the user, so the debugger can sit behind any REPL.
+pub struct Debugger {
+    pub machine: Machine,
+    breakpoints: BTreeSet<u16>,
+    watches: Vec<Watch>,
+}
+
+impl Debugger {
+
+    pub fn new(machine: Machine) -> Self {
+        Self {
+            machine,
+            breakpoints: BTreeSet::new(),
+            watches: Vec::new(),
+        }
+    }
+
+    /// The addresses breakpoints are set at, in order
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// The watchpoints, in the order they were added
+    pub fn watches(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    /// Execute a command and return its output. Quit is left to the REPL and does nothing here.
+    pub fn execute(&mut self, command: Command) -> String {
+        match command {
+            Command::Step(n) => self.step(n),
+            Command::Continue => self.cont(),
+            Command::Break(Some(addr)) => {
+                self.breakpoints.insert(addr);
+                format!("breakpoint at {:03x}", addr)
+            }
+            Command::Break(None) => self.list_breakpoints(),
+            Command::Delete(Some(addr)) => {
+                if self.breakpoints.remove(&addr) {
+                    format!("deleted breakpoint at {:03x}", addr)
+                } else {
+                    format!("no breakpoint at {:03x}", addr)
+                }
+            }
+            Command::Delete(None) => {
+                self.breakpoints.clear();
+                "deleted all breakpoints".to_string()
+            }
+            Command::Watch(Some(watch)) => {
+                self.watches.push(watch);
+                format!("watchpoint on {}", watch)
+            }
+            Command::Watch(None) => self.list_watches(),
+            Command::Unwatch => {
+                self.watches.clear();
+                "deleted all watchpoints".to_string()
+            }
+            Command::Regs => self.regs(),
+            Command::Mem(addr, len) => self.mem(addr, len),
+            Command::Disas(addr, n) => self.disas(addr, n),
+            Command::Frame => self.frame(),
+            Command::Help => HELP.to_string(),
+            Command::Quit => String::new(),
+        }
+    }
+
+    /// Execute up to n instructions, stopping early if the machine cannot make progress or a
+    /// watchpoint triggers
+    fn step(&mut self, n: usize) -> String {
+        for _ in 0..n {
+            if let Err(stop) = self.step_watched() {
+                return stop;
+            }
+        }
+
+        self.location()
+    }
+
+    /// Step the machine once, checking the watchpoints against the instruction. Returns the
+    /// reason for stopping as the error if execution should not continue.
+    fn step_watched(&mut self) -> Result<(), String> {
+        let registers = &self.machine.cpu.registers;
+        let before = Before {
+            pc: registers.pc.0,
+            opcode: self.machine.memory.get16(registers.pc.0 as usize).ok().map(|opcode| opcode.0),
+            i: registers.i.0,
+            v: registers.v.map(|v| v.0),
+        };
+
+        // Only the accesses made by this instruction are logged, whatever the step ends with
+        self.machine.memory.take_accesses();
+        self.machine.memory.log_accesses = self.watches.iter().any(|watch| matches!(watch, Watch::Memory { .. }));
+        let result = self.machine.step();
+        self.machine.memory.log_accesses = false;
+        let accesses = self.machine.memory.take_accesses();
+
+        match result {
+            Ok(StepOutcome::Executed) => {}
+            Ok(outcome) => return Err(self.stopped(outcome)),
+            Err(err) => return Err(format!("fault: {}", err)),
+        }
+
+        for watch in self.watches.iter() {
+            if let Some(what) = watch.check(&self.machine, &before, &accesses) {
+                let instruction = before.opcode.map(opcode::disassemble).unwrap_or_default();
+                return Err(format!(
+                    "watchpoint on {}: {}\n   by {:04x}: {}\n{}",
+                    watch, what, before.pc, instruction, self.location()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run until the PC reaches a breakpoint. A breakpoint at the current PC is stepped over
+    /// first. Running also stops if the program jumps to itself, which is the usual way for a
+    /// CHIP-8 program to end.
+    fn cont(&mut self) -> String {
+        loop {
+            let pc = self.machine.cpu.registers.pc.0;
+
+            if let Err(stop) = self.step_watched() {
+                return stop;
+            }
+
+            let next_pc = self.machine.cpu.registers.pc.0;
+
+            if self.breakpoints.contains(&next_pc) {
+                return format!("breakpoint\n{}", self.location());
+            }
+
+            if next_pc == pc {
+                return format!("stuck in a loop\n{}", self.location());
+            }
+        }
+    }
+
+    fn stopped(&self, outcome: StepOutcome) -> String {
+        match outcome {
+            StepOutcome::Executed => self.location(),
+            StepOutcome::WaitingForKey => format!("waiting for a key\n{}", self.location()),
+            StepOutcome::Halted => "program exited".to_string(),
+        }
+    }
+
+    /// The current PC and the instruction at it
+    fn location(&self) -> String {
+        self.disas(self.machine.cpu.registers.pc.0, 1)
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "no breakpoints".to_string();
+        }
+
+        self.breakpoints
+            .iter()
+            .map(|addr| format!("breakpoint at {:03x}", addr))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn list_watches(&self) -> String {
+        if self.watches.is_empty() {
+            return "no watchpoints".to_string();
+        }
+
+        self.watches
+            .iter()
+            .map(|watch| format!("watchpoint on {}", watch))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn regs(&self) -> String {
+        let registers = &self.machine.cpu.registers;
+        let mut out = String::new();
+
+        for (idx, v) in registers.v.iter().enumerate() {
+            let separator = if idx % 8 == 7 { "\n" } else { " " };
+            write!(out, "v{:x} {:02x}{}", idx, v.0, separator).unwrap();
+        }
+
+        writeln!(out, "i {:04x} pc {:04x} sp {}", registers.i.0, registers.pc.0, registers.stack_idx / 2).unwrap();
+
+        let stack: Vec<String> = registers.stack[..registers.stack_idx]
+            .chunks(2)
+            .map(|pair| format!("{:04x}", (pair[0].0 as u16) << 8 | pair[1].0 as u16))
+            .collect();
+        writeln!(out, "stack [{}]", stack.join(" ")).unwrap();
+
+        write!(out, "delay {:02x} sound {:02x}", registers.delay.0, registers.sound.0).unwrap();
+        out
+    }
+
+    /// Hex dump len bytes of memory, 16 bytes to a line, stopping at the end of memory
+    fn mem(&self, addr: u16, len: usize) -> String {
+        let mut lines = Vec::new();
+        let end = (addr as usize).saturating_add(len).min(MEMORY_SIZE);
+
+        for line_start in (addr as usize..end).step_by(16) {
+            let line_end = (line_start + 16).min(end);
+            let mut line = format!("{:04x}:", line_start);
+
+            for idx in line_start..line_end {
+                match self.machine.memory.peek(idx) {
+                    Ok(byte) => write!(line, " {:02x}", byte.0).unwrap(),
+                    Err(err) => {
+                        lines.push(line);
+                        lines.push(err.to_string());
+                        return lines.join("\n");
+                    }
+                }
+            }
+
+            lines.push(line);
+        }
+
+        lines.join("\n")
+    }
+
+    /// Disassemble n instructions. The XO-CHIP long load is printed with the address that
+    /// follows it.
+    fn disas(&self, addr: u16, n: usize) -> String {
+        let mut lines = Vec::new();
+        let mut addr = addr as usize;
+        let marker = |addr: usize| if addr == self.machine.cpu.registers.pc.0 as usize { "=>" } else { "  " };
+
+        for _ in 0..n {
+            let opcode = match self.machine.memory.get16(addr) {
+                Ok(opcode) => opcode.0,
+                Err(err) => {
+                    lines.push(err.to_string());
+                    break;
+                }
+            };
+
+            if opcode == LONG_LOAD_OPCODE {
+                if let Ok(long_addr) = self.machine.memory.get16(addr + INSTRUCTION_SIZE as usize) {
+                    lines.push(format!("{} {:04x}: {:04x} {:04x}  i := long 0x{:04x}", marker(addr), addr, opcode, long_addr.0, long_addr.0));
+                    addr += 2 * INSTRUCTION_SIZE as usize;
+                    continue;
+                }
+            }
+
+            lines.push(format!("{} {:04x}: {:04x}       {}", marker(addr), addr, opcode, opcode::disassemble(opcode)));
+            addr += INSTRUCTION_SIZE as usize;
+        }
+
+        lines.join("\n")
+    }
+
+    /// Print the frame buffer in the current resolution, one character per pixel
+    fn frame(&self) -> String {
+        let memory = &self.machine.memory;
+
+        (0..memory.height())
+            .map(|y| {
+                (0..memory.width())
+                    .map(|x| FRAME_CHARS[memory.pixel(x, y) as usize & 0x3])
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// v0 := 1, v1 := 2, v0 += v1, call 20a, jump to self, (20a) v2 := 7, return
+    const PROGRAM: [u8; 14] = [0x60, 0x01, 0x61, 0x02, 0x80, 0x14, 0x22, 0x0A, 0x12, 0x08, 0x62, 0x07, 0x00, 0xEE];
+
+    fn debugger() -> Debugger {
+        Debugger::new(Machine::of_bytes(PROGRAM.to_vec()))
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Command::parse("step"), Ok(Command::Step(1)));
+        assert_eq!(Command::parse("s 10"), Ok(Command::Step(10)));
+        assert_eq!(Command::parse("break 0x20a"), Ok(Command::Break(Some(0x20A))));
+        assert_eq!(Command::parse("delete"), Ok(Command::Delete(None)));
+        assert_eq!(Command::parse("mem 200 16"), Ok(Command::Mem(0x200, 16)));
+        assert_eq!(Command::parse("disas 200 4"), Ok(Command::Disas(0x200, 4)));
+        assert!(Command::parse("mem 200").is_err());
+        assert!(Command::parse("break zz").is_err());
+        assert!(Command::parse("jump").is_err());
+    }
+
+    #[test]
+    fn step_and_regs() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.execute(Command::Step(3)), "=> 0206: 220a       :call 0x20a");
+        let regs = debugger.execute(Command::Regs);
+        assert!(regs.starts_with("v0 03 v1 02 v2 00"));
+        assert!(regs.contains("i 0000 pc 0206 sp 0"));
+
+        debugger.execute(Command::Step(1));
+        assert!(debugger.execute(Command::Regs).contains("stack [0208]"));
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut debugger = debugger();
+        debugger.execute(Command::Break(Some(0x20C)));
+        debugger.execute(Command::Break(Some(0x204)));
+        assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), vec![0x204, 0x20C]);
+
+        assert!(debugger.execute(Command::Continue).starts_with("breakpoint\n=> 0204"));
+        assert!(debugger.execute(Command::Continue).starts_with("breakpoint\n=> 020c"));
+        assert_eq!(debugger.machine.cpu.registers.v[2].0, 7);
+
+        debugger.execute(Command::Delete(None));
+        assert!(debugger.execute(Command::Continue).starts_with("stuck in a loop\n=> 0208"));
+    }
+
+    #[test]
+    fn parse_watch() {
+        assert_eq!(Command::parse("watch 300 w"), Ok(Command::Watch(Some(Watch::Memory { addr: 0x300, read: false, write: true }))));
+        assert_eq!(Command::parse("watch 300"), Ok(Command::Watch(Some(Watch::Memory { addr: 0x300, read: true, write: true }))));
+        assert_eq!(Command::parse("watch i 300 30f"), Ok(Command::Watch(Some(Watch::Index { start: 0x300, end: 0x30F }))));
+        assert_eq!(Command::parse("watch vf 1"), Ok(Command::Watch(Some(Watch::Register { register: 0xF, value: 1 }))));
+        assert!(Command::parse("watch vg 1").is_err());
+        assert!(Command::parse("watch 300 x").is_err());
+    }
+
+    #[test]
+    fn memory_watches() {
+        // i := 300, v0 := 7b, bcd v0, i := 300, load v2, jump to self
+        let program = vec![0xA3, 0x00, 0x60, 0x7B, 0xF0, 0x33, 0xA3, 0x00, 0xF2, 0x65, 0x12, 0x0A];
+
+        let mut debugger = Debugger::new(Machine::of_bytes(program.clone()));
+        debugger.execute(Command::Watch(Some(Watch::Memory { addr: 0x301, read: false, write: true })));
+        let stop = debugger.execute(Command::Continue);
+        assert!(stop.starts_with("watchpoint on write 0301: wrote 02 to 0301\n   by 0204: "), "{}", stop);
+        assert!(stop.ends_with("=> 0206: a300       i := 0x300"), "{}", stop);
+
+        let mut debugger = Debugger::new(Machine::of_bytes(program));
+        debugger.execute(Command::Watch(Some(Watch::Memory { addr: 0x302, read: true, write: false })));
+        let stop = debugger.execute(Command::Continue);
+        assert!(stop.starts_with("watchpoint on read 0302: read 03 from 0302\n   by 0208: "), "{}", stop);
+        assert!(!debugger.machine.memory.log_accesses);
+    }
+
+    #[test]
+    fn inspecting_memory_is_not_watched() {
+        let mut debugger = debugger();
+        debugger.execute(Command::Watch(Some(Watch::Memory { addr: 0x200, read: true, write: false })));
+        debugger.execute(Command::Step(1));
+        debugger.execute(Command::Mem(0x200, 2));
+        debugger.machine.memory.log_accesses = true;
+        debugger.machine.memory.get(0x200).unwrap();
+        assert_eq!(debugger.execute(Command::Step(1)), "=> 0204: 8014       v0 += v1");
+    }
+
+    #[test]
+    fn register_watches() {
+        // i := 2f0, v0 := 10, i += v0, v1 := ff, v0 += v1 (sets vf), jump to self
+        let program = vec![0xA2, 0xF0, 0x60, 0x10, 0xF0, 0x1E, 0x61, 0xFF, 0x80, 0x14, 0x12, 0x0A];
+
+        let mut debugger = Debugger::new(Machine::of_bytes(program.clone()));
+        debugger.execute(Command::Watch(Some(Watch::Index { start: 0x300, end: 0x30F })));
+        let stop = debugger.execute(Command::Continue);
+        assert!(stop.starts_with("watchpoint on i in 0300-030f: i = 0300\n   by 0204: "), "{}", stop);
+
+        let mut debugger = Debugger::new(Machine::of_bytes(program));
+        debugger.execute(Command::Watch(Some(Watch::Register { register: 0xF, value: 1 })));
+        let stop = debugger.execute(Command::Continue);
+        assert!(stop.starts_with("watchpoint on vf == 01: vf = 01\n   by 0208: "), "{}", stop);
+    }
+
+    #[test]
+    fn faults_are_reported() {
+        let mut debugger = Debugger::new(Machine::of_bytes(vec![0x00, 0xEE]));
+        assert_eq!(debugger.execute(Command::Continue), "fault: stack underflow at 200");
+    }
+
+    #[test]
+    fn mem_and_disas() {
+        let debugger = debugger();
+        assert_eq!(debugger.mem(0x200, 20), "0200: 60 01 61 02 80 14 22 0a 12 08 62 07 00 ee 00 00\n0210: 00 00 00 00");
+        assert_eq!(debugger.mem(0xFFFE, usize::MAX), "fffe: 00 00");
+        assert_eq!(
+            debugger.disas(0x200, 2),
+            "=> 0200: 6001       v0 := 0x01\n   0202: 6102       v1 := 0x02"
+        );
+    }
+
+    #[test]
+    fn frame() {
+        let mut debugger = Debugger::new(Machine::of_bytes(vec![0xF0, 0x29, 0xD0, 0x05]));
+        debugger.execute(Command::Step(2));
+        let frame = debugger.execute(Command::Frame);
+        let lines: Vec<&str> = frame.lines().collect();
+        assert_eq!(lines.len(), 32);
+        assert!(lines[0].starts_with("####...."));
+        assert!(lines[1].starts_with("#..#...."));
+    }
+}
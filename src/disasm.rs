@@ -0,0 +1,336 @@
+use crate::cpu::{INSTRUCTION_SIZE, LONG_LOAD_OPCODE};
+use crate::memory::MEMORY_SIZE;
+use crate::opcode::{self, Opcode};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+
+/// The number of data bytes printed on each line of a listing
+const DATA_BYTES_PER_LINE: usize = 8;
+
+/// How control leaves an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    /// Execution continues with the next instruction
+    Next,
+    /// The next instruction may be skipped
+    Skip,
+    /// Execution continues at the address
+    Jump(u16),
+    /// Execution continues at the address and returns to the next instruction
+    Call(u16),
+    /// Execution continues at the address plus v0, usually a table of jumps
+    JumpTable(u16),
+    /// Execution does not continue past the instruction (return, exit or a machine code call)
+    Stop,
+}
+
+/// Decide how control leaves an instruction
+fn flow(opcode: Opcode) -> Flow {
+    match opcode {
+        Opcode::Ret | Opcode::Exit | Opcode::Sys(_) => Flow::Stop,
+        Opcode::Jp(addr) => Flow::Jump(addr),
+        Opcode::Call(addr) => Flow::Call(addr),
+        Opcode::JpV0(addr) => Flow::JumpTable(addr),
+        _ if opcode.is_skip() => Flow::Skip,
+        _ => Flow::Next,
+    }
+}
+
+/// The result of walking the code reachable from the start of a ROM. Every byte that is not
+/// part of a reachable instruction is treated as data.
+pub struct Disassembly {
+    /// The address the ROM is loaded at
+    pub origin: u16,
+    rom: Vec<u8>,
+    /// The address and size in bytes of every reachable instruction
+    pub code: BTreeMap<u16, u16>,
+    /// Labels for the targets of jumps, calls and I loads that fall inside the ROM
+    pub labels: BTreeMap<u16, String>,
+}
+
+impl Disassembly {
+
+    /// Walk the code of a ROM loaded at origin, starting from origin and following jumps, calls,
+    /// skips and returns. Anything past the end of the address space is ignored.
+    pub fn new(rom: &[u8], origin: u16) -> Self {
+        let len = rom.len().min(MEMORY_SIZE - origin as usize);
+        let mut disassembly = Self {
+            origin,
+            rom: rom[..len].to_vec(),
+            code: BTreeMap::new(),
+            labels: BTreeMap::new(),
+        };
+
+        let mut calls = BTreeSet::new();
+        let mut jumps = BTreeSet::new();
+        let mut data = BTreeSet::new();
+        let mut pending = vec![origin];
+
+        while let Some(addr) = pending.pop() {
+            if disassembly.code.contains_key(&addr) {
+                continue;
+            }
+
+            // Invalid opcodes and instructions that run off the end of the ROM are data
+            let opcode = match disassembly.opcode(addr).map(Opcode::decode) {
+                Some(Ok(opcode)) => opcode,
+                _ => continue,
+            };
+
+            let size = disassembly.size(addr);
+            let next = match addr.checked_add(size) {
+                Some(next) if disassembly.contains(next - 1) => next,
+                _ => continue,
+            };
+
+            disassembly.code.insert(addr, size);
+
+            if let Opcode::LdI(target) = opcode {
+                data.insert(target);
+            }
+
+            match flow(opcode) {
+                Flow::Next => pending.push(next),
+                Flow::Skip => {
+                    pending.push(next);
+                    pending.extend(next.checked_add(disassembly.size(next)));
+                }
+                Flow::Jump(target) => {
+                    jumps.insert(target);
+                    pending.push(target);
+                }
+                Flow::Call(target) => {
+                    calls.insert(target);
+                    pending.push(target);
+                    pending.push(next);
+                }
+                Flow::JumpTable(target) => {
+                    jumps.insert(target);
+                    pending.push(target);
+                }
+                Flow::Stop => {}
+            }
+        }
+
+        // Calls take priority over jumps, which take priority over data
+        for (targets, prefix) in [(data, "data"), (jumps, "label"), (calls, "sub")] {
+            for target in targets {
+                if disassembly.contains(target) {
+                    disassembly.labels.insert(target, format!("{}_{:03x}", prefix, target));
+                }
+            }
+        }
+
+        disassembly
+    }
+
+    /// True if the address falls inside the ROM
+    fn contains(&self, addr: u16) -> bool {
+        addr >= self.origin && ((addr - self.origin) as usize) < self.rom.len()
+    }
+
+    fn byte(&self, addr: u16) -> Option<u8> {
+        if self.contains(addr) {
+            Some(self.rom[(addr - self.origin) as usize])
+        } else {
+            None
+        }
+    }
+
+    /// The opcode at an address, if both of its bytes fall inside the ROM
+    fn opcode(&self, addr: u16) -> Option<u16> {
+        Some((self.byte(addr)? as u16) << 8 | self.byte(addr.checked_add(1)?)? as u16)
+    }
+
+    /// The size of the instruction at an address. Only the XO-CHIP long load is four bytes.
+    fn size(&self, addr: u16) -> u16 {
+        if self.opcode(addr) == Some(LONG_LOAD_OPCODE) { 2 * INSTRUCTION_SIZE } else { INSTRUCTION_SIZE }
+    }
+
+    /// The length of the run of data starting at an address, which ends at the next instruction
+    /// or label or after eight bytes
+    fn data_len(&self, addr: u16) -> usize {
+        let end = self.origin as usize + self.rom.len();
+
+        (addr as usize..end)
+            .take(DATA_BYTES_PER_LINE)
+            .skip(1)
+            .position(|next| self.is_code(next as u16) || self.labels.contains_key(&(next as u16)))
+            .map(|offset| offset + 1)
+            .unwrap_or_else(|| DATA_BYTES_PER_LINE.min(end - addr as usize))
+    }
+
+    /// Write the ROM as Octo source that assembles back to the same bytes. Each instruction is
+    /// written in the syntax of opcode::disassemble and data is written as raw bytes.
+    pub fn source(&self) -> String {
+        let mut source = String::new();
+        let end = self.origin as usize + self.rom.len();
+        let mut addr = self.origin as usize;
+
+        while addr < end {
+            if let Some(label) = self.labels.get(&(addr as u16)) {
+                writeln!(source, ": {}", label).unwrap();
+            }
+
+            let len = match self.code.get(&(addr as u16)) {
+                Some(size) => {
+                    writeln!(source, "    {}", self.mnemonic(addr as u16)).unwrap();
+                    *size as usize
+                }
+                None => {
+                    let len = self.data_len(addr as u16);
+                    let bytes: Vec<String> = self.bytes(addr, len).iter().map(|byte| format!("0x{:02x}", byte)).collect();
+                    writeln!(source, "    {}", bytes.join(" ")).unwrap();
+                    len
+                }
+            };
+
+            addr += len;
+        }
+
+        source
+    }
+
+    fn bytes(&self, addr: usize, len: usize) -> &[u8] {
+        let start = addr - self.origin as usize;
+        &self.rom[start..start + len]
+    }
+
+    /// True if the address is the start of a reachable instruction
+    pub fn is_code(&self, addr: u16) -> bool {
+        self.code.contains_key(&addr)
+    }
+
+    /// The mnemonic for the instruction at an address, with the label of any target it refers to
+    fn mnemonic(&self, addr: u16) -> String {
+        let opcode = self.opcode(addr).unwrap_or_default();
+
+        if opcode == LONG_LOAD_OPCODE {
+            let target = self.opcode(addr + INSTRUCTION_SIZE).unwrap_or_default();
+            return format!("i := long 0x{:04x}", target);
+        }
+
+        let mnemonic = opcode::disassemble(opcode);
+        let target = match Opcode::decode(opcode) {
+            Ok(Opcode::Jp(target) | Opcode::Call(target) | Opcode::LdI(target) | Opcode::JpV0(target)) => target,
+            _ => return mnemonic,
+        };
+
+        match self.labels.get(&target) {
+            Some(label) => format!("{:<16} # {}", mnemonic, label),
+            None => mnemonic,
+        }
+    }
+}
+
+/// Print the annotated listing: labels, then the address, raw bytes and mnemonic of each
+/// instruction. Runs of data are printed eight bytes to a line.
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let end = self.origin as usize + self.rom.len();
+        let mut addr = self.origin as usize;
+
+        while addr < end {
+            if let Some(label) = self.labels.get(&(addr as u16)) {
+                writeln!(f, "{}:", label)?;
+            }
+
+            let (len, mnemonic) = match self.code.get(&(addr as u16)) {
+                Some(size) => (*size as usize, self.mnemonic(addr as u16)),
+                None => (self.data_len(addr as u16), "data".to_string()),
+            };
+
+            let mut raw = String::new();
+            for byte in self.bytes(addr, len) {
+                write!(raw, "{:02x} ", byte)?;
+            }
+
+            writeln!(f, "    {:04x}  {:<24}{}", addr, raw, mnemonic)?;
+            addr += len;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    #[test]
+    fn follows_control_flow() {
+        let rom = [
+            0x22, 0x08, // 200: call 208
+            0x30, 0x01, // 202: skip if v0 == 1
+            0x12, 0x06, // 204: goto 206
+            0x12, 0x06, // 206: goto 206
+            0xA2, 0x0E, // 208: i := 20e
+            0x00, 0xEE, // 20a: return
+            0xFF, 0xFF, // 20c: data
+            0x3C, 0x42, // 20e: sprite
+        ];
+        let disassembly = Disassembly::new(&rom, 0x200);
+
+        assert_eq!(disassembly.code.keys().copied().collect::<Vec<_>>(), vec![0x200, 0x202, 0x204, 0x206, 0x208, 0x20A]);
+        assert_eq!(disassembly.labels.get(&0x206).map(String::as_str), Some("label_206"));
+        assert_eq!(disassembly.labels.get(&0x208).map(String::as_str), Some("sub_208"));
+        assert_eq!(disassembly.labels.get(&0x20E).map(String::as_str), Some("data_20e"));
+        assert!(!disassembly.is_code(0x20C));
+    }
+
+    #[test]
+    fn skips_over_long_load() {
+        let rom = [
+            0x30, 0x01, // 200: skip if v0 == 1
+            0xF0, 0x00, 0x03, 0x00, // 202: i := long 300
+            0x00, 0xFD, // 206: exit
+        ];
+        let disassembly = Disassembly::new(&rom, 0x200);
+
+        assert_eq!(disassembly.code.iter().map(|(addr, size)| (*addr, *size)).collect::<Vec<_>>(), vec![(0x200, 2), (0x202, 4), (0x206, 2)]);
+    }
+
+    #[test]
+    fn invalid_opcodes_are_data() {
+        let rom = [0x61, 0x02, 0x80, 0x1F, 0x12, 0x00];
+        let disassembly = Disassembly::new(&rom, 0x200);
+        assert_eq!(disassembly.code.keys().copied().collect::<Vec<_>>(), vec![0x200]);
+    }
+
+    #[test]
+    fn source_round_trips() {
+        let rom = [
+            0x22, 0x08, // 200: call 208
+            0x30, 0x01, // 202: skip if v0 == 1
+            0x12, 0x06, // 204: goto 206
+            0x12, 0x06, // 206: goto 206
+            0xF0, 0x00, 0x02, 0x12, // 208: i := long 212
+            0x00, 0xEE, // 20c: return
+            0x80, 0x1F, 0xF1, 0x02, // 20e: data that looks like invalid opcodes
+            0x3C, 0x42, 0x81, // 212: sprite
+        ];
+        let source = Disassembly::new(&rom, 0x200).source();
+
+        assert!(source.contains(": sub_208\n    i := long 0x0212\n    return\n"), "{}", source);
+        assert_eq!(assemble(&source), Ok(rom.to_vec()));
+    }
+
+    #[test]
+    fn listing() {
+        let rom = [0xA2, 0x06, 0x12, 0x04, 0x12, 0x04, 0xF0, 0x90, 0xF0];
+        let listing = Disassembly::new(&rom, 0x200).to_string();
+
+        assert_eq!(
+            listing,
+            concat!(
+                "    0200  a2 06                   i := 0x206       # data_206\n",
+                "    0202  12 04                   jump 0x204       # label_204\n",
+                "label_204:\n",
+                "    0204  12 04                   jump 0x204       # label_204\n",
+                "data_206:\n",
+                "    0206  f0 90 f0                data\n",
+            )
+        );
+    }
+}
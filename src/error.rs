@@ -0,0 +1,152 @@
+use std::error::Error;
+use std::fmt;
+
+/// A fault raised while executing a program. A bad ROM reports one of these from the step rather
+/// than panicking, so the frontend can restore the terminal and report where execution stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecError {
+    /// The opcode at pc does not decode to any known instruction
+    InvalidOpcode { pc: u16, opcode: u16 },
+    /// A call was made with the stack already full
+    StackOverflow { pc: u16 },
+    /// A return was made with the stack empty
+    StackUnderflow { pc: u16 },
+    /// An instruction tried to read or write outside of the address space
+    MemoryOutOfBounds { addr: usize },
+    /// The program called a machine code routine (0NNN), which we do not emulate
+    UnsupportedMachineCall { pc: u16, addr: u16 },
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecError::InvalidOpcode { pc, opcode } => {
+                write!(f, "invalid opcode {:04x} at {:03x}", opcode, pc)
+            }
+            ExecError::StackOverflow { pc } => write!(f, "stack overflow at {:03x}", pc),
+            ExecError::StackUnderflow { pc } => write!(f, "stack underflow at {:03x}", pc),
+            ExecError::MemoryOutOfBounds { addr } => {
+                write!(f, "memory access out of bounds at {:x}", addr)
+            }
+            ExecError::UnsupportedMachineCall { pc, addr } => {
+                write!(f, "unsupported machine code routine {:03x} called at {:03x}", addr, pc)
+            }
+        }
+    }
+}
+
+impl Error for ExecError {}
+
+/// An opcode that does not decode to any known instruction, returned by Opcode::decode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError {
+    pub opcode: u16,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "opcode {:04x} is not a valid instruction", self.opcode)
+    }
+}
+
+impl Error for DecodeError {}
+
+/// A problem with a save state passed to Machine::load_state. The machine is left untouched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    /// The data does not start with the save state header
+    BadMagic,
+    /// The save state was written by a version of the format we cannot read
+    UnsupportedVersion { version: u16 },
+    /// The payload does not match the checksum in the header
+    ChecksumMismatch,
+    /// The data ended before the whole state was read
+    Truncated,
+    /// There is data left over after the whole state was read
+    TrailingData,
+    /// A field holds a value that no machine could be in
+    InvalidValue { field: &'static str },
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion { version } => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::ChecksumMismatch => write!(f, "save state checksum mismatch"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::TrailingData => write!(f, "save state has trailing data"),
+            StateError::InvalidValue { field } => write!(f, "save state has an invalid {}", field),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+/// A problem reading or playing back an input movie
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieError {
+    /// The movie file could not be parsed
+    Parse { line: usize, message: String },
+    /// The movie has no line for a field it needs
+    MissingField { field: &'static str },
+    /// The movie was recorded against a different ROM
+    RomMismatch { expected: u32, actual: u32 },
+    /// The machine state hash after a frame differs from the one recorded
+    Desync { frame: u64, expected: u32, actual: u32 },
+    /// The program faulted during playback
+    Exec(ExecError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            MovieError::MissingField { field } => write!(f, "movie has no {} line", field),
+            MovieError::RomMismatch { expected, actual } => {
+                write!(f, "movie was recorded against rom {:08x} but this rom is {:08x}", expected, actual)
+            }
+            MovieError::Desync { frame, expected, actual } => {
+                write!(f, "desync at frame {}: expected state {:08x} but got {:08x}", frame, expected, actual)
+            }
+            MovieError::Exec(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+impl From<ExecError> for MovieError {
+    fn from(err: ExecError) -> Self {
+        MovieError::Exec(err)
+    }
+}
+
+/// A problem reading a golden image or a mismatch between it and the display
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageError {
+    /// The golden image could not be parsed
+    Parse(String),
+    /// The display and the golden image are different sizes, as (width, height)
+    SizeMismatch { expected: (usize, usize), actual: (usize, usize) },
+    /// Some pixels differ. first is the (x, y) of the first one found, row by row.
+    PixelMismatch { count: usize, first: (usize, usize) },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Parse(message) => write!(f, "bad golden image: {}", message),
+            ImageError::SizeMismatch { expected, actual } => {
+                write!(f, "display is {}x{} but the golden image is {}x{}", actual.0, actual.1, expected.0, expected.1)
+            }
+            ImageError::PixelMismatch { count, first } => {
+                write!(f, "{} pixels differ from the golden image, starting at ({}, {})", count, first.0, first.1)
+            }
+        }
+    }
+}
+
+impl Error for ImageError {}
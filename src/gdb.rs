@@ -0,0 +1,614 @@
+use crate::error::ExecError;
+use crate::machine::Machine;
+use crate::memory::MEMORY_SIZE;
+use log::{debug, trace};
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::num::Wrapping;
+
+/// The number of registers described to the debugger: v0-vf, i, pc, sp, delay and sound
+pub const NUM_GDB_REGISTERS: usize = 21;
+
+/// The register numbers of the registers that follow v0-vf
+const REG_I: usize = 16;
+const REG_PC: usize = 17;
+const REG_SP: usize = 18;
+const REG_DELAY: usize = 19;
+const REG_SOUND: usize = 20;
+
+/// How many instructions to execute between checks for an interrupt from the debugger while
+/// continuing
+const STEPS_BETWEEN_INTERRUPT_CHECKS: usize = 1000;
+
+/// The byte a debugger sends to interrupt a running target
+const INTERRUPT: u8 = 0x03;
+
+/// Signals reported to the debugger when the target stops
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Describes the registers of the custom chip8 architecture to the debugger. The bit size of
+/// each register decides how many bytes it takes in the g and p packets, which are sent
+/// big-endian to match CHIP-8 memory.
+pub fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>\n",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+        "<target version=\"1.0\">\n",
+        "  <architecture>chip8</architecture>\n",
+        "  <feature name=\"org.chip9.chip8.core\">\n",
+    ));
+
+    for register in 0..16 {
+        writeln!(xml, "    <reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\" regnum=\"{}\"/>", register, register).unwrap();
+    }
+
+    xml.push_str(concat!(
+        "    <reg name=\"i\" bitsize=\"16\" type=\"data_ptr\" regnum=\"16\"/>\n",
+        "    <reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\" regnum=\"17\"/>\n",
+        "    <reg name=\"sp\" bitsize=\"8\" type=\"uint8\" regnum=\"18\"/>\n",
+        "    <reg name=\"delay\" bitsize=\"8\" type=\"uint8\" regnum=\"19\"/>\n",
+        "    <reg name=\"sound\" bitsize=\"8\" type=\"uint8\" regnum=\"20\"/>\n",
+        "  </feature>\n",
+        "</target>\n",
+    ));
+
+    xml
+}
+
+/// The checksum of a packet is the sum of its bytes modulo 256
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Frame a response as a packet
+fn frame(data: &str) -> String {
+    format!("${}#{:02x}", data, checksum(data.as_bytes()))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parse the "addr,len" argument shared by the memory and breakpoint packets
+fn parse_addr_len(args: &str) -> Option<(usize, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((usize::from_str_radix(addr, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+}
+
+/// What the server should do after handling a packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reply {
+    /// Send the response and wait for the next packet
+    Send(String),
+    /// Run the machine until it stops and then report why
+    Continue,
+    /// Send the response, if there is one, and close the connection
+    Close(Option<String>),
+}
+
+/// A GDB remote serial protocol server for a machine. The debugger can read and write the
+/// registers and the address space, set software breakpoints, single step and continue.
+pub struct GdbServer {
+    pub machine: Machine,
+    breakpoints: BTreeSet<u16>,
+}
+
+impl GdbServer {
+
+    pub fn new(machine: Machine) -> Self {
+        Self {
+            machine,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    /// Accept a single debugger connection and serve it until the debugger detaches or kills
+    /// the target
+    pub fn serve(&mut self, listener: &TcpListener) -> io::Result<()> {
+        let (stream, addr) = listener.accept()?;
+        debug!("gdb connected from {}", addr);
+        self.serve_connection(stream)
+    }
+
+    /// Serve a connected debugger until it detaches, kills the target or disconnects
+    pub fn serve_connection(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+
+        while let Some(packet) = Self::read_packet(&mut stream)? {
+            trace!("gdb <- {}", packet);
+
+            let response = match self.handle_packet(&packet) {
+                Reply::Send(response) => response,
+                Reply::Continue => self.run(&mut stream)?,
+                Reply::Close(response) => {
+                    if let Some(response) = response {
+                        stream.write_all(frame(&response).as_bytes())?;
+                    }
+                    return Ok(());
+                }
+            };
+
+            trace!("gdb -> {}", response);
+            stream.write_all(frame(&response).as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Read the next packet, acknowledging it. Acknowledgements from the debugger are skipped
+    /// and an interrupt while stopped is treated as a request for the stop reason. Returns None
+    /// once the debugger disconnects.
+    fn read_packet(stream: &mut TcpStream) -> io::Result<Option<String>> {
+        let mut byte = [0u8; 1];
+
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+
+            match byte[0] {
+                b'$' => {}
+                INTERRUPT => return Ok(Some("?".to_string())),
+                _ => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                if stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+
+                if byte[0] == b'#' {
+                    break;
+                }
+
+                data.push(byte[0]);
+            }
+
+            let mut sum = [0u8; 2];
+            stream.read_exact(&mut sum)?;
+
+            let expected = std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            if expected != Some(checksum(&data)) {
+                stream.write_all(b"-")?;
+                continue;
+            }
+
+            stream.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    /// Handle a packet that does not run the machine
+    fn handle_packet(&mut self, packet: &str) -> Reply {
+        let (command, args) = packet.split_at(packet.len().min(1));
+
+        let response = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "s" => self.step(),
+            "c" => return Reply::Continue,
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "H" => "OK".to_string(),
+            "q" => self.query(args),
+            "D" => return Reply::Close(Some("OK".to_string())),
+            "k" => return Reply::Close(None),
+            _ => String::new(),
+        };
+
+        Reply::Send(response)
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            return "PacketSize=4000;qXfer:features:read+;swbreak+".to_string();
+        }
+
+        if let Some(annex) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            return match parse_addr_len(annex) {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let start = offset.min(xml.len());
+                    let end = (start + len).min(xml.len());
+                    let marker = if end == xml.len() { 'l' } else { 'm' };
+                    format!("{}{}", marker, &xml[start..end])
+                }
+                None => "E01".to_string(),
+            };
+        }
+
+        match args {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// The big-endian bytes of a register
+    fn register_bytes(&self, register: usize) -> Option<Vec<u8>> {
+        let registers = &self.machine.cpu.registers;
+
+        let bytes = match register {
+            0..=15 => vec![registers.v[register].0],
+            REG_I => registers.i.0.to_be_bytes().to_vec(),
+            REG_PC => registers.pc.0.to_be_bytes().to_vec(),
+            REG_SP => vec![(registers.stack_idx / 2) as u8],
+            REG_DELAY => vec![registers.delay.0],
+            REG_SOUND => vec![registers.sound.0],
+            _ => return None,
+        };
+
+        Some(bytes)
+    }
+
+    /// Set a register from its big-endian bytes, returning None if the register does not exist
+    /// or the value is the wrong size
+    fn set_register(&mut self, register: usize, bytes: &[u8]) -> Option<()> {
+        let registers = &mut self.machine.cpu.registers;
+
+        match (register, bytes) {
+            (0..=15, [value]) => registers.v[register] = Wrapping(*value),
+            (REG_I, [high, low]) => registers.i = Wrapping(u16::from_be_bytes([*high, *low])),
+            (REG_PC, [high, low]) => registers.pc = Wrapping(u16::from_be_bytes([*high, *low])),
+            (REG_SP, [sp]) => registers.stack_idx = (*sp as usize * 2).min(registers.stack.len()),
+            (REG_DELAY, [value]) => registers.delay = Wrapping(*value),
+            (REG_SOUND, [value]) => registers.sound = Wrapping(*value),
+            _ => return None,
+        }
+
+        Some(())
+    }
+
+    fn read_registers(&self) -> String {
+        (0..NUM_GDB_REGISTERS)
+            .filter_map(|register| self.register_bytes(register))
+            .map(|bytes| encode_hex(&bytes))
+            .collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        let mut bytes = match decode_hex(args) {
+            Some(bytes) => bytes.into_iter(),
+            None => return "E01".to_string(),
+        };
+
+        for register in 0..NUM_GDB_REGISTERS {
+            let size = self.register_bytes(register).map(|bytes| bytes.len()).unwrap_or(0);
+            let value: Vec<u8> = bytes.by_ref().take(size).collect();
+            if self.set_register(register, &value).is_none() {
+                return "E01".to_string();
+            }
+        }
+
+        "OK".to_string()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        usize::from_str_radix(args, 16)
+            .ok()
+            .and_then(|register| self.register_bytes(register))
+            .map(|bytes| encode_hex(&bytes))
+            .unwrap_or_else(|| "E01".to_string())
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let written = args.split_once('=').and_then(|(register, value)| {
+            let register = usize::from_str_radix(register, 16).ok()?;
+            self.set_register(register, &decode_hex(value)?)
+        });
+
+        match written {
+            Some(()) => "OK".to_string(),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let (addr, len) = match parse_addr_len(args) {
+            Some(addr_len) => addr_len,
+            None => return "E01".to_string(),
+        };
+
+        // The range is checked up front so a huge length is refused rather than looped over
+        let end = match addr.checked_add(len).filter(|end| *end <= MEMORY_SIZE) {
+            Some(end) => end,
+            None => return "E01".to_string(),
+        };
+
+        let bytes: Result<Vec<u8>, ExecError> = (addr..end)
+            .map(|idx| self.machine.memory.peek(idx).map(|byte| byte.0))
+            .collect();
+
+        match bytes {
+            Ok(bytes) => encode_hex(&bytes),
+            Err(_) => "E01".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let parsed = args.split_once(':').and_then(|(addr_len, data)| {
+            let (addr, len) = parse_addr_len(addr_len)?;
+            let data = decode_hex(data)?;
+            if data.len() == len { Some((addr, data)) } else { None }
+        });
+
+        let (addr, data) = match parsed {
+            Some(parsed) => parsed,
+            None => return "E01".to_string(),
+        };
+
+        if addr.checked_add(data.len()).map_or(true, |end| end > MEMORY_SIZE) {
+            return "E01".to_string();
+        }
+
+        for (idx, byte) in data.iter().enumerate() {
+            if self.machine.memory.set(addr + idx, Wrapping(*byte)).is_err() {
+                return "E01".to_string();
+            }
+        }
+
+        "OK".to_string()
+    }
+
+    /// Insert or remove a software breakpoint. Only type 0 breakpoints are supported.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let addr = match args.strip_prefix("0,").and_then(parse_addr_len) {
+            Some((addr, _kind)) => addr as u16,
+            None => return String::new(),
+        };
+
+        if insert {
+            self.breakpoints.insert(addr);
+        } else {
+            self.breakpoints.remove(&addr);
+        }
+
+        "OK".to_string()
+    }
+
+    /// Execute a single instruction and report the stop
+    fn step(&mut self) -> String {
+        match self.machine.step() {
+            Ok(_) if self.machine.halted() => "W00".to_string(),
+            Ok(_) => format!("S{:02x}", SIGTRAP),
+            Err(err) => Self::fault(err),
+        }
+    }
+
+    /// Run until a breakpoint is hit, the program exits or faults, or the debugger sends an
+    /// interrupt. A breakpoint at the current PC is stepped over first.
+    fn run(&mut self, stream: &mut TcpStream) -> io::Result<String> {
+        loop {
+            for _ in 0..STEPS_BETWEEN_INTERRUPT_CHECKS {
+                match self.machine.step() {
+                    Ok(_) if self.machine.halted() => return Ok("W00".to_string()),
+                    Ok(_) => {}
+                    Err(err) => return Ok(Self::fault(err)),
+                }
+
+                if self.breakpoints.contains(&self.machine.cpu.registers.pc.0) {
+                    return Ok(format!("S{:02x}", SIGTRAP));
+                }
+            }
+
+            if Self::interrupted(stream)? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    /// Check for an interrupt from the debugger without blocking. Anything else the debugger has
+    /// sent is left in the stream for read_packet.
+    fn interrupted(stream: &mut TcpStream) -> io::Result<bool> {
+        let mut byte = [0u8; 1];
+        stream.set_nonblocking(true)?;
+        let peeked = stream.peek(&mut byte);
+        stream.set_nonblocking(false)?;
+
+        match peeked {
+            Ok(1) if byte[0] == INTERRUPT => {
+                stream.read_exact(&mut byte)?;
+                Ok(true)
+            }
+            Ok(1) => Ok(false),
+            Ok(_) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Report a fault as the signal a real processor would raise
+    fn fault(err: ExecError) -> String {
+        let signal = match err {
+            ExecError::InvalidOpcode { .. } | ExecError::UnsupportedMachineCall { .. } => SIGILL,
+            _ => SIGSEGV,
+        };
+
+        format!("S{:02x}", signal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// v0 := 1, v1 := 2, v0 += v1, i := 300, jump to self
+    const PROGRAM: [u8; 10] = [0x60, 0x01, 0x61, 0x02, 0x80, 0x14, 0xA3, 0x00, 0x12, 0x08];
+
+    /// A scripted debugger that sends one packet at a time and returns the response
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn send(&mut self, packet: &str) -> String {
+            self.stream.write_all(frame(packet).as_bytes()).unwrap();
+
+            let mut ack = [0u8; 1];
+            self.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+
+            self.read_response()
+        }
+
+        fn read_response(&mut self) -> String {
+            let mut byte = [0u8; 1];
+            let mut data = Vec::new();
+
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'$');
+
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+
+            let mut sum = [0u8; 2];
+            self.stream.read_exact(&mut sum).unwrap();
+            assert_eq!(std::str::from_utf8(&sum).unwrap(), format!("{:02x}", checksum(&data)));
+            self.stream.write_all(b"+").unwrap();
+
+            String::from_utf8(data).unwrap()
+        }
+    }
+
+    /// Start a server for the program on a local socket and connect a client to it
+    fn connect(program: &[u8]) -> (Client, thread::JoinHandle<(u16, u8)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let machine = Machine::of_bytes(program.to_vec());
+
+        let server = thread::spawn(move || {
+            let mut server = GdbServer::new(machine);
+            server.serve(&listener).unwrap();
+            (server.machine.cpu.registers.pc.0, server.machine.cpu.registers.v[0].0)
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        (Client { stream }, server)
+    }
+
+    #[test]
+    fn packets() {
+        assert_eq!(frame("OK"), "$OK#9a");
+        assert_eq!(decode_hex("0aff"), Some(vec![0x0A, 0xFF]));
+        assert_eq!(decode_hex("0af"), None);
+        assert!(target_xml().contains("<architecture>chip8</architecture>"));
+        assert!(target_xml().contains("<reg name=\"vf\" bitsize=\"8\" type=\"uint8\" regnum=\"15\"/>"));
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut server = GdbServer::new(Machine::of_bytes(PROGRAM.to_vec()));
+        server.machine.cpu.registers.v[0xF] = Wrapping(0xAB);
+
+        let registers = server.read_registers();
+        assert_eq!(registers.len(), 2 * (16 + 2 + 2 + 1 + 1 + 1));
+        assert_eq!(&registers[30..], "ab00000200000000");
+
+        assert_eq!(server.handle_packet("P11=0204"), Reply::Send("OK".to_string()));
+        assert_eq!(server.handle_packet("p11"), Reply::Send("0204".to_string()));
+        assert_eq!(server.handle_packet("P13=2a"), Reply::Send("OK".to_string()));
+        assert_eq!(server.machine.cpu.registers.delay.0, 0x2A);
+        assert_eq!(server.handle_packet("p15"), Reply::Send("E01".to_string()));
+
+        assert_eq!(server.handle_packet("m200,4"), Reply::Send("60016102".to_string()));
+        assert_eq!(server.handle_packet("M300,2:beef"), Reply::Send("OK".to_string()));
+        assert_eq!(server.handle_packet("m300,2"), Reply::Send("beef".to_string()));
+        assert_eq!(server.handle_packet("mffff,2"), Reply::Send("E01".to_string()));
+        assert_eq!(server.handle_packet("m1,ffffffffffffffff"), Reply::Send("E01".to_string()));
+        assert_eq!(server.handle_packet("mffffffffffffffff,2"), Reply::Send("E01".to_string()));
+        assert_eq!(server.handle_packet("Mffffffffffffffff,2:0102"), Reply::Send("E01".to_string()));
+
+        let mut written = registers.clone();
+        written.replace_range(0..2, "7f");
+        assert_eq!(server.handle_packet(&format!("G{}", written)), Reply::Send("OK".to_string()));
+        assert_eq!(server.machine.cpu.registers.v[0].0, 0x7F);
+        assert_eq!(server.machine.cpu.registers.pc.0, 0x200);
+    }
+
+    #[test]
+    fn session() {
+        let (mut client, server) = connect(&PROGRAM);
+
+        assert!(client.send("qSupported:multiprocess+;swbreak+").contains("qXfer:features:read+"));
+        assert_eq!(client.send("?"), "S05");
+
+        let xml = client.send("qXfer:features:read:target.xml:0,fff");
+        assert_eq!(xml, format!("l{}", target_xml()));
+        assert!(client.send("qXfer:features:read:target.xml:0,10").starts_with("m<?xml"));
+
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("p11"), "0202");
+
+        assert_eq!(client.send("Z0,206,2"), "OK");
+        assert_eq!(client.send("c"), "S05");
+        assert_eq!(client.send("p11"), "0206");
+        assert_eq!(client.send("p0"), "03");
+        assert_eq!(client.send("z0,206,2"), "OK");
+
+        // The program now spins on the jump to itself until it is interrupted
+        client.stream.write_all(frame("c").as_bytes()).unwrap();
+        let mut ack = [0u8; 1];
+        client.stream.read_exact(&mut ack).unwrap();
+        client.stream.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(client.read_response(), "S02");
+        assert_eq!(client.send("p10"), "0300");
+
+        assert_eq!(client.send("D"), "OK");
+        assert_eq!(server.join().unwrap(), (0x208, 3));
+    }
+
+    #[test]
+    fn interrupt_check_leaves_packets() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        client.write_all(frame("?").as_bytes()).unwrap();
+        // Wait for the packet to arrive
+        stream.peek(&mut [0u8; 1]).unwrap();
+        assert!(!GdbServer::interrupted(&mut stream).unwrap());
+        assert_eq!(GdbServer::read_packet(&mut stream).unwrap(), Some("?".to_string()));
+    }
+
+    #[test]
+    fn exit_and_fault() {
+        let mut server = GdbServer::new(Machine::of_bytes(vec![0x00, 0xFD]));
+        assert_eq!(server.step(), "W00");
+
+        let mut server = GdbServer::new(Machine::of_bytes(vec![0x00, 0xEE]));
+        assert_eq!(server.step(), "S0b");
+
+        let mut server = GdbServer::new(Machine::of_bytes(vec![0x80, 0x1F]));
+        assert_eq!(server.step(), "S04");
+    }
+}
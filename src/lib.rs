@@ -0,0 +1,34 @@
+//! A CHIP-8 emulator core with SUPER-CHIP and XO-CHIP extensions.
+//!
+//! A `Machine` joins together the `Cpu` (registers and quirks) and the `Memory`
+//! (address space and frame buffer). Load a ROM with `Machine::of_bytes`, feed it key presses
+//! with `Machine::set_key` and drive it with `Machine::step`, reading the display back from
+//! `Machine::memory`. Alternatively implement the `Display`, `Input` and `Audio` backend traits
+//! and let `Machine::update` drive them one frame at a time.
+
+pub mod asm;
+pub mod backend;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod gdb;
+pub mod machine;
+pub mod memory;
+pub mod movie;
+pub mod opcode;
+pub mod phosphor;
+pub mod quirks;
+pub mod random;
+pub mod rewind;
+pub mod screenshot;
+pub mod state;
+
+pub use backend::{Audio, Backends, Display, Headless, Input, KeyEvent, KeyScript};
+pub use cpu::{Cpu, Registers, StepOutcome};
+pub use error::{DecodeError, ExecError, ImageError, MovieError, StateError};
+pub use machine::{Machine, Timing};
+pub use memory::Memory;
+pub use opcode::Opcode;
+pub use quirks::Quirks;
+pub use random::{Random, RandomMode};
@@ -1,34 +1,87 @@
-use crate::cpu::Cpu;
-use crate::memory::Memory;
+use crate::backend::{Audio, Display, Input};
+use crate::cpu::{Cpu, Registers, StepOutcome, INSTRUCTION_SIZE};
+use crate::error::{ExecError, StateError};
+use crate::memory::{Memory, NUM_PLANES};
+use crate::opcode::{self, Opcode};
+use crate::quirks::Quirks;
+use crate::random::Random;
+use crate::state::{StateReader, StateWriter};
 
-/// The CHIP-8 ran at roughly ~500Hz and clocks tick at 60Hhz, so we should tick the clocks
-/// roughly 8 times per step
-pub const CLOCKS_PER_DELAY: usize = 8;
+/// The number of instructions executed in every 60Hz frame unless configured otherwise, which
+/// runs programs at 600 instructions a second
+pub const DEFAULT_IPF: usize = 10;
 
+/// The COSMAC VIP's 1.76MHz CDP1802 runs roughly 3668 machine cycles of 8 clocks in each 60Hz
+/// frame
+pub const VIP_CYCLES_PER_FRAME: u32 = 3668;
+
+/// The machine cycles of each VIP frame taken by the display DMA and interrupt routine, which are
+/// not available to the interpreter
+pub const VIP_DISPLAY_CYCLES: u32 = 1024 + 46;
+
+/// How emulated time is measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    /// Every instruction takes the same time and a frame lasts Machine::ipf instructions
+    Instructions,
+    /// Instructions take the approximate number of machine cycles they took on the COSMAC VIP
+    /// and a frame lasts as many cycles as the VIP interpreter had. Like the VIP, DXYN waits for
+    /// the start of the next frame before drawing, so at most one sprite is drawn each frame.
+    CosmacVip,
+}
+
+/// Emulated time is measured in instructions or VIP machine cycles, depending on the timing mode.
+/// The delay and sound timers tick once at the end of every frame, so they run at exactly 60Hz of
+/// emulated time whatever the instruction rate.
 pub struct Machine {
     pub cpu: Cpu,
     pub memory: Memory,
-    clocks_since_delay: usize,
+    pub timing: Timing,
+    /// The number of instructions executed in each 60Hz frame when timing by instructions
+    pub ipf: usize,
+    /// The instructions or machine cycles used so far in the current frame
+    frame_time: u32,
 }
 
 impl Machine {
 
     /// Create a new machine with the specific data loaded at the start address (0x200)
     pub fn of_bytes(data: Vec<u8>) -> Self {
+        Self::of_bytes_with_quirks(data, Quirks::default())
+    }
+
+    /// Create a new machine with the specific data loaded at the start address (0x200) that
+    /// executes ambiguous instructions using the given quirks
+    pub fn of_bytes_with_quirks(data: Vec<u8>, quirks: Quirks) -> Self {
         Self {
-            cpu: Cpu::new(),
+            cpu: Cpu::with_quirks(quirks),
+            memory: Memory::of_bytes(&data, 0x200),
+            timing: Timing::Instructions,
+            ipf: DEFAULT_IPF,
+            frame_time: 0,
+        }
+    }
+
+    /// Create a new machine with the specific data loaded at the start address (0x200), the
+    /// given quirks and a seeded random number generator, so runs can be reproduced exactly
+    pub fn of_bytes_with_random(data: Vec<u8>, quirks: Quirks, rng: Random) -> Self {
+        Self {
+            cpu: Cpu::with_random(quirks, rng),
             memory: Memory::of_bytes(&data, 0x200),
-            clocks_since_delay: 0
+            timing: Timing::Instructions,
+            ipf: DEFAULT_IPF,
+            frame_time: 0,
         }
     }
 
     /// Create a new machine with empty memory
-    #[allow(dead_code)]
     pub fn new() -> Self {
         Self {
             cpu: Cpu::new(),
             memory: Memory::new(),
-            clocks_since_delay: 0
+            timing: Timing::Instructions,
+            ipf: DEFAULT_IPF,
+            frame_time: 0,
         }
     }
 
@@ -52,26 +105,450 @@ impl Machine {
         self.cpu.registers.sound.0 > 0
     }
 
-    /// Step the machine, this steps the CPU and decrements the delay and sound timers when
-    /// appropriate
-    pub fn step(&mut self) {
+    /// Return true if the program has exited
+    pub fn halted(&self) -> bool {
+        self.cpu.registers.halted
+    }
+
+    /// Snapshot the registers (including the random number generator), memory, display and the
+    /// position within the current frame into a versioned save state that can be restored with
+    /// load_state. The quirks and timing settings are not saved, but the length of the frame is
+    /// so that the position can be carried over to a machine with different timing.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        self.cpu.registers.save_state(&mut writer);
+        self.memory.save_state(&mut writer);
+        writer.u32(self.frame_time);
+        writer.u32(self.frame_length());
+        writer.finish()
+    }
+
+    /// Restore a save state written by save_state. The machine is only changed if the whole
+    /// state is valid. Older states without a random number generator keep the current one, and
+    /// older states without a frame position start at the beginning of a frame.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(state)?;
+        let mut registers = Registers::load_state(&mut reader)?;
+        if reader.version() < 2 {
+            registers.rng = self.cpu.registers.rng;
+        }
+        let mut memory = Memory::load_state(&mut reader)?;
+
+        // Before version 3 this was a timer clock that was never reset, which says nothing about
+        // where in the frame the machine was
+        let frame_time = if reader.version() < 3 {
+            reader.u32()?;
+            0
+        } else {
+            let (frame_time, frame_length) = (reader.u32()?, reader.u32()?);
+            if frame_time >= frame_length {
+                return Err(StateError::InvalidValue { field: "frame time" });
+            }
+
+            // Keep the same fraction of the frame, since the state may come from a machine that
+            // measures frames in other units or at another speed
+            (frame_time as u64 * self.frame_length() as u64 / frame_length as u64) as u32
+        };
+        reader.finish()?;
+
+        self.cpu.registers = registers;
+        memory.log_accesses = self.memory.log_accesses;
+        self.memory = memory;
+        self.frame_time = frame_time;
+        Ok(())
+    }
+
+    /// Check the properties that must hold between steps whatever program is running, returning
+    /// a description of the first one that does not. Cheap enough to call after every step, so
+    /// the fuzz targets and tests can catch a bad state at the instruction that caused it.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        let registers = &self.cpu.registers;
+
+        if registers.stack_idx > registers.stack.len() || registers.stack_idx % 2 != 0 {
+            return Err(format!("stack index {} is not a slot in the stack", registers.stack_idx));
+        }
+
+        if registers.wait_for_key.is_some_and(|register| register >= registers.v.len()) {
+            return Err(format!("waiting for a key in register {:?}", registers.wait_for_key));
+        }
+
+        if (self.memory.planes as usize) >= 1 << NUM_PLANES {
+            return Err(format!("plane mask {:#x} selects planes that do not exist", self.memory.planes));
+        }
+
+        if let Some(pixel) = self.memory.frame_buffer.iter().find(|pixel| (**pixel as usize) >= 1 << NUM_PLANES) {
+            return Err(format!("frame buffer holds pixel value {:#x}", pixel));
+        }
+
+        if self.frame_time >= self.frame_length() {
+            return Err(format!("frame time {} is past the end of the frame", self.frame_time));
+        }
+
+        // Disassembling the next instruction should never fail, whatever it is
+        if let Ok(opcode) = self.memory.get16(registers.pc.0 as usize) {
+            opcode::disassemble(opcode.0);
+        }
+
+        Ok(())
+    }
+
+    /// Step the machine, this steps the CPU and decrements the delay and sound timers at the end
+    /// of each frame. The CPU does not execute anything while waiting for a key press or after the
+    /// program has exited, but the timers still run. With COSMAC VIP timing a sprite drawn part
+    /// way through a frame first waits for the rest of the frame to pass.
+    pub fn step(&mut self) -> Result<StepOutcome, ExecError> {
+        if self.waits_for_vblank() {
+            self.end_frame();
+        }
+
+        Ok(self.execute()?.0)
+    }
+
+    /// Advance emulated time by exactly one 60Hz frame, stepping until the timers tick. If the
+    /// machine was part way through a frame (after single stepping) only the rest of that frame is
+    /// run. Returns the outcome of the last step.
+    pub fn run_frame(&mut self) -> Result<StepOutcome, ExecError> {
+        let mut outcome = StepOutcome::Executed;
+
+        loop {
+            if self.waits_for_vblank() {
+                self.end_frame();
+                return Ok(outcome);
+            }
+
+            let (last, frame_ended) = self.execute()?;
+            outcome = last;
+
+            if frame_ended {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    /// The length of a frame in the units of the timing mode
+    fn frame_length(&self) -> u32 {
+        match self.timing {
+            Timing::Instructions => self.ipf.max(1) as u32,
+            Timing::CosmacVip => VIP_CYCLES_PER_FRAME - VIP_DISPLAY_CYCLES,
+        }
+    }
+
+    /// True if the next instruction is a sprite draw that has to wait for the next frame
+    fn waits_for_vblank(&self) -> bool {
+        let registers = &self.cpu.registers;
+
+        self.timing == Timing::CosmacVip
+            && self.frame_time > 0
+            && !registers.halted
+            && registers.wait_for_key.is_none()
+            && self.memory.get16(registers.pc.0 as usize).is_ok_and(|opcode| matches!(Opcode::decode(opcode.0), Ok(Opcode::Drw(..))))
+    }
+
+    /// Execute one instruction and account for the time it took, returning the outcome and true
+    /// if the frame ended
+    fn execute(&mut self) -> Result<(StepOutcome, bool), ExecError> {
+        let pc = self.cpu.registers.pc.0;
+        let opcode = self.memory.get16(pc as usize).map(|opcode| opcode.0);
+
+        let outcome = self.cpu.step(&mut self.memory)?;
+
+        let time = match (self.timing, outcome, opcode) {
+            (Timing::Instructions, _, _) => 1,
+            (Timing::CosmacVip, StepOutcome::Executed, Ok(opcode)) => {
+                let skipped = Opcode::decode(opcode).is_ok_and(Opcode::is_skip)
+                    && self.cpu.registers.pc.0.wrapping_sub(pc) > INSTRUCTION_SIZE;
+                self.cpu.cycles(opcode, skipped)
+            }
+            // The VIP idles until the next frame while waiting for a key or after exiting
+            (Timing::CosmacVip, _, _) => self.frame_length(),
+        };
+
+        self.frame_time += time;
+
+        if self.frame_time >= self.frame_length() {
+            // A long instruction eats into the next frame
+            self.frame_time = (self.frame_time - self.frame_length()).min(self.frame_length() - 1);
+            self.tick_timers();
+            return Ok((outcome, true));
+        }
+
+        Ok((outcome, false))
+    }
+
+    /// Skip the rest of the current frame
+    fn end_frame(&mut self) {
+        self.frame_time = 0;
+        self.tick_timers();
+    }
+
+    fn tick_timers(&mut self) {
+        if self.cpu.registers.sound.0 > 0 {
+            self.cpu.registers.sound.0 -= 1;
+        }
+
+        if self.cpu.registers.delay.0 > 0 {
+            self.cpu.registers.delay.0 -= 1;
+        }
+    }
+
+    /// Run a single frame against the given backend. The keypad state is polled from the input
+    /// backend, the machine is advanced one frame with run_frame, and then the audio and display
+    /// backends are updated. Returns the outcome of the last step.
+    pub fn update<B: Display + Input + Audio>(&mut self, backend: &mut B) -> Result<StepOutcome, ExecError> {
+        let keys = backend.poll();
+        for (key, state) in keys.iter().enumerate() {
+            self.set_key(key as u8, *state);
+        }
+
+        let outcome = self.run_frame()?;
+
+        backend.set_playing(self.sound());
+        backend.draw(&self.memory);
+        Ok(outcome)
+    }
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::Wrapping;
+
+    #[test]
+    fn save_and_load_state() {
+        // v0 := 7, delay := v0, i := 0x300, save v0, clear, jump 0x20a
+        let program = vec![0x60, 0x07, 0xF0, 0x15, 0xA3, 0x00, 0xF0, 0x55, 0x22, 0x0C, 0x12, 0x0A, 0xD0, 0x05, 0x00, 0xEE];
+        let mut machine = Machine::of_bytes(program.clone());
+        for _ in 0..5 {
+            machine.step().unwrap();
+        }
+        machine.set_key(3, true);
+        let state = machine.save_state();
+
+        let mut restored = Machine::of_bytes(program);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.cpu.registers.v, machine.cpu.registers.v);
+        assert_eq!(restored.cpu.registers.pc, machine.cpu.registers.pc);
+        assert_eq!(restored.cpu.registers.stack_idx, 2);
+        assert_eq!(restored.cpu.registers.delay, machine.cpu.registers.delay);
+        assert!(restored.cpu.registers.keys[3]);
+        assert_eq!(restored.memory.get(0x300), Ok(Wrapping(7)));
+        assert_eq!(restored.frame_time, machine.frame_time);
+        assert_eq!(restored.save_state(), state);
+
+        // Both machines carry on identically
+        for _ in 0..20 {
+            machine.step().unwrap();
+            restored.step().unwrap();
+        }
+        assert_eq!(restored.save_state(), machine.save_state());
+    }
+
+    #[test]
+    fn state_restores_random() {
+        // v0 := random 0xff, jump 0x200
+        let program = vec![0xC0, 0xFF, 0x12, 0x00];
+        let mut machine = Machine::of_bytes_with_random(program.clone(), Quirks::default(), Random::new(7));
+        machine.step().unwrap();
+        let state = machine.save_state();
+
+        let mut restored = Machine::of_bytes(program);
+        restored.load_state(&state).unwrap();
+        for _ in 0..10 {
+            machine.step().unwrap();
+            restored.step().unwrap();
+            assert_eq!(restored.cpu.registers.v[0], machine.cpu.registers.v[0]);
+        }
+    }
+
+    /// Rewrite the save state of a machine in the format of an older version. Version 2 states
+    /// end with a single u32 rather than the frame position and length, and version 1 states also
+    /// lack the generator at the end of the registers.
+    fn older_state(machine: &Machine, version: u16) -> Vec<u8> {
+        let header = StateWriter::new().finish().len();
+        let mut payload = machine.save_state().split_off(header);
+        payload.truncate(payload.len() - 4);
 
-        // Only step the CPU if we are not waiting for a key press
-        if let None = self.cpu.registers.wait_for_key {
-            self.cpu.step(&mut self.memory);
+        if version < 2 {
+            let mut rng = StateWriter::new();
+            machine.cpu.registers.rng.save_state(&mut rng);
+            let rng = rng.finish().len() - header;
+            let mut registers = StateWriter::new();
+            machine.cpu.registers.save_state(&mut registers);
+            let registers = registers.finish().len() - header;
+            payload.drain(registers - rng..registers);
         }
 
-        // Increment the timers at roughly 1 clock per 8 steps
-        self.clocks_since_delay += 1;
+        let mut state = StateWriter::new();
+        state.bytes(&payload);
+        state.finish_as(version)
+    }
+
+    #[test]
+    fn version_1_state_keeps_random() {
+        // v0 := random 0xff, jump 0x200
+        let program = vec![0xC0, 0xFF, 0x12, 0x00];
+        let mut machine = Machine::of_bytes_with_random(program.clone(), Quirks::default(), Random::new(7));
+        machine.step().unwrap();
+        let state = older_state(&machine, 1);
 
-        if self.clocks_since_delay >= CLOCKS_PER_DELAY {
-            if self.cpu.registers.sound.0 > 0 {
-                self.cpu.registers.sound.0 -= 1;
+        let mut restored = Machine::of_bytes_with_random(program, Quirks::default(), Random::new(99));
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.cpu.registers.pc, machine.cpu.registers.pc);
+        assert_eq!(restored.cpu.registers.v[0], machine.cpu.registers.v[0]);
+        assert_eq!(restored.cpu.registers.rng, Random::new(99));
+        assert_eq!(restored.frame_time, 0);
+    }
+
+    #[test]
+    fn version_2_state_starts_a_frame() {
+        let mut machine = Machine::of_bytes(vec![0x12, 0x00]);
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        let state = older_state(&machine, 2);
+
+        let mut restored = Machine::of_bytes(vec![0x12, 0x00]);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.cpu.registers.rng, machine.cpu.registers.rng);
+        assert_eq!(restored.frame_time, 0);
+    }
+
+    #[test]
+    fn timers_tick_once_per_frame() {
+        // v0 := 30, delay := v0, sound := v0, jump 0x206
+        let mut machine = Machine::of_bytes(vec![0x60, 0x1E, 0xF0, 0x15, 0xF0, 0x18, 0x12, 0x06]);
+        machine.ipf = 3;
+        machine.run_frame().unwrap();
+        assert_eq!(machine.cpu.registers.delay.0, 29);
+        assert_eq!(machine.cpu.registers.sound.0, 29);
+
+        for _ in 0..10 {
+            machine.run_frame().unwrap();
+        }
+        assert_eq!(machine.cpu.registers.delay.0, 19);
+
+        // Changing the instruction rate does not change the timer rate
+        machine.ipf = 50;
+        for _ in 0..10 {
+            machine.run_frame().unwrap();
+        }
+        assert_eq!(machine.cpu.registers.delay.0, 9);
+    }
+
+    #[test]
+    fn run_frame_finishes_partial_frame() {
+        let mut machine = Machine::of_bytes(vec![0x12, 0x00]);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        machine.run_frame().unwrap();
+        assert_eq!(machine.frame_time, 0);
+
+        machine.cpu.registers.delay.0 = 5;
+        for _ in 0..DEFAULT_IPF - 1 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.cpu.registers.delay.0, 5);
+        machine.step().unwrap();
+        assert_eq!(machine.cpu.registers.delay.0, 4);
+    }
+
+    #[test]
+    fn vip_draws_once_per_frame() {
+        // i := sprite 0, draw v0 v0 5 four times, then jump to self
+        let program = vec![0xF0, 0x29, 0xD0, 0x05, 0xD0, 0x05, 0xD0, 0x05, 0xD0, 0x05, 0x12, 0x0A];
+        let mut machine = Machine::of_bytes(program);
+        machine.timing = Timing::CosmacVip;
+        machine.cpu.registers.delay.0 = 10;
+
+        // Each frame ends when it reaches a draw, which then starts the next frame
+        for pc in [0x202, 0x204, 0x206, 0x208] {
+            machine.run_frame().unwrap();
+            assert_eq!(machine.cpu.registers.pc.0, pc);
+        }
+        assert_eq!(machine.cpu.registers.delay.0, 6);
+        assert_eq!(machine.frame_time, 0);
+
+        // Single stepping a draw part way through a frame waits for the frame to end first
+        let mut machine = Machine::of_bytes(vec![0x60, 0x01, 0xD0, 0x05]);
+        machine.timing = Timing::CosmacVip;
+        machine.cpu.registers.delay.0 = 10;
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!(machine.cpu.registers.delay.0, 9);
+        assert_eq!(machine.frame_time, machine.cpu.cycles(0xD005, false));
+    }
+
+    #[test]
+    fn vip_frames_are_measured_in_cycles() {
+        // v0 += 1, jump 0x200
+        let mut machine = Machine::of_bytes(vec![0x70, 0x01, 0x12, 0x00]);
+        machine.timing = Timing::CosmacVip;
+        machine.run_frame().unwrap();
+
+        let per_loop = machine.cpu.cycles(0x7001, false) + machine.cpu.cycles(0x1200, false);
+        let loops = (VIP_CYCLES_PER_FRAME - VIP_DISPLAY_CYCLES).div_ceil(per_loop);
+        assert_eq!(machine.cpu.registers.v[0].0 as u32, loops);
+    }
+
+    #[test]
+    fn invariants_hold_for_random_programs() {
+        let profiles = [Quirks::cosmac_vip(), Quirks::chip48(), Quirks::super_chip(), Quirks::xo_chip(), Quirks::modern()];
+        let mut rom_rng = Random::new(9);
+        let memory = Memory::new();
+
+        for (idx, quirks) in profiles.iter().cycle().take(100).enumerate() {
+            let rom = (0..128).map(|_| rom_rng.next_byte(&memory).unwrap()).collect();
+            let mut machine = Machine::of_bytes_with_random(rom, *quirks, Random::new(idx as u64));
+            if idx % 2 == 1 {
+                machine.timing = Timing::CosmacVip;
             }
 
-            if self.cpu.registers.delay.0 > 0 {
-                self.cpu.registers.delay.0 -= 1;
+            for step in 0..500 {
+                machine.set_key(step as u8 % 16, step % 3 == 0);
+                let outcome = machine.step();
+                machine.check_invariants().unwrap();
+                if matches!(outcome, Ok(StepOutcome::Halted) | Err(_)) {
+                    break;
+                }
             }
         }
     }
+
+    #[test]
+    fn loaded_frame_time_fits_the_frame() {
+        let mut machine = Machine::of_bytes(vec![0x12, 0x00]);
+        machine.timing = Timing::CosmacVip;
+        machine.step().unwrap();
+        let state = machine.save_state();
+
+        let mut restored = Machine::of_bytes(vec![0x12, 0x00]);
+        restored.load_state(&state).unwrap();
+        restored.check_invariants().unwrap();
+
+        // Half way through a frame stays half way through whatever the frame is measured in
+        machine.frame_time = machine.frame_length() / 2;
+        restored.ipf = 20;
+        restored.load_state(&machine.save_state()).unwrap();
+        assert_eq!(restored.frame_time, 10);
+    }
+
+    #[test]
+    fn bad_state_leaves_machine_alone() {
+        let mut machine = Machine::of_bytes(vec![0x60, 0x07]);
+        machine.step().unwrap();
+        let before = machine.save_state();
+
+        let mut state = Machine::new().save_state();
+        state.push(0);
+        assert_eq!(machine.load_state(&state), Err(StateError::ChecksumMismatch));
+        assert_eq!(machine.load_state(&state[..100]), Err(StateError::ChecksumMismatch));
+        assert_eq!(machine.load_state(b"CHIP8SAV"), Err(StateError::BadMagic));
+        assert_eq!(machine.save_state(), before);
+    }
 }
@@ -1,102 +1,323 @@
+use crate::error::{ExecError, StateError};
+use crate::state::{StateReader, StateWriter};
 use log::trace;
-use std::cmp::min;
+use std::cell::RefCell;
 use std::num::Wrapping;
 
-/// The CHIP-8 VM has 4kb of user accessible memory
-pub const MEMORY_SIZE: usize = 1024 * 8;
+/// The CHIP-8 VM has 4kb of user accessible memory, XO-CHIP extends this to a 64kb address space
+pub const MEMORY_SIZE: usize = 1024 * 64;
 
+/// The standard CHIP-8 display is 64x32
 pub const SCREEN_WIDTH: usize = 64;
 pub const SCREEN_HEIGHT: usize = 32;
-pub const SCREEN_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+
+/// SUPER-CHIP adds a 128x64 high resolution mode
+pub const HIRES_SCREEN_WIDTH: usize = 128;
+pub const HIRES_SCREEN_HEIGHT: usize = 64;
+
+/// The frame buffer is sized to hold the high resolution display
+pub const SCREEN_SIZE: usize = HIRES_SCREEN_WIDTH * HIRES_SCREEN_HEIGHT;
+
+/// The address the 5 byte high hex font is loaded at, in the interpreter area below 0x200
+pub const SPRITE_ADDR: usize = 0x50;
+
+/// The address the 10 byte high SUPER-CHIP hex font is loaded at (directly after the small font)
+pub const BIG_SPRITE_ADDR: usize = SPRITE_ADDR + SPRITE_MEM.len();
+
+/// XO-CHIP has two bit planes. Each frame buffer pixel holds one bit per plane, giving 4 colors.
+pub const NUM_PLANES: usize = 2;
 
 /// The CHIP-8 VM has sprites for the characters 0-F hardcoded. These bytes encode that.
 pub const SPRITE_MEM: [u8; 5 * 16] = [0xF0_u8, 0x90, 0x90, 0x90, 0xF0, 0x20, 0x60, 0x20, 0x20, 0x70, 0xF0, 0x10, 0xF0, 0x80, 0xF0, 0xF0, 0x10, 0xF0, 0x10, 0xF0, 0x90, 0x90, 0xF0, 0x10, 0x10, 0xF0, 0x80, 0xF0, 0x10, 0xF0, 0xF0, 0x80, 0xF0, 0x90, 0xF0, 0xF0, 0x10, 0x20, 0x40, 0x40, 0xF0, 0x90, 0xF0, 0x90, 0xF0, 0xF0, 0x90, 0xF0, 0x10, 0xF0, 0xF0, 0x90, 0xF0, 0x90, 0x90, 0xE0, 0x90, 0xE0, 0x90, 0xE0, 0xF0, 0x80, 0x80, 0x80, 0xF0, 0xE0, 0x90, 0x90, 0x90, 0xE0, 0xF0, 0x80, 0xF0, 0x80, 0xF0, 0xF0, 0x80, 0xF0, 0x80, 0x80];
 
+/// SUPER-CHIP adds a large 8x10 font for the characters 0-F
+pub const BIG_SPRITE_MEM: [u8; 10 * 16] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+/// Whether a memory access read or wrote the byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A single byte read or written through Memory::get or Memory::set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub addr: usize,
+    pub kind: AccessKind,
+    /// The byte read, or the byte written
+    pub value: u8,
+}
+
 /// The memory structure contains the user accessible data and the current frame buffer.
 pub struct Memory {
     data: [Wrapping<u8>; MEMORY_SIZE],
+
+    /// The frame buffer holds one byte per pixel, row by row, using the width of the current
+    /// resolution as the stride
     pub frame_buffer: [u8; SCREEN_SIZE],
+
+    /// True if the display is in the SUPER-CHIP 128x64 high resolution mode
+    pub hires: bool,
+
+    /// Bitmask of the XO-CHIP planes that drawing, clearing and scrolling affect
+    pub planes: u8,
+
+    /// While set every get and set is recorded in the access log, so a debugger can see what an
+    /// instruction touched
+    pub log_accesses: bool,
+    access_log: RefCell<Vec<Access>>,
 }
 
 impl Memory {
 
-    /// Create a new completely clear memory
+    /// Create a new completely clear memory with only the font sprites loaded
     pub fn new() -> Self {
-        Self {
+        let mut new_memory = Self {
             data: [Wrapping(0); MEMORY_SIZE],
-            frame_buffer: [0; SCREEN_SIZE]
+            frame_buffer: [0; SCREEN_SIZE],
+            hires: false,
+            planes: 0x1,
+            log_accesses: false,
+            access_log: RefCell::new(Vec::new()),
+        };
+
+        for (i, byte) in SPRITE_MEM.iter().enumerate() {
+            new_memory.data[SPRITE_ADDR + i] = Wrapping(*byte);
+        }
+
+        for (i, byte) in BIG_SPRITE_MEM.iter().enumerate() {
+            new_memory.data[BIG_SPRITE_ADDR + i] = Wrapping(*byte);
         }
+
+        new_memory
     }
 
-    /// Create a new 4kb memory region with the supplied data set at the given offset. Used to load
+    /// Create a new memory region with the supplied data set at the given offset. Used to load
     /// programs at 0x200 (the default starting location)
     pub fn of_bytes(data: &[u8], offset: usize) -> Self {
         let mut new_memory = Self::new();
-        for i in 0..min(data.len(), MEMORY_SIZE) {
-            new_memory.data[offset + i] = Wrapping(data[i]);
+        // Anything past the end of memory, including an offset past it, is dropped
+        for (dst, src) in new_memory.data.iter_mut().skip(offset).zip(data) {
+            *dst = Wrapping(*src);
         }
         new_memory
     }
 
-    /// Get a u8 from memory. If the address is > 0x4000 then it references the SPRITE_MEM
-    /// containing text
-    pub fn get(&self, idx: usize) -> Wrapping<u8> {
-        if idx < 0x4000 {
-            self.data[idx]
-        } else {
-            Wrapping(SPRITE_MEM[idx - 0x4000])
+    /// Write the whole address space and the display to a save state
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.data.iter().for_each(|byte| writer.u8(byte.0));
+        writer.bytes(&self.frame_buffer);
+        writer.bool(self.hires);
+        writer.u8(self.planes);
+    }
+
+    /// Read back a memory written by save_state
+    pub fn load_state(reader: &mut StateReader) -> Result<Self, StateError> {
+        let mut memory = Self::new();
+
+        for (dst, src) in memory.data.iter_mut().zip(reader.bytes(MEMORY_SIZE)?) {
+            *dst = Wrapping(*src);
+        }
+        memory.frame_buffer.copy_from_slice(reader.bytes(SCREEN_SIZE)?);
+        if memory.frame_buffer.iter().any(|pixel| (*pixel as usize) >= 1 << NUM_PLANES) {
+            return Err(StateError::InvalidValue { field: "pixel" });
         }
+        memory.hires = reader.bool("resolution")?;
+        memory.planes = reader.u8()?;
+        if memory.planes >= 1 << NUM_PLANES {
+            return Err(StateError::InvalidValue { field: "plane mask" });
+        }
+
+        Ok(memory)
     }
 
-    /// Set a u8 in memory
-    pub fn set(&mut self, idx: usize, val: Wrapping<u8>) {
-        self.data[idx] = val;
+    /// Get a u8 from memory, failing if the address is outside of the address space
+    pub fn get(&self, idx: usize) -> Result<Wrapping<u8>, ExecError> {
+        let val = self.read(idx)?;
+        self.log(idx, AccessKind::Read, val.0);
+        Ok(val)
     }
 
-    /// Return a u16 in system order from memory, performing necessary endianness conversion
-    pub fn get16(&self, idx: usize) -> Wrapping<u16> {
-        let first_part = self.get(idx).0;
-        let second_part = self.get(idx + 1).0;
+    /// Set a u8 in memory, failing if the address is outside of the address space
+    pub fn set(&mut self, idx: usize, val: Wrapping<u8>) -> Result<(), ExecError> {
+        let byte = self.data.get_mut(idx).ok_or(ExecError::MemoryOutOfBounds { addr: idx })?;
+        *byte = val;
+        self.log(idx, AccessKind::Write, val.0);
+        Ok(())
+    }
+
+    /// Get a u8 from memory without recording it in the access log, for debuggers inspecting
+    /// memory between instructions
+    pub fn peek(&self, idx: usize) -> Result<Wrapping<u8>, ExecError> {
+        self.read(idx)
+    }
+
+    fn read(&self, idx: usize) -> Result<Wrapping<u8>, ExecError> {
+        self.data.get(idx).copied().ok_or(ExecError::MemoryOutOfBounds { addr: idx })
+    }
+
+    fn log(&self, addr: usize, kind: AccessKind, value: u8) {
+        if self.log_accesses {
+            self.access_log.borrow_mut().push(Access { addr, kind, value });
+        }
+    }
+
+    /// Take the accesses recorded since the last call, emptying the access log
+    pub fn take_accesses(&self) -> Vec<Access> {
+        self.access_log.take()
+    }
+
+    /// Return a u16 in system order from memory, performing necessary endianness conversion.
+    /// This is used to fetch instructions, so it is not recorded in the access log.
+    pub fn get16(&self, idx: usize) -> Result<Wrapping<u16>, ExecError> {
+        let first_part = self.read(idx)?.0;
+        let second_part = self.read(idx + 1)?.0;
         let combined = first_part as u16 | (second_part as u16) << 8;
-        Wrapping(u16::from_be(combined))
+        Ok(Wrapping(u16::from_be(combined)))
+    }
+
+    /// The width of the display in the current resolution
+    pub fn width(&self) -> usize {
+        if self.hires { HIRES_SCREEN_WIDTH } else { SCREEN_WIDTH }
+    }
+
+    /// The height of the display in the current resolution
+    pub fn height(&self) -> usize {
+        if self.hires { HIRES_SCREEN_HEIGHT } else { SCREEN_HEIGHT }
+    }
+
+    /// Return the value of the pixel at (x, y) in the current resolution
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        self.frame_buffer[(y * self.width()) + x]
     }
 
-    /// Clear the entire framebuffer
+    /// Switch between the low and high resolution modes. Every plane of the display is cleared on
+    /// a switch.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.frame_buffer = [0; SCREEN_SIZE];
+    }
+
+    /// Clear the selected planes of the framebuffer
     pub fn clear_display(&mut self) {
-        for i in 0..SCREEN_SIZE {
-            self.frame_buffer[i] = 0;
+        let planes = self.planes;
+        self.frame_buffer.iter_mut().for_each(|pixel| *pixel &= !planes);
+    }
+
+    /// Scroll the selected planes of the display by (dx, dy) pixels, filling the uncovered area
+    /// with blank pixels
+    pub fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width() as isize, self.height() as isize);
+        let planes = self.planes;
+        let previous = self.frame_buffer;
+
+        for y in 0..height {
+            for x in 0..width {
+                let (src_x, src_y) = (x - dx, y - dy);
+                let value = if src_x >= 0 && src_x < width && src_y >= 0 && src_y < height {
+                    previous[(src_y * width + src_x) as usize]
+                } else {
+                    0
+                };
+                let idx = (y * width + x) as usize;
+                self.frame_buffer[idx] = (self.frame_buffer[idx] & !planes) | (value & planes);
+            }
         }
     }
 
-    pub fn draw_sprite(&mut self, x: usize, y: usize, n: usize, i: usize) -> u8 {
+    /// Draw an 8 pixel wide, n pixel high sprite read from i at (x, y), returning 1 if any set
+    /// pixel was cleared. If n is zero then a SUPER-CHIP 16x16 sprite is drawn instead, stored as
+    /// two bytes per row. The starting position always wraps around the screen but if clip is set
+    /// then pixels that fall off the right or bottom edge are discarded rather than wrapped.
+    /// When more than one XO-CHIP plane is selected the sprite data for each plane follows the
+    /// previous one in memory.
+    pub fn draw_sprite(&mut self, x: usize, y: usize, n: usize, i: usize, clip: bool) -> Result<u8, ExecError> {
 
         let mut vf_reg = 0;
+        let (width, height) = (self.width(), self.height());
+        let (sprite_width, sprite_height) = if n == 0 { (16, 16) } else { (8, n) };
+        let bytes_per_row = sprite_width / 8;
+        let x = x % width;
+        let y = y % height;
+        let mut i = i;
 
-        for yoff in 0..n {
-
-            let y = (y + yoff) % SCREEN_HEIGHT;
-            let sprite = self.get(i + yoff).0;
+        for plane in (0..NUM_PLANES).map(|plane| 1 << plane) {
 
-            for xoff in 0..8 {
-                let x = (x + xoff) % SCREEN_WIDTH;
+            if self.planes & plane == 0 {
+                continue;
+            }
 
-                let fb = &mut self.frame_buffer;
-                let fb_idx = (y * SCREEN_WIDTH) + x;
-                // TODO: Return 1 if any pixel touched is already set. Flip it then also
-                let xor_value = if sprite & (1 << (7 - xoff)) != 0 { 1 } else { 0 };
-                let current_value = fb[fb_idx];
-                let new_value = current_value ^ xor_value;
-                trace!("{} {} {} {} {}", x, y, new_value, sprite, i + yoff);
+            for yoff in 0..sprite_height {
 
-                if current_value == 1 && new_value == 0 {
-                    vf_reg = 1;
+                if clip && y + yoff >= height {
+                    break;
                 }
 
-                fb[fb_idx] = new_value;
+                let y = (y + yoff) % height;
+
+                for byte in 0..bytes_per_row {
+
+                    if clip && x + byte * 8 >= width {
+                        break;
+                    }
+
+                    // Each sprite byte is read once so the access log records one read per byte
+                    let sprite_idx = i + (yoff * bytes_per_row) + byte;
+                    let sprite = self.get(sprite_idx)?.0;
+
+                    for bit in 0..8 {
+                        let xoff = byte * 8 + bit;
+
+                        if clip && x + xoff >= width {
+                            break;
+                        }
+
+                        if sprite & (1 << (7 - bit)) == 0 {
+                            continue;
+                        }
+
+                        let x = (x + xoff) % width;
+                        let fb_idx = (y * width) + x;
+                        let current_value = self.frame_buffer[fb_idx];
+                        trace!("{} {} {} {} {}", x, y, current_value ^ plane, sprite, sprite_idx);
+
+                        if current_value & plane != 0 {
+                            vf_reg = 1;
+                        }
+
+                        self.frame_buffer[fb_idx] = current_value ^ plane;
+                    }
+                }
             }
+
+            i += sprite_height * bytes_per_row;
         }
 
-        vf_reg
+        Ok(vf_reg)
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -107,15 +328,153 @@ mod tests {
     #[test]
     fn set_and_get() {
         let mut mem = Memory::new();
-        mem.set(0x5, Wrapping(0x9E));
-        assert_eq!(mem.get(0x5), Wrapping(0x9E));
+        mem.set(0x5, Wrapping(0x9E)).unwrap();
+        assert_eq!(mem.get(0x5), Ok(Wrapping(0x9E)));
+    }
+
+    #[test]
+    fn access_log() {
+        let mut mem = Memory::new();
+        mem.set(0x300, Wrapping(0x12)).unwrap();
+        assert!(mem.take_accesses().is_empty());
+
+        mem.log_accesses = true;
+        mem.set(0x300, Wrapping(0x34)).unwrap();
+        mem.get(0x300).unwrap();
+        mem.get16(0x300).unwrap();
+        mem.peek(0x300).unwrap();
+        assert_eq!(mem.take_accesses(), vec![
+            Access { addr: 0x300, kind: AccessKind::Write, value: 0x34 },
+            Access { addr: 0x300, kind: AccessKind::Read, value: 0x34 },
+        ]);
+        assert!(mem.take_accesses().is_empty());
+    }
+
+    #[test]
+    fn draw_sprite_reads_each_byte_once() {
+        let mut mem = Memory::new();
+        mem.set(0x300, Wrapping(0xFF)).unwrap();
+        mem.set(0x301, Wrapping(0x81)).unwrap();
+        mem.log_accesses = true;
+        mem.draw_sprite(0, 0, 2, 0x300, false).unwrap();
+        assert_eq!(mem.take_accesses(), vec![
+            Access { addr: 0x300, kind: AccessKind::Read, value: 0xFF },
+            Access { addr: 0x301, kind: AccessKind::Read, value: 0x81 },
+        ]);
     }
 
     #[test]
     fn get16() {
         let mut mem = Memory::new();
-        mem.set(0x5, Wrapping(0x9E));
-        mem.set(0x6, Wrapping(0xFE));
-        assert_eq!(mem.get16(0x5), Wrapping(0x9EFE));
+        mem.set(0x5, Wrapping(0x9E)).unwrap();
+        mem.set(0x6, Wrapping(0xFE)).unwrap();
+        assert_eq!(mem.get16(0x5), Ok(Wrapping(0x9EFE)));
+    }
+
+    #[test]
+    fn draw_sprite_wraps() {
+        let mut mem = Memory::new();
+        mem.set(0x300, Wrapping(0xFF)).unwrap();
+        assert_eq!(mem.draw_sprite(60, 31, 1, 0x300, false), Ok(0));
+        assert_eq!(mem.frame_buffer[31 * SCREEN_WIDTH + 63], 1);
+        assert_eq!(mem.frame_buffer[31 * SCREEN_WIDTH], 1);
+        assert_eq!(mem.frame_buffer[31 * SCREEN_WIDTH + 3], 1);
+        assert_eq!(mem.frame_buffer[31 * SCREEN_WIDTH + 4], 0);
+    }
+
+    #[test]
+    fn draw_sprite_clips() {
+        let mut mem = Memory::new();
+        mem.set(0x300, Wrapping(0xFF)).unwrap();
+        mem.set(0x301, Wrapping(0xFF)).unwrap();
+        assert_eq!(mem.draw_sprite(60, 31, 2, 0x300, true), Ok(0));
+        assert_eq!(mem.frame_buffer[31 * SCREEN_WIDTH + 63], 1);
+        assert_eq!(mem.frame_buffer[31 * SCREEN_WIDTH], 0);
+        assert_eq!(mem.frame_buffer[3], 0);
+        assert_eq!(mem.frame_buffer.iter().filter(|x| **x != 0).count(), 4);
+    }
+
+    #[test]
+    fn draw_large_sprite() {
+        let mut mem = Memory::new();
+        mem.set_hires(true);
+        for i in 0..32 {
+            mem.set(0x300 + i, Wrapping(0x80)).unwrap();
+        }
+        assert_eq!(mem.draw_sprite(100, 10, 0, 0x300, false), Ok(0));
+        assert_eq!(mem.pixel(100, 10), 1);
+        assert_eq!(mem.pixel(108, 10), 1);
+        assert_eq!(mem.pixel(101, 10), 0);
+        assert_eq!(mem.pixel(108, 25), 1);
+        assert_eq!(mem.frame_buffer.iter().filter(|x| **x != 0).count(), 32);
+        assert_eq!(mem.draw_sprite(100, 10, 0, 0x300, false), Ok(1));
+    }
+
+    #[test]
+    fn scroll() {
+        let mut mem = Memory::new();
+        mem.set_hires(true);
+        mem.frame_buffer[0] = 1;
+        mem.scroll(4, 2);
+        assert_eq!(mem.pixel(0, 0), 0);
+        assert_eq!(mem.pixel(4, 2), 1);
+        mem.scroll(-8, 0);
+        assert_eq!(mem.frame_buffer.iter().filter(|x| **x != 0).count(), 0);
+    }
+
+    #[test]
+    fn fonts_are_loaded() {
+        let mem = Memory::of_bytes(&[0x12, 0x34], 0x200);
+        assert_eq!(mem.get(SPRITE_ADDR + 5).unwrap().0, 0x20);
+        assert_eq!(mem.get(BIG_SPRITE_ADDR + 10).unwrap().0, 0x18);
+        assert_eq!(mem.get(0x200).unwrap().0, 0x12);
+        assert_eq!(mem.get(MEMORY_SIZE - 1).unwrap().0, 0);
+    }
+
+    #[test]
+    fn of_bytes_past_end_of_memory() {
+        let mem = Memory::of_bytes(&[0x12, 0x34], MEMORY_SIZE - 1);
+        assert_eq!(mem.get(MEMORY_SIZE - 1).unwrap().0, 0x12);
+        let mem = Memory::of_bytes(&[0x12, 0x34], MEMORY_SIZE + 1);
+        assert_eq!(mem.get(MEMORY_SIZE - 1).unwrap().0, 0);
+    }
+
+    #[test]
+    fn draw_two_planes() {
+        let mut mem = Memory::new();
+        mem.set(0x300, Wrapping(0x80)).unwrap();
+        mem.set(0x301, Wrapping(0xC0)).unwrap();
+        mem.planes = 0x3;
+        assert_eq!(mem.draw_sprite(0, 0, 1, 0x300, false), Ok(0));
+        assert_eq!(mem.pixel(0, 0), 3);
+        assert_eq!(mem.pixel(1, 0), 2);
+
+        mem.planes = 0x2;
+        mem.clear_display();
+        assert_eq!(mem.pixel(0, 0), 1);
+        assert_eq!(mem.pixel(1, 0), 0);
+
+        mem.planes = 0x1;
+        assert_eq!(mem.draw_sprite(0, 0, 1, 0x300, false), Ok(1));
+        assert_eq!(mem.pixel(0, 0), 0);
+    }
+
+    #[test]
+    fn state_rejects_bad_pixels() {
+        let mut mem = Memory::new();
+        mem.frame_buffer[10] = 1 << NUM_PLANES;
+        let mut writer = StateWriter::new();
+        mem.save_state(&mut writer);
+        let state = writer.finish();
+        let mut reader = StateReader::new(&state).unwrap();
+        assert_eq!(Memory::load_state(&mut reader).err(), Some(StateError::InvalidValue { field: "pixel" }));
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let mut mem = Memory::new();
+        assert_eq!(mem.get(MEMORY_SIZE), Err(ExecError::MemoryOutOfBounds { addr: MEMORY_SIZE }));
+        assert_eq!(mem.set(MEMORY_SIZE, Wrapping(1)), Err(ExecError::MemoryOutOfBounds { addr: MEMORY_SIZE }));
+        assert_eq!(mem.get16(MEMORY_SIZE - 1), Err(ExecError::MemoryOutOfBounds { addr: MEMORY_SIZE }));
     }
 }
@@ -0,0 +1,441 @@
+use crate::backend::{Audio, Backends, Display, Headless, Input, KeyScript};
+pub use crate::backend::KeyEvent;
+use crate::cpu::{StepOutcome, NUM_KEYS};
+use crate::error::{ExecError, MovieError};
+use crate::machine::{Machine, Timing, DEFAULT_IPF};
+use crate::memory::Memory;
+use crate::quirks::Quirks;
+use crate::random::{Random, RandomMode};
+use crate::state::crc32;
+use std::fmt;
+
+/// The first line of every movie file
+const MOVIE_HEADER: &str = "chip9 movie 2";
+
+/// The first line of movies written before the quirks were recorded
+const MOVIE_HEADER_V1: &str = "chip9 movie 1";
+
+/// Borrows one of the flags of a Quirks
+type QuirkFlag = fn(&mut Quirks) -> &mut bool;
+
+/// The names the quirks are written with on the quirks line of a movie, with the flag for each
+const QUIRK_NAMES: [(&str, QuirkFlag); 5] = [
+    ("shift-vy", |quirks| &mut quirks.shift_uses_vy),
+    ("load-store-i", |quirks| &mut quirks.load_store_increments_i),
+    ("jump-vx", |quirks| &mut quirks.jump_uses_vx),
+    ("logic-vf", |quirks| &mut quirks.logic_resets_vf),
+    ("clip", |quirks| &mut quirks.clip_sprites),
+];
+
+/// A hash of the machine state is recorded every this many frames, and at the end of the movie
+pub const CHECKPOINT_INTERVAL: u64 = 60;
+
+/// A recording of every key change made while a ROM ran, along with what is needed to run it
+/// again identically: the ROM it was recorded against, the random number generator the machine
+/// started with, its quirks and speed, and hashes of the machine state to check playback against.
+/// The quirks line lists the quirks that were enabled.
+///
+/// Movies are stored as text, one item per line:
+///
+/// ```text
+/// chip9 movie 2
+/// rom 1a2b3c4d
+/// random splitmix 000000000000002a
+/// quirks shift-vy load-store-i logic-vf clip
+/// timing instructions
+/// ipf 10
+/// frames 600
+/// key 12 5 down
+/// check 59 89abcdef
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    /// The CRC-32 of the ROM
+    pub rom_hash: u32,
+    /// The random number generator at the start of the recording
+    pub rng: Random,
+    /// The quirks the machine ran with
+    pub quirks: Quirks,
+    /// How the machine measured time
+    pub timing: Timing,
+    /// The instructions per frame the machine ran at
+    pub ipf: usize,
+    /// The number of frames recorded
+    pub frames: u64,
+    /// Every key change, in frame order
+    pub events: Vec<KeyEvent>,
+    /// The frame number and CRC-32 of the save state taken after that frame
+    pub checkpoints: Vec<(u64, u32)>,
+}
+
+/// Hash the complete state of a machine
+pub fn state_hash(machine: &Machine) -> u32 {
+    crc32(&machine.save_state())
+}
+
+impl Movie {
+
+    /// Parse a movie written by to_string
+    pub fn parse(text: &str) -> Result<Self, MovieError> {
+        let mut lines = text.lines().enumerate().map(|(idx, line)| (idx + 1, line.trim()));
+
+        match lines.next() {
+            Some((_, MOVIE_HEADER)) => {}
+            Some((_, MOVIE_HEADER_V1)) => {
+                return Err(MovieError::Parse { line: 1, message: "version 1 movies do not record the quirks, record it again".to_string() });
+            }
+            _ => return Err(MovieError::Parse { line: 1, message: "not a chip9 movie".to_string() }),
+        }
+
+        let mut rom_hash = None;
+        let mut rng = None;
+        let mut quirks = None;
+        let mut frames = None;
+        let mut timing = Timing::Instructions;
+        let mut ipf = DEFAULT_IPF;
+        let mut events = Vec::new();
+        let mut checkpoints = Vec::new();
+        let mut checkpoint_lines = Vec::new();
+
+        for (line, text) in lines.filter(|(_, text)| !text.is_empty()) {
+            let error = |message: &str| MovieError::Parse { line, message: message.to_string() };
+            let hex32 = |field: &str| u32::from_str_radix(field, 16).map_err(|_| error("bad hash"));
+            let fields: Vec<&str> = text.split_whitespace().collect();
+
+            match fields.as_slice() {
+                ["rom", hash] => rom_hash = Some(hex32(hash)?),
+                ["random", mode, state] => {
+                    let state = u64::from_str_radix(state, 16).map_err(|_| error("bad random state"))?;
+                    rng = Some(match *mode {
+                        "splitmix" => Random::new(state),
+                        "vip" => Random::vip(state),
+                        _ => return Err(error("unknown random mode")),
+                    });
+                }
+                ["quirks", names @ ..] => {
+                    let mut enabled = Quirks {
+                        shift_uses_vy: false,
+                        load_store_increments_i: false,
+                        jump_uses_vx: false,
+                        logic_resets_vf: false,
+                        clip_sprites: false,
+                    };
+                    for name in names {
+                        let (_, quirk) = QUIRK_NAMES.iter().find(|(quirk, _)| quirk == name).ok_or_else(|| error("unknown quirk"))?;
+                        *quirk(&mut enabled) = true;
+                    }
+                    quirks = Some(enabled);
+                }
+                ["timing", "instructions"] => timing = Timing::Instructions,
+                ["timing", "vip"] => timing = Timing::CosmacVip,
+                ["ipf", count] => ipf = count.parse().map_err(|_| error("bad instructions per frame"))?,
+                ["frames", count] => frames = Some(count.parse().map_err(|_| error("bad frame count"))?),
+                ["key", frame, key, state] => {
+                    let frame = frame.parse().map_err(|_| error("bad frame"))?;
+                    let key = u8::from_str_radix(key, 16).ok().filter(|key| (*key as usize) < NUM_KEYS).ok_or_else(|| error("bad key"))?;
+                    let pressed = match *state {
+                        "down" => true,
+                        "up" => false,
+                        _ => return Err(error("expected down or up")),
+                    };
+                    if events.last().is_some_and(|last: &KeyEvent| last.frame > frame) {
+                        return Err(error("key events are out of order"));
+                    }
+                    events.push(KeyEvent { frame, key, pressed });
+                }
+                ["check", frame, hash] => {
+                    let frame = frame.parse().map_err(|_| error("bad frame"))?;
+                    if checkpoints.last().is_some_and(|(last, _): &(u64, u32)| *last >= frame) {
+                        return Err(error("checkpoints are out of order"));
+                    }
+                    checkpoints.push((frame, hex32(hash)?));
+                    checkpoint_lines.push(line);
+                }
+                _ => return Err(error("unrecognised line")),
+            }
+        }
+
+        let missing = |field: &'static str| MovieError::MissingField { field };
+        let frames = frames.ok_or_else(|| missing("frames"))?;
+
+        // A checkpoint after the last frame would never be checked
+        if let Some(idx) = checkpoints.iter().position(|(frame, _)| *frame >= frames) {
+            return Err(MovieError::Parse { line: checkpoint_lines[idx], message: "checkpoint is after the last frame".to_string() });
+        }
+
+        Ok(Self {
+            rom_hash: rom_hash.ok_or_else(|| missing("rom"))?,
+            rng: rng.ok_or_else(|| missing("random"))?,
+            quirks: quirks.ok_or_else(|| missing("quirks"))?,
+            timing,
+            ipf,
+            frames,
+            events,
+            checkpoints,
+        })
+    }
+
+    /// Run the movie headlessly on a fresh machine with the recorded quirks and speed, feeding it
+    /// the recorded key changes and checking the machine state at every checkpoint. Returns the
+    /// machine after the last frame.
+    pub fn play(&self, rom: &[u8]) -> Result<Machine, MovieError> {
+        let actual = crc32(rom);
+        if actual != self.rom_hash {
+            return Err(MovieError::RomMismatch { expected: self.rom_hash, actual });
+        }
+
+        let mut machine = Machine::of_bytes_with_random(rom.to_vec(), self.quirks, self.rng);
+        machine.timing = self.timing;
+        machine.ipf = self.ipf;
+        let mut backends = Backends {
+            display: Headless,
+            input: KeyScript::new(self.events.clone()),
+            audio: Headless,
+        };
+        let mut checkpoints = self.checkpoints.iter().peekable();
+
+        for frame in 0..self.frames {
+            machine.update(&mut backends)?;
+
+            while let Some((_, expected)) = checkpoints.next_if(|(checkpoint, _)| *checkpoint == frame) {
+                let actual = state_hash(&machine);
+                if actual != *expected {
+                    return Err(MovieError::Desync { frame, expected: *expected, actual });
+                }
+            }
+        }
+
+        Ok(machine)
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self.rng.mode {
+            RandomMode::SplitMix => "splitmix",
+            RandomMode::Vip => "vip",
+        };
+
+        writeln!(f, "{}", MOVIE_HEADER)?;
+        writeln!(f, "rom {:08x}", self.rom_hash)?;
+        writeln!(f, "random {} {:016x}", mode, self.rng.state())?;
+
+        let mut quirks = self.quirks;
+        write!(f, "quirks")?;
+        for (name, quirk) in QUIRK_NAMES.iter() {
+            if *quirk(&mut quirks) {
+                write!(f, " {}", name)?;
+            }
+        }
+        writeln!(f)?;
+
+        writeln!(f, "timing {}", if self.timing == Timing::CosmacVip { "vip" } else { "instructions" })?;
+        writeln!(f, "ipf {}", self.ipf)?;
+        writeln!(f, "frames {}", self.frames)?;
+
+        for event in &self.events {
+            writeln!(f, "key {} {:x} {}", event.frame, event.key, if event.pressed { "down" } else { "up" })?;
+        }
+
+        for (frame, hash) in &self.checkpoints {
+            writeln!(f, "check {} {:08x}", frame, hash)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Records a movie while a machine is driven by Recorder::update in place of Machine::update.
+/// The recorder must be created alongside a fresh machine.
+pub struct Recorder {
+    movie: Movie,
+    keys: [bool; NUM_KEYS],
+}
+
+impl Recorder {
+
+    /// Start recording a machine that has just been created from the ROM
+    pub fn new(rom: &[u8], machine: &Machine) -> Self {
+        Self {
+            movie: Movie {
+                rom_hash: crc32(rom),
+                rng: machine.cpu.registers.rng,
+                quirks: machine.cpu.quirks,
+                timing: machine.timing,
+                ipf: machine.ipf,
+                frames: 0,
+                events: Vec::new(),
+                checkpoints: Vec::new(),
+            },
+            keys: [false; NUM_KEYS],
+        }
+    }
+
+    /// Run a frame of the machine against the backend with Machine::update, recording any key
+    /// that changed and a checkpoint every CHECKPOINT_INTERVAL frames
+    pub fn update<B: Display + Input + Audio>(&mut self, machine: &mut Machine, backend: &mut B) -> Result<StepOutcome, ExecError> {
+        let frame = self.movie.frames;
+        let mut recording = Recording { backend, frame, keys: &mut self.keys, events: &mut self.movie.events };
+        let outcome = machine.update(&mut recording)?;
+
+        if (frame + 1) % CHECKPOINT_INTERVAL == 0 {
+            self.movie.checkpoints.push((frame, state_hash(machine)));
+        }
+
+        self.movie.frames += 1;
+        Ok(outcome)
+    }
+
+    /// Stop recording, adding a final checkpoint for the state the machine finished in
+    pub fn finish(mut self, machine: &Machine) -> Movie {
+        if let Some(last) = self.movie.frames.checked_sub(1) {
+            if self.movie.checkpoints.last().map(|(frame, _)| *frame) != Some(last) {
+                self.movie.checkpoints.push((last, state_hash(machine)));
+            }
+        }
+
+        self.movie
+    }
+}
+
+/// Passes a frame through to the real backend, noting which keys changed when it is polled
+struct Recording<'a, B> {
+    backend: &'a mut B,
+    frame: u64,
+    keys: &'a mut [bool; NUM_KEYS],
+    events: &'a mut Vec<KeyEvent>,
+}
+
+impl<'a, B: Input> Input for Recording<'a, B> {
+    fn poll(&mut self) -> [bool; NUM_KEYS] {
+        let keys = self.backend.poll();
+
+        for (key, (pressed, previous)) in keys.iter().zip(self.keys.iter()).enumerate() {
+            if pressed != previous {
+                self.events.push(KeyEvent { frame: self.frame, key: key as u8, pressed: *pressed });
+            }
+        }
+
+        *self.keys = keys;
+        keys
+    }
+}
+
+impl<'a, B: Display> Display for Recording<'a, B> {
+    fn draw(&mut self, memory: &Memory) {
+        self.backend.draw(memory);
+    }
+}
+
+impl<'a, B: Audio> Audio for Recording<'a, B> {
+    fn set_playing(&mut self, playing: bool) {
+        self.backend.set_playing(playing);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Presses each key in turn for a few frames
+    struct Script(u64);
+
+    impl Input for Script {
+        fn poll(&mut self) -> [bool; NUM_KEYS] {
+            let mut keys = [false; NUM_KEYS];
+            if self.0 % 8 < 3 {
+                keys[(self.0 / 8) as usize % NUM_KEYS] = true;
+            }
+            self.0 += 1;
+            keys
+        }
+    }
+
+    impl Display for Script {
+        fn draw(&mut self, _memory: &Memory) {}
+    }
+
+    impl Audio for Script {
+        fn set_playing(&mut self, _playing: bool) {}
+    }
+
+    /// Waits for a key, then draws a random sprite at a random position and loops
+    const ROM: [u8; 12] = [0xF0, 0x0A, 0xC1, 0x3F, 0xC2, 0x1F, 0xF0, 0x29, 0xD1, 0x25, 0x12, 0x00];
+
+    fn record(frames: u64) -> (Movie, Machine) {
+        record_with(frames, Quirks::default())
+    }
+
+    fn record_with(frames: u64, quirks: Quirks) -> (Movie, Machine) {
+        let mut machine = Machine::of_bytes_with_random(ROM.to_vec(), quirks, Random::new(99));
+        let mut recorder = Recorder::new(&ROM, &machine);
+        let mut script = Script(0);
+
+        for _ in 0..frames {
+            recorder.update(&mut machine, &mut script).unwrap();
+        }
+
+        (recorder.finish(&machine), machine)
+    }
+
+    #[test]
+    fn playback_matches_recording() {
+        let (movie, machine) = record(150);
+        assert_eq!(movie.checkpoints.iter().map(|(frame, _)| *frame).collect::<Vec<_>>(), vec![59, 119, 149]);
+        assert_eq!(movie.events[..2], [
+            KeyEvent { frame: 0, key: 0, pressed: true },
+            KeyEvent { frame: 3, key: 0, pressed: false },
+        ]);
+
+        let parsed = Movie::parse(&movie.to_string()).unwrap();
+        assert_eq!(parsed, movie);
+
+        let played = parsed.play(&ROM).unwrap();
+        assert_eq!(played.save_state(), machine.save_state());
+    }
+
+    #[test]
+    fn playback_uses_recorded_quirks() {
+        let (movie, machine) = record_with(150, Quirks::cosmac_vip());
+        assert!(movie.to_string().contains("\nquirks shift-vy load-store-i logic-vf clip\n"));
+
+        let parsed = Movie::parse(&movie.to_string()).unwrap();
+        assert_eq!(parsed.quirks, Quirks::cosmac_vip());
+        assert_eq!(parsed.play(&ROM).unwrap().save_state(), machine.save_state());
+    }
+
+    #[test]
+    fn playback_detects_desync() {
+        let (mut movie, _) = record(150);
+        movie.events.remove(2);
+        assert!(matches!(movie.play(&ROM), Err(MovieError::Desync { frame: 59, .. })));
+
+        let (mut movie, _) = record(10);
+        movie.rng = Random::new(100);
+        assert!(matches!(movie.play(&ROM), Err(MovieError::Desync { frame: 9, .. })));
+
+        assert!(matches!(movie.play(&[0x12, 0x00]), Err(MovieError::RomMismatch { .. })));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Movie::parse("hello"), Err(MovieError::Parse { line: 1, message: "not a chip9 movie".to_string() }));
+        let error = Movie::parse("chip9 movie 2\nrom 0\nkey 1 g down\n").unwrap_err();
+        assert_eq!(error, MovieError::Parse { line: 3, message: "bad key".to_string() });
+        let error = Movie::parse("chip9 movie 2\nrom 0\nrandom splitmix 0\nquirks\n").unwrap_err();
+        assert_eq!(error, MovieError::MissingField { field: "frames" });
+
+        let (movie, _) = record(150);
+        let text = movie.to_string();
+        let error = Movie::parse(&text.replace("check 119 ", "check 30 ")).unwrap_err();
+        assert!(matches!(error, MovieError::Parse { message, .. } if message == "checkpoints are out of order"));
+        let error = Movie::parse(&text.replace("check 149 ", "check 150 ")).unwrap_err();
+        let line = text.lines().position(|line| line.starts_with("check 149 ")).unwrap() + 1;
+        assert_eq!(error, MovieError::Parse { line, message: "checkpoint is after the last frame".to_string() });
+
+        let error = Movie::parse("chip9 movie 2\nquirks clip wrap\n").unwrap_err();
+        assert_eq!(error, MovieError::Parse { line: 2, message: "unknown quirk".to_string() });
+        let error = Movie::parse("chip9 movie 1\nrom 0\n").unwrap_err();
+        assert_eq!(error, MovieError::Parse { line: 1, message: "version 1 movies do not record the quirks, record it again".to_string() });
+    }
+}
@@ -0,0 +1,411 @@
+use crate::error::DecodeError;
+use std::fmt;
+
+/// If opcode has the form _XN_ or _XR_ then the first register can be extracted with this mask
+pub const REGISTER_MASK: u16 = 0x0F00;
+
+/// If the opcode has the form _XR_ the second register can be extracted with this mask
+pub const REGISTER_TWO_MASK: u16 = 0x00F0;
+
+/// If opcodes have the form __II then the immediate value can be extracted with this mask
+pub const DATA_MASK: u16 = 0x00FF;
+
+/// If the opcode immediate contains only a single nibble of data (the final nibble of the opcode)
+/// we extract it with this mask
+pub const NIBBLE_DATA_MASK: u16 = 0x000F;
+
+/// If the opcode has the form _NNN then the address can be extracted with this mask
+pub const ADDRESS_MASK: u16 = 0x0FFF;
+
+/// A decoded CHIP-8, SUPER-CHIP or XO-CHIP instruction. Registers are numbered 0-F and addresses
+/// are 12 bits, and encode keeps only those bits of each field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    /// 00E0: clear the selected planes of the display
+    Cls,
+    /// 00EE: return from a subroutine
+    Ret,
+    /// 0NNN: call a machine code routine, which we do not emulate
+    Sys(u16),
+    /// 00CN: scroll the display down N pixels (SUPER-CHIP)
+    ScrollDown(u8),
+    /// 00DN: scroll the display up N pixels (XO-CHIP)
+    ScrollUp(u8),
+    /// 00FB: scroll the display right 4 pixels (SUPER-CHIP)
+    ScrollRight,
+    /// 00FC: scroll the display left 4 pixels (SUPER-CHIP)
+    ScrollLeft,
+    /// 00FD: exit the program (SUPER-CHIP)
+    Exit,
+    /// 00FE: switch to the 64x32 display (SUPER-CHIP)
+    Lores,
+    /// 00FF: switch to the 128x64 display (SUPER-CHIP)
+    Hires,
+    /// 1NNN: jump to NNN
+    Jp(u16),
+    /// 2NNN: call the subroutine at NNN
+    Call(u16),
+    /// 3XNN: skip the next instruction if VX == NN
+    SeImm(u8, u8),
+    /// 4XNN: skip the next instruction if VX != NN
+    SneImm(u8, u8),
+    /// 5XY0: skip the next instruction if VX == VY
+    SeReg(u8, u8),
+    /// 5XY2: save VX to VY to memory at I (XO-CHIP)
+    SaveRange(u8, u8),
+    /// 5XY3: load VX to VY from memory at I (XO-CHIP)
+    LoadRange(u8, u8),
+    /// 6XNN: VX = NN
+    LdImm(u8, u8),
+    /// 7XNN: VX += NN, without touching VF
+    AddImm(u8, u8),
+    /// 8XY0: VX = VY
+    LdReg(u8, u8),
+    /// 8XY1: VX |= VY
+    Or(u8, u8),
+    /// 8XY2: VX &= VY
+    And(u8, u8),
+    /// 8XY3: VX ^= VY
+    Xor(u8, u8),
+    /// 8XY4: VX += VY, with VF set on carry
+    AddReg(u8, u8),
+    /// 8XY5: VX -= VY, with VF set when there is no borrow
+    Sub(u8, u8),
+    /// 8XY6: VX = VY >> 1 (or VX >> 1), with VF set to the bit shifted out
+    Shr(u8, u8),
+    /// 8XY7: VX = VY - VX, with VF set when there is no borrow
+    Subn(u8, u8),
+    /// 8XYE: VX = VY << 1 (or VX << 1), with VF set to the bit shifted out
+    Shl(u8, u8),
+    /// 9XY0: skip the next instruction if VX != VY
+    SneReg(u8, u8),
+    /// ANNN: I = NNN
+    LdI(u16),
+    /// BNNN: jump to NNN + V0 (or VX)
+    JpV0(u16),
+    /// CXNN: VX = a random byte & NN
+    Rnd(u8, u8),
+    /// DXYN: draw an N row sprite from I at (VX, VY), or a 16x16 sprite if N is zero
+    Drw(u8, u8, u8),
+    /// EX9E: skip the next instruction if the key in VX is pressed
+    Skp(u8),
+    /// EXA1: skip the next instruction if the key in VX is not pressed
+    Sknp(u8),
+    /// F000 NNNN: I = the 16-bit address in the following word (XO-CHIP)
+    LdILong,
+    /// FN01: select the bit planes in N (XO-CHIP)
+    Plane(u8),
+    /// F002: load the audio pattern from memory at I (XO-CHIP)
+    Audio,
+    /// FX07: VX = delay timer
+    LdVxDt(u8),
+    /// FX0A: wait for a key press and store it in VX
+    LdVxK(u8),
+    /// FX15: delay timer = VX
+    LdDtVx(u8),
+    /// FX18: sound timer = VX
+    LdStVx(u8),
+    /// FX1E: I += VX
+    AddI(u8),
+    /// FX29: point I at the small font sprite for the digit in VX
+    LdF(u8),
+    /// FX30: point I at the big font sprite for the digit in VX (SUPER-CHIP)
+    LdHf(u8),
+    /// FX33: store the decimal digits of VX at I, I + 1 and I + 2
+    Bcd(u8),
+    /// FX3A: audio pattern pitch = VX (XO-CHIP)
+    Pitch(u8),
+    /// FX55: save V0 to VX to memory at I
+    Save(u8),
+    /// FX65: load V0 to VX from memory at I
+    Load(u8),
+    /// FX75: save V0 to VX to the RPL user flags (SUPER-CHIP)
+    SaveFlags(u8),
+    /// FX85: load V0 to VX from the RPL user flags (SUPER-CHIP)
+    LoadFlags(u8),
+}
+
+impl Opcode {
+
+    /// Decode an opcode. The interpreter ignores the final nibble of 9XYN and the X nibble of
+    /// FX00 and FX02, so those decode as though the nibble were zero and encode to the canonical
+    /// form. Every other opcode that decodes encodes back to itself.
+    pub fn decode(opcode: u16) -> Result<Self, DecodeError> {
+        let x = ((opcode & REGISTER_MASK) >> 8) as u8;
+        let y = ((opcode & REGISTER_TWO_MASK) >> 4) as u8;
+        let n = (opcode & NIBBLE_DATA_MASK) as u8;
+        let nn = (opcode & DATA_MASK) as u8;
+        let nnn = opcode & ADDRESS_MASK;
+
+        let decoded = match opcode >> 12 {
+            0x0 => match opcode {
+                0x00E0 => Opcode::Cls,
+                0x00EE => Opcode::Ret,
+                0x00C0..=0x00CF => Opcode::ScrollDown(n),
+                0x00D0..=0x00DF => Opcode::ScrollUp(n),
+                0x00FB => Opcode::ScrollRight,
+                0x00FC => Opcode::ScrollLeft,
+                0x00FD => Opcode::Exit,
+                0x00FE => Opcode::Lores,
+                0x00FF => Opcode::Hires,
+                _ => Opcode::Sys(nnn),
+            },
+            0x1 => Opcode::Jp(nnn),
+            0x2 => Opcode::Call(nnn),
+            0x3 => Opcode::SeImm(x, nn),
+            0x4 => Opcode::SneImm(x, nn),
+            0x5 => match n {
+                0x0 => Opcode::SeReg(x, y),
+                0x2 => Opcode::SaveRange(x, y),
+                0x3 => Opcode::LoadRange(x, y),
+                _ => return Err(DecodeError { opcode }),
+            },
+            0x6 => Opcode::LdImm(x, nn),
+            0x7 => Opcode::AddImm(x, nn),
+            0x8 => match n {
+                0x0 => Opcode::LdReg(x, y),
+                0x1 => Opcode::Or(x, y),
+                0x2 => Opcode::And(x, y),
+                0x3 => Opcode::Xor(x, y),
+                0x4 => Opcode::AddReg(x, y),
+                0x5 => Opcode::Sub(x, y),
+                0x6 => Opcode::Shr(x, y),
+                0x7 => Opcode::Subn(x, y),
+                0xE => Opcode::Shl(x, y),
+                _ => return Err(DecodeError { opcode }),
+            },
+            0x9 => Opcode::SneReg(x, y),
+            0xA => Opcode::LdI(nnn),
+            0xB => Opcode::JpV0(nnn),
+            0xC => Opcode::Rnd(x, nn),
+            0xD => Opcode::Drw(x, y, n),
+            0xE => match nn {
+                0x9E => Opcode::Skp(x),
+                0xA1 => Opcode::Sknp(x),
+                _ => return Err(DecodeError { opcode }),
+            },
+            _ => match nn {
+                0x00 => Opcode::LdILong,
+                0x01 => Opcode::Plane(x),
+                0x02 => Opcode::Audio,
+                0x07 => Opcode::LdVxDt(x),
+                0x0A => Opcode::LdVxK(x),
+                0x15 => Opcode::LdDtVx(x),
+                0x18 => Opcode::LdStVx(x),
+                0x1E => Opcode::AddI(x),
+                0x29 => Opcode::LdF(x),
+                0x30 => Opcode::LdHf(x),
+                0x33 => Opcode::Bcd(x),
+                0x3A => Opcode::Pitch(x),
+                0x55 => Opcode::Save(x),
+                0x65 => Opcode::Load(x),
+                0x75 => Opcode::SaveFlags(x),
+                0x85 => Opcode::LoadFlags(x),
+                _ => return Err(DecodeError { opcode }),
+            },
+        };
+
+        Ok(decoded)
+    }
+
+    /// Encode the instruction as an opcode. The address of the long load is not part of the
+    /// opcode, so it is left to the caller to write the following word.
+    pub fn encode(self) -> u16 {
+        let x = |x: u8| (x as u16 & 0xF) << 8;
+        let xy = |x_reg: u8, y: u8| x(x_reg) | (y as u16 & 0xF) << 4;
+        let addr = |addr: u16| addr & ADDRESS_MASK;
+
+        match self {
+            Opcode::Cls => 0x00E0,
+            Opcode::Ret => 0x00EE,
+            Opcode::Sys(nnn) => addr(nnn),
+            Opcode::ScrollDown(n) => 0x00C0 | (n as u16 & 0xF),
+            Opcode::ScrollUp(n) => 0x00D0 | (n as u16 & 0xF),
+            Opcode::ScrollRight => 0x00FB,
+            Opcode::ScrollLeft => 0x00FC,
+            Opcode::Exit => 0x00FD,
+            Opcode::Lores => 0x00FE,
+            Opcode::Hires => 0x00FF,
+            Opcode::Jp(nnn) => 0x1000 | addr(nnn),
+            Opcode::Call(nnn) => 0x2000 | addr(nnn),
+            Opcode::SeImm(vx, nn) => 0x3000 | x(vx) | nn as u16,
+            Opcode::SneImm(vx, nn) => 0x4000 | x(vx) | nn as u16,
+            Opcode::SeReg(vx, vy) => 0x5000 | xy(vx, vy),
+            Opcode::SaveRange(vx, vy) => 0x5002 | xy(vx, vy),
+            Opcode::LoadRange(vx, vy) => 0x5003 | xy(vx, vy),
+            Opcode::LdImm(vx, nn) => 0x6000 | x(vx) | nn as u16,
+            Opcode::AddImm(vx, nn) => 0x7000 | x(vx) | nn as u16,
+            Opcode::LdReg(vx, vy) => 0x8000 | xy(vx, vy),
+            Opcode::Or(vx, vy) => 0x8001 | xy(vx, vy),
+            Opcode::And(vx, vy) => 0x8002 | xy(vx, vy),
+            Opcode::Xor(vx, vy) => 0x8003 | xy(vx, vy),
+            Opcode::AddReg(vx, vy) => 0x8004 | xy(vx, vy),
+            Opcode::Sub(vx, vy) => 0x8005 | xy(vx, vy),
+            Opcode::Shr(vx, vy) => 0x8006 | xy(vx, vy),
+            Opcode::Subn(vx, vy) => 0x8007 | xy(vx, vy),
+            Opcode::Shl(vx, vy) => 0x800E | xy(vx, vy),
+            Opcode::SneReg(vx, vy) => 0x9000 | xy(vx, vy),
+            Opcode::LdI(nnn) => 0xA000 | addr(nnn),
+            Opcode::JpV0(nnn) => 0xB000 | addr(nnn),
+            Opcode::Rnd(vx, nn) => 0xC000 | x(vx) | nn as u16,
+            Opcode::Drw(vx, vy, n) => 0xD000 | xy(vx, vy) | (n as u16 & 0xF),
+            Opcode::Skp(vx) => 0xE09E | x(vx),
+            Opcode::Sknp(vx) => 0xE0A1 | x(vx),
+            Opcode::LdILong => 0xF000,
+            Opcode::Plane(n) => 0xF001 | x(n),
+            Opcode::Audio => 0xF002,
+            Opcode::LdVxDt(vx) => 0xF007 | x(vx),
+            Opcode::LdVxK(vx) => 0xF00A | x(vx),
+            Opcode::LdDtVx(vx) => 0xF015 | x(vx),
+            Opcode::LdStVx(vx) => 0xF018 | x(vx),
+            Opcode::AddI(vx) => 0xF01E | x(vx),
+            Opcode::LdF(vx) => 0xF029 | x(vx),
+            Opcode::LdHf(vx) => 0xF030 | x(vx),
+            Opcode::Bcd(vx) => 0xF033 | x(vx),
+            Opcode::Pitch(vx) => 0xF03A | x(vx),
+            Opcode::Save(vx) => 0xF055 | x(vx),
+            Opcode::Load(vx) => 0xF065 | x(vx),
+            Opcode::SaveFlags(vx) => 0xF075 | x(vx),
+            Opcode::LoadFlags(vx) => 0xF085 | x(vx),
+        }
+    }
+
+    /// True if the instruction may skip the one after it
+    pub fn is_skip(self) -> bool {
+        matches!(
+            self,
+            Opcode::SeImm(..) | Opcode::SneImm(..) | Opcode::SeReg(..) | Opcode::SneReg(..) | Opcode::Skp(_) | Opcode::Sknp(_)
+        )
+    }
+}
+
+/// Write the instruction in Octo assembly syntax. Skips are written as the `if ... then` that
+/// assembles to them, and the address of the long load is left to the caller to append.
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Opcode::Cls => write!(f, "clear"),
+            Opcode::Ret => write!(f, "return"),
+            Opcode::Sys(nnn) => write!(f, "native 0x{:03x}", nnn),
+            Opcode::ScrollDown(n) => write!(f, "scroll-down 0x{:x}", n),
+            Opcode::ScrollUp(n) => write!(f, "scroll-up 0x{:x}", n),
+            Opcode::ScrollRight => write!(f, "scroll-right"),
+            Opcode::ScrollLeft => write!(f, "scroll-left"),
+            Opcode::Exit => write!(f, "exit"),
+            Opcode::Lores => write!(f, "lores"),
+            Opcode::Hires => write!(f, "hires"),
+            Opcode::Jp(nnn) => write!(f, "jump 0x{:03x}", nnn),
+            Opcode::Call(nnn) => write!(f, ":call 0x{:03x}", nnn),
+            Opcode::SeImm(x, nn) => write!(f, "if v{:x} != 0x{:02x} then", x, nn),
+            Opcode::SneImm(x, nn) => write!(f, "if v{:x} == 0x{:02x} then", x, nn),
+            Opcode::SeReg(x, y) => write!(f, "if v{:x} != v{:x} then", x, y),
+            Opcode::SaveRange(x, y) => write!(f, "save v{:x} - v{:x}", x, y),
+            Opcode::LoadRange(x, y) => write!(f, "load v{:x} - v{:x}", x, y),
+            Opcode::LdImm(x, nn) => write!(f, "v{:x} := 0x{:02x}", x, nn),
+            Opcode::AddImm(x, nn) => write!(f, "v{:x} += 0x{:02x}", x, nn),
+            Opcode::LdReg(x, y) => write!(f, "v{:x} := v{:x}", x, y),
+            Opcode::Or(x, y) => write!(f, "v{:x} |= v{:x}", x, y),
+            Opcode::And(x, y) => write!(f, "v{:x} &= v{:x}", x, y),
+            Opcode::Xor(x, y) => write!(f, "v{:x} ^= v{:x}", x, y),
+            Opcode::AddReg(x, y) => write!(f, "v{:x} += v{:x}", x, y),
+            Opcode::Sub(x, y) => write!(f, "v{:x} -= v{:x}", x, y),
+            Opcode::Shr(x, y) => write!(f, "v{:x} >>= v{:x}", x, y),
+            Opcode::Subn(x, y) => write!(f, "v{:x} =- v{:x}", x, y),
+            Opcode::Shl(x, y) => write!(f, "v{:x} <<= v{:x}", x, y),
+            Opcode::SneReg(x, y) => write!(f, "if v{:x} == v{:x} then", x, y),
+            Opcode::LdI(nnn) => write!(f, "i := 0x{:03x}", nnn),
+            Opcode::JpV0(nnn) => write!(f, "jump0 0x{:03x}", nnn),
+            Opcode::Rnd(x, nn) => write!(f, "v{:x} := random 0x{:02x}", x, nn),
+            Opcode::Drw(x, y, n) => write!(f, "sprite v{:x} v{:x} 0x{:x}", x, y, n),
+            Opcode::Skp(x) => write!(f, "if v{:x} -key then", x),
+            Opcode::Sknp(x) => write!(f, "if v{:x} key then", x),
+            Opcode::LdILong => write!(f, "i := long"),
+            Opcode::Plane(n) => write!(f, "plane 0x{:x}", n),
+            Opcode::Audio => write!(f, "audio"),
+            Opcode::LdVxDt(x) => write!(f, "v{:x} := delay", x),
+            Opcode::LdVxK(x) => write!(f, "v{:x} := key", x),
+            Opcode::LdDtVx(x) => write!(f, "delay := v{:x}", x),
+            Opcode::LdStVx(x) => write!(f, "buzzer := v{:x}", x),
+            Opcode::AddI(x) => write!(f, "i += v{:x}", x),
+            Opcode::LdF(x) => write!(f, "i := hex v{:x}", x),
+            Opcode::LdHf(x) => write!(f, "i := bighex v{:x}", x),
+            Opcode::Bcd(x) => write!(f, "bcd v{:x}", x),
+            Opcode::Pitch(x) => write!(f, "pitch := v{:x}", x),
+            Opcode::Save(x) => write!(f, "save v{:x}", x),
+            Opcode::Load(x) => write!(f, "load v{:x}", x),
+            Opcode::SaveFlags(x) => write!(f, "saveflags v{:x}", x),
+            Opcode::LoadFlags(x) => write!(f, "loadflags v{:x}", x),
+        }
+    }
+}
+
+/// Describe an opcode in Octo assembly syntax. Assembling the result gives back the same opcode.
+/// Opcodes that are not valid instructions, or that have no assembly form because the
+/// instruction ignores part of the opcode, are written as raw bytes. The address of the XO-CHIP
+/// long load (F000 NNNN) follows the opcode, so it is left to the caller to append.
+pub fn disassemble(opcode: u16) -> String {
+    match Opcode::decode(opcode) {
+        Ok(decoded) if decoded.encode() == opcode => decoded.to_string(),
+        _ => format!("0x{:02x} 0x{:02x}", opcode >> 8, opcode & 0xFF),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        assert_eq!(Opcode::decode(0x00E0), Ok(Opcode::Cls));
+        assert_eq!(Opcode::decode(0x0123), Ok(Opcode::Sys(0x123)));
+        assert_eq!(Opcode::decode(0x3A2B), Ok(Opcode::SeImm(0xA, 0x2B)));
+        assert_eq!(Opcode::decode(0x5122), Ok(Opcode::SaveRange(1, 2)));
+        assert_eq!(Opcode::decode(0x8ABE), Ok(Opcode::Shl(0xA, 0xB)));
+        assert_eq!(Opcode::decode(0xD12F), Ok(Opcode::Drw(1, 2, 0xF)));
+        assert_eq!(Opcode::decode(0xF301), Ok(Opcode::Plane(3)));
+        assert_eq!(Opcode::decode(0xF765), Ok(Opcode::Load(7)));
+    }
+
+    #[test]
+    fn decode_errors() {
+        for opcode in [0x5121_u16, 0x801F, 0xE19F, 0xF0FF] {
+            assert_eq!(Opcode::decode(opcode), Err(DecodeError { opcode }));
+        }
+    }
+
+    #[test]
+    fn ignored_nibbles_encode_canonically() {
+        assert_eq!(Opcode::decode(0x9121).map(Opcode::encode), Ok(0x9120));
+        assert_eq!(Opcode::decode(0xF100).map(Opcode::encode), Ok(0xF000));
+        assert_eq!(Opcode::decode(0xF202).map(Opcode::encode), Ok(0xF002));
+    }
+
+    #[test]
+    fn every_opcode_encodes_back() {
+        for opcode in 0..=0xFFFF_u16 {
+            if let Ok(decoded) = Opcode::decode(opcode) {
+                let canonical = decoded.encode();
+                assert_eq!(Opcode::decode(canonical), Ok(decoded), "{:04x}", opcode);
+                if !matches!(decoded, Opcode::SneReg(..) | Opcode::LdILong | Opcode::Audio) {
+                    assert_eq!(canonical, opcode, "{:04x} decoded to {:?}", opcode, decoded);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encode_keeps_field_bits() {
+        assert_eq!(Opcode::Jp(0xF234).encode(), 0x1234);
+        assert_eq!(Opcode::LdReg(0x12, 0x34).encode(), 0x8240);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Opcode::SneImm(2, 5).to_string(), "if v2 == 0x05 then");
+        assert_eq!(Opcode::Call(0x208).to_string(), ":call 0x208");
+        assert_eq!(Opcode::Subn(0xA, 0xB).to_string(), "va =- vb");
+        assert_eq!(disassemble(0x9121), "0x91 0x21");
+        assert_eq!(disassemble(0x801F), "0x80 0x1f");
+        assert_eq!(disassemble(0xF000), "i := long");
+    }
+}
@@ -0,0 +1,108 @@
+use crate::memory::{Memory, SCREEN_SIZE, SCREEN_WIDTH};
+
+/// The brightness of a pixel that is lit in the current frame
+pub const FULL_BRIGHTNESS: u8 = 255;
+
+/// A display filter that imitates the persistence of a CRT phosphor. CHIP-8 programs move
+/// sprites by XORing them off and back on, so a sprite is often missing from the frame buffer at
+/// the moment it is drawn. Rather than switching off immediately, a pixel fades out over a number
+/// of frames, which blends consecutive frames together and hides the flicker.
+pub struct Phosphor {
+    decay_step: u8,
+    hires: bool,
+    width: usize,
+    /// The brightness of each pixel, indexed like the frame buffer
+    brightness: [u8; SCREEN_SIZE],
+    /// The plane value each pixel was last lit with, so a fading pixel keeps its color
+    values: [u8; SCREEN_SIZE],
+}
+
+impl Phosphor {
+
+    /// A filter where unlit pixels fade to black over decay_frames frames. A decay of zero or
+    /// one frame turns pixels off immediately, like the unfiltered display.
+    pub fn new(decay_frames: u8) -> Self {
+        Self {
+            decay_step: FULL_BRIGHTNESS / decay_frames.max(1),
+            hires: false,
+            width: SCREEN_WIDTH,
+            brightness: [0; SCREEN_SIZE],
+            values: [0; SCREEN_SIZE],
+        }
+    }
+
+    /// Blend in the next frame. Call once for every frame drawn.
+    pub fn update(&mut self, memory: &Memory) {
+        // Switching resolution clears the display, so there is nothing to fade
+        if memory.hires != self.hires {
+            self.hires = memory.hires;
+            self.brightness = [0; SCREEN_SIZE];
+        }
+        self.width = memory.width();
+
+        for ((brightness, value), pixel) in self.brightness.iter_mut().zip(self.values.iter_mut()).zip(memory.frame_buffer.iter()) {
+            if *pixel != 0 {
+                *brightness = FULL_BRIGHTNESS;
+                *value = *pixel;
+            } else {
+                *brightness = brightness.saturating_sub(self.decay_step);
+            }
+        }
+    }
+
+    /// The plane value and brightness of the pixel at (x, y) in the resolution of the last frame.
+    /// Pixels that have faded out completely are (0, 0).
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8) {
+        let idx = y * self.width + x;
+        match self.brightness[idx] {
+            0 => (0, 0),
+            brightness => (self.values[idx], brightness),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixels_fade() {
+        let mut memory = Memory::new();
+        let mut phosphor = Phosphor::new(3);
+
+        memory.frame_buffer[1] = 2;
+        phosphor.update(&memory);
+        assert_eq!(phosphor.pixel(1, 0), (2, FULL_BRIGHTNESS));
+
+        memory.frame_buffer[1] = 0;
+        phosphor.update(&memory);
+        assert_eq!(phosphor.pixel(1, 0), (2, 170));
+        phosphor.update(&memory);
+        phosphor.update(&memory);
+        assert_eq!(phosphor.pixel(1, 0), (0, 0));
+    }
+
+    #[test]
+    fn no_decay_matches_frame_buffer() {
+        let mut memory = Memory::new();
+        let mut phosphor = Phosphor::new(0);
+
+        memory.frame_buffer[0] = 1;
+        phosphor.update(&memory);
+        memory.frame_buffer[0] = 0;
+        phosphor.update(&memory);
+        assert_eq!(phosphor.pixel(0, 0), (0, 0));
+    }
+
+    #[test]
+    fn resolution_switch_clears() {
+        let mut memory = Memory::new();
+        let mut phosphor = Phosphor::new(10);
+
+        memory.frame_buffer[0] = 1;
+        phosphor.update(&memory);
+        memory.set_hires(true);
+        phosphor.update(&memory);
+        assert_eq!(phosphor.pixel(0, 0), (0, 0));
+    }
+}
@@ -0,0 +1,95 @@
+/// CHIP-8 was never formally specified and the interpreters that followed the original COSMAC
+/// VIP implementation disagree on the behaviour of a handful of opcodes. ROMs are usually written
+/// against one specific interpreter, so the quirks are configurable rather than hardcoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// If true 8XY6 and 8XYE shift VY and store the result in VX (the original COSMAC VIP
+    /// behaviour). Otherwise VX is shifted in place and VY is ignored.
+    pub shift_uses_vy: bool,
+
+    /// If true FX55 and FX65 leave I pointing at the byte after the last register saved or
+    /// loaded. Otherwise I is left unchanged.
+    pub load_store_increments_i: bool,
+
+    /// If true BNNN is treated as BXNN, jumping to XNN + VX rather than NNN + V0
+    pub jump_uses_vx: bool,
+
+    /// If true 8XY1, 8XY2 and 8XY3 reset VF to zero after the operation
+    pub logic_resets_vf: bool,
+
+    /// If true sprites drawn over the edge of the screen are clipped. Otherwise they wrap around
+    /// to the opposite edge.
+    pub clip_sprites: bool,
+}
+
+impl Quirks {
+    /// The behaviour of the original CHIP-8 interpreter on the COSMAC VIP
+    pub fn cosmac_vip() -> Self {
+        Self {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            clip_sprites: true,
+        }
+    }
+
+    /// The behaviour of CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Self {
+        Self {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+        }
+    }
+
+    /// The behaviour of SUPER-CHIP 1.1, which is the same as CHIP-48 for every quirk modelled
+    /// here. The one difference is that CHIP-48 FX55 and FX65 advance I by X rather than X + 1,
+    /// which load_store_increments_i cannot express, so both leave I unchanged.
+    pub fn super_chip() -> Self {
+        Self::chip48()
+    }
+
+    /// The behaviour of XO-CHIP as implemented by Octo
+    pub fn xo_chip() -> Self {
+        Self {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+        }
+    }
+
+    /// The behaviour most modern interpreters settled on, and the one this emulator has always
+    /// used. Works with the majority of ROMs in circulation.
+    pub fn modern() -> Self {
+        Self {
+            shift_uses_vy: false,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+        }
+    }
+
+    /// Look up a preset by name, returning None if the name is not recognised
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "vip" | "cosmac-vip" => Some(Self::cosmac_vip()),
+            "chip48" | "chip-48" => Some(Self::chip48()),
+            "schip" | "super-chip" => Some(Self::super_chip()),
+            "xochip" | "xo-chip" => Some(Self::xo_chip()),
+            "modern" => Some(Self::modern()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self::modern()
+    }
+}
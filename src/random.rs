@@ -0,0 +1,120 @@
+use crate::error::{ExecError, StateError};
+use crate::memory::Memory;
+use crate::state::{StateReader, StateWriter};
+use std::num::Wrapping;
+
+/// The page of memory the approximate COSMAC VIP random routine reads from. On the VIP the
+/// routine read the interpreter's own code, which is not in memory here, so this page holds
+/// whatever the emulator keeps there (the fonts and zeros) instead.
+pub const VIP_RANDOM_PAGE: usize = 0x100;
+
+/// The routine CXNN uses to produce random bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RandomMode {
+    /// A SplitMix64 generator, which gives good random bytes from any 64-bit seed
+    SplitMix,
+    /// An approximation of the COSMAC VIP routine: an 8-bit pointer walks the bytes of
+    /// VIP_RANDOM_PAGE and each byte read is added to the previous result along with the pointer.
+    /// Like the original the sequence depends on the contents of memory and repeats quickly, but
+    /// the bytes read differ so the numbers do not match a real VIP.
+    Vip,
+}
+
+/// A seedable random number generator whose whole state fits in a save state, so a run can be
+/// reproduced exactly from its seed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Random {
+    pub mode: RandomMode,
+    state: u64,
+}
+
+impl Random {
+
+    /// A SplitMix64 generator starting from the given seed
+    pub fn new(seed: u64) -> Self {
+        Self { mode: RandomMode::SplitMix, state: seed }
+    }
+
+    /// An approximate COSMAC VIP generator. Only the low 16 bits of the seed (the pointer and the previous
+    /// result) are used.
+    pub fn vip(seed: u64) -> Self {
+        Self { mode: RandomMode::Vip, state: seed & 0xFFFF }
+    }
+
+    /// A SplitMix64 generator seeded from the operating system, for when runs do not need to be
+    /// reproducible
+    pub fn from_entropy() -> Self {
+        Self::new(rand::random())
+    }
+
+    /// The current state of the generator. For a freshly created generator this is its seed.
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    /// Produce the next random byte. Only the VIP routine reads memory.
+    pub fn next_byte(&mut self, memory: &Memory) -> Result<u8, ExecError> {
+        match self.mode {
+            RandomMode::SplitMix => {
+                self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                let mut z = self.state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                Ok((z ^ (z >> 31)) as u8)
+            }
+            RandomMode::Vip => {
+                let pointer = Wrapping(self.state as u8) + Wrapping(1);
+                let previous = Wrapping((self.state >> 8) as u8);
+                let byte = memory.get(VIP_RANDOM_PAGE + pointer.0 as usize)?;
+                let value = previous + byte + pointer;
+                self.state = (value.0 as u64) << 8 | pointer.0 as u64;
+                Ok(value.0)
+            }
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(match self.mode {
+            RandomMode::SplitMix => 0,
+            RandomMode::Vip => 1,
+        });
+        writer.u64(self.state);
+    }
+
+    pub fn load_state(reader: &mut StateReader) -> Result<Self, StateError> {
+        let mode = match reader.u8()? {
+            0 => RandomMode::SplitMix,
+            1 => RandomMode::Vip,
+            _ => return Err(StateError::InvalidValue { field: "random mode" }),
+        };
+        Ok(Self { mode, state: reader.u64()? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_runs_repeat() {
+        let memory = Memory::new();
+        let mut first = Random::new(42);
+        let mut second = Random::new(42);
+        let bytes: Vec<u8> = (0..32).map(|_| first.next_byte(&memory).unwrap()).collect();
+
+        assert_eq!(bytes, (0..32).map(|_| second.next_byte(&memory).unwrap()).collect::<Vec<_>>());
+        assert_ne!(bytes, (0..32).map(|_| Random::new(43).next_byte(&memory).unwrap()).collect::<Vec<_>>());
+        assert!(bytes.iter().any(|byte| *byte != bytes[0]));
+    }
+
+    #[test]
+    fn vip_reads_memory() {
+        let mut memory = Memory::new();
+        memory.set(VIP_RANDOM_PAGE + 1, Wrapping(0x10)).unwrap();
+        memory.set(VIP_RANDOM_PAGE + 2, Wrapping(0x20)).unwrap();
+
+        let mut random = Random::vip(0x0500);
+        assert_eq!(random.next_byte(&memory), Ok(0x16));
+        assert_eq!(random.next_byte(&memory), Ok(0x38));
+    }
+}
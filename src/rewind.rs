@@ -0,0 +1,245 @@
+use crate::error::StateError;
+use crate::machine::Machine;
+use std::collections::VecDeque;
+
+/// Keeps a bounded history of machine snapshots so play can be stepped backwards.
+///
+/// Only the newest snapshot is kept whole. Every older snapshot is stored as the difference from
+/// the one after it: the two save states are XORed together and the long runs of zero bytes this
+/// leaves are run length encoded, so a snapshot costs roughly the number of bytes that changed.
+/// When the history grows past its byte budget the oldest snapshots are dropped. The budget counts
+/// the newest snapshot as well as the differences, but the newest is always kept, so a budget
+/// smaller than one save state keeps just that.
+pub struct Rewind {
+    interval: u64,
+    max_bytes: usize,
+    frames_since_snapshot: u64,
+    newest: Option<Vec<u8>>,
+    /// Differences from each snapshot to the one before it, oldest first
+    deltas: VecDeque<Vec<u8>>,
+    delta_bytes: usize,
+}
+
+impl Rewind {
+
+    /// Take a snapshot every interval frames, keeping at most max_bytes of history (or the newest
+    /// snapshot alone if that is larger)
+    pub fn new(interval: u64, max_bytes: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            max_bytes,
+            frames_since_snapshot: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+        }
+    }
+
+    /// Call once per frame. A snapshot of the machine is taken every interval frames.
+    pub fn frame(&mut self, machine: &Machine) {
+        self.frames_since_snapshot += 1;
+        if self.frames_since_snapshot < self.interval {
+            return;
+        }
+
+        self.frames_since_snapshot = 0;
+        let state = machine.save_state();
+
+        if let Some(previous) = self.newest.take() {
+            let delta = encode_delta(&state, &previous);
+            self.delta_bytes += delta.len();
+            self.deltas.push_back(delta);
+        }
+
+        self.newest = Some(state);
+
+        while self.memory_used() > self.max_bytes {
+            match self.deltas.pop_front() {
+                Some(delta) => self.delta_bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Restore the machine to the most recent snapshot and drop it from the history, so calling
+    /// this every frame steps steadily backwards. Returns false once the history is empty. If the
+    /// snapshot cannot be loaded the machine and the history are left alone.
+    pub fn rewind(&mut self, machine: &mut Machine) -> Result<bool, StateError> {
+        let newest = match self.newest.take() {
+            Some(newest) => newest,
+            None => return Ok(false),
+        };
+
+        if let Err(err) = machine.load_state(&newest) {
+            self.newest = Some(newest);
+            return Err(err);
+        }
+
+        if let Some(delta) = self.deltas.pop_back() {
+            self.delta_bytes -= delta.len();
+            self.newest = Some(apply_delta(&newest, &delta));
+        }
+
+        self.frames_since_snapshot = 0;
+        Ok(true)
+    }
+
+    /// The number of snapshots held
+    pub fn len(&self) -> usize {
+        self.newest.as_ref().map_or(0, |_| 1 + self.deltas.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// The number of bytes used to hold the history
+    pub fn memory_used(&self) -> usize {
+        self.newest.as_ref().map_or(0, Vec::len) + self.delta_bytes
+    }
+}
+
+/// Encode the XOR of two equal length states as pairs of a run of unchanged bytes followed by a
+/// run of changed bytes, each preceded by its length as a LEB128 number
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut xor = from.iter().zip(to).map(|(a, b)| a ^ b).peekable();
+
+    while xor.peek().is_some() {
+        let mut unchanged = 0;
+        while xor.next_if_eq(&0).is_some() {
+            unchanged += 1;
+        }
+
+        let mut changed = Vec::new();
+        while let Some(byte) = xor.next_if(|byte| *byte != 0) {
+            changed.push(byte);
+        }
+
+        write_length(&mut delta, unchanged);
+        write_length(&mut delta, changed.len());
+        delta.extend_from_slice(&changed);
+    }
+
+    delta
+}
+
+/// Apply a delta written by encode_delta(from, to) to from, giving to
+fn apply_delta(from: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut to = from.to_vec();
+    let mut pos = 0;
+    let mut delta = delta.iter().copied();
+
+    while let Some(unchanged) = read_length(&mut delta) {
+        pos += unchanged;
+        let changed = read_length(&mut delta).unwrap_or(0);
+        for (dst, byte) in to[pos..pos + changed].iter_mut().zip(&mut delta) {
+            *dst ^= byte;
+        }
+        pos += changed;
+    }
+
+    to
+}
+
+fn write_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 0x80 {
+        out.push((len as u8 & 0x7F) | 0x80);
+        len >>= 7;
+    }
+    out.push(len as u8);
+}
+
+fn read_length(bytes: &mut impl Iterator<Item = u8>) -> Option<usize> {
+    let mut len = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = bytes.next()?;
+        len |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(len);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_round_trips() {
+        let from: Vec<u8> = (0..1000).map(|i| (i % 7) as u8).collect();
+        let mut to = from.clone();
+        to[0] = 0xFF;
+        to[500] ^= 0x10;
+        to[501] ^= 0x20;
+        to[999] = 0;
+
+        let delta = encode_delta(&from, &to);
+        assert!(delta.len() < 20, "{:?}", delta);
+        assert_eq!(apply_delta(&from, &delta), to);
+        assert_eq!(apply_delta(&to, &encode_delta(&to, &from)), from);
+    }
+
+    #[test]
+    fn rewinds_to_earlier_frames() {
+        // v0 += 1, jump 0x200
+        let mut machine = Machine::of_bytes(vec![0x70, 0x01, 0x12, 0x00]);
+        let mut rewind = Rewind::new(2, usize::MAX);
+        let mut history = Vec::new();
+
+        for frame in 1..=10 {
+            machine.step().unwrap();
+            machine.step().unwrap();
+            rewind.frame(&machine);
+            if frame % 2 == 0 {
+                history.push(machine.cpu.registers.v[0].0);
+            }
+        }
+
+        assert_eq!(rewind.len(), 5);
+        while let Some(expected) = history.pop() {
+            assert_eq!(rewind.rewind(&mut machine), Ok(true));
+            assert_eq!(machine.cpu.registers.v[0].0, expected);
+        }
+        assert_eq!(rewind.rewind(&mut machine), Ok(false));
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn history_is_capped() {
+        let mut machine = Machine::of_bytes(vec![0x70, 0x01, 0x12, 0x00]);
+        let state_size = machine.save_state().len();
+        let mut rewind = Rewind::new(1, state_size + 64);
+
+        for _ in 0..100 {
+            machine.step().unwrap();
+            rewind.frame(&machine);
+        }
+
+        assert!(rewind.memory_used() <= state_size + 64);
+        assert!(rewind.len() > 1 && rewind.len() < 100);
+
+        // The newest snapshot is kept even when it alone is over the budget
+        let mut rewind = Rewind::new(1, 16);
+        for _ in 0..10 {
+            machine.step().unwrap();
+            rewind.frame(&machine);
+        }
+        assert_eq!(rewind.len(), 1);
+        assert_eq!(rewind.memory_used(), state_size);
+    }
+
+    #[test]
+    fn bad_snapshot_is_reported() {
+        let mut machine = Machine::of_bytes(vec![0x70, 0x01, 0x12, 0x00]);
+        let mut rewind = Rewind::new(1, usize::MAX);
+        rewind.frame(&machine);
+        rewind.newest.as_mut().unwrap().push(0);
+
+        assert_eq!(rewind.rewind(&mut machine), Err(StateError::ChecksumMismatch));
+        assert_eq!(rewind.len(), 1);
+    }
+}
@@ -0,0 +1,276 @@
+use crate::error::ImageError;
+use crate::memory::Memory;
+use crate::state::crc32;
+use std::fmt::Write;
+
+/// The characters a screenshot is written with in ASCII art, indexed by the plane value
+const ASCII_PIXELS: [char; 4] = ['.', '#', '+', '@'];
+
+/// The grey level a screenshot is written with in a PNG, indexed by the plane value
+const PNG_GREYS: [u8; 4] = [0x00, 0xFF, 0x80, 0xC0];
+
+/// The largest block zlib can store uncompressed
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+/// A copy of the display in its current resolution, which can be written as ASCII art, a PBM or
+/// a PNG, and compared against a golden image in any of those formats except PNG
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Screenshot {
+    pub width: usize,
+    pub height: usize,
+    /// The plane value of each pixel, row by row
+    pub pixels: Vec<u8>,
+    /// True if the image only records whether each pixel is lit, as a PBM does
+    pub monochrome: bool,
+}
+
+impl Screenshot {
+
+    /// Capture the display
+    pub fn of_memory(memory: &Memory) -> Self {
+        let (width, height) = (memory.width(), memory.height());
+        Self {
+            width,
+            height,
+            pixels: (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).map(|(x, y)| memory.pixel(x, y)).collect(),
+            monochrome: false,
+        }
+    }
+
+    /// Parse a golden image. Files starting with P1 or P4 are read as PBMs and anything else as
+    /// ASCII art, where `.` or a space is an unlit pixel.
+    pub fn parse(data: &[u8]) -> Result<Self, ImageError> {
+        match data {
+            [b'P', b'1', ..] | [b'P', b'4', ..] => Self::parse_pbm(data),
+            _ => Self::parse_ascii(std::str::from_utf8(data).map_err(|_| ImageError::Parse("not a PBM or ASCII art".to_string()))?),
+        }
+    }
+
+    fn parse_ascii(text: &str) -> Result<Self, ImageError> {
+        let rows: Vec<&str> = text.lines().filter(|line| !line.is_empty()).collect();
+        let width = rows.first().map_or(0, |row| row.chars().count());
+        let mut pixels = Vec::with_capacity(width * rows.len());
+
+        for (line, row) in rows.iter().enumerate() {
+            if row.chars().count() != width {
+                return Err(ImageError::Parse(format!("row {} is not {} pixels wide", line + 1, width)));
+            }
+
+            for pixel in row.chars() {
+                pixels.push(match pixel {
+                    '.' | ' ' => 0,
+                    '+' => 2,
+                    '@' => 3,
+                    _ => 1,
+                });
+            }
+        }
+
+        Ok(Self { width, height: rows.len(), pixels, monochrome: false })
+    }
+
+    fn parse_pbm(data: &[u8]) -> Result<Self, ImageError> {
+        let error = |message: &str| ImageError::Parse(message.to_string());
+
+        // The header is the magic, width and height separated by whitespace, with # comments
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        while fields.len() < 3 {
+            while data.get(pos).is_some_and(u8::is_ascii_whitespace) {
+                pos += 1;
+            }
+            if data.get(pos) == Some(&b'#') {
+                while data.get(pos).is_some_and(|byte| *byte != b'\n') {
+                    pos += 1;
+                }
+                continue;
+            }
+            let start = pos;
+            while data.get(pos).is_some_and(|byte| !byte.is_ascii_whitespace()) {
+                pos += 1;
+            }
+            if start == pos {
+                return Err(error("truncated PBM header"));
+            }
+            fields.push(std::str::from_utf8(&data[start..pos]).map_err(|_| error("bad PBM header"))?);
+        }
+
+        let width: usize = fields[1].parse().map_err(|_| error("bad PBM width"))?;
+        let height: usize = fields[2].parse().map_err(|_| error("bad PBM height"))?;
+        let body = &data[pos..];
+
+        let pixels = if fields[0] == "P4" {
+            // A single whitespace byte separates the header from the packed rows
+            let row_bytes = width.div_ceil(8);
+            let body = body.get(1..1 + row_bytes * height).ok_or_else(|| error("truncated PBM"))?;
+            (0..height).flat_map(|y| (0..width).map(move |x| (x, y)))
+                .map(|(x, y)| (body[y * row_bytes + x / 8] >> (7 - x % 8)) & 1)
+                .collect()
+        } else {
+            let pixels: Vec<u8> = body.iter().filter(|byte| matches!(byte, b'0' | b'1')).map(|byte| byte - b'0').collect();
+            if pixels.len() != width * height {
+                return Err(error("wrong number of PBM pixels"));
+            }
+            pixels
+        };
+
+        Ok(Self { width, height, pixels, monochrome: true })
+    }
+
+    /// One line of characters per row: `.` for unlit pixels, `#` for plane 1, `+` for plane 2 and
+    /// `@` for both
+    pub fn to_ascii(&self) -> String {
+        let mut ascii = String::with_capacity((self.width + 1) * self.height);
+        for row in self.pixels.chunks(self.width) {
+            row.iter().for_each(|pixel| ascii.push(ASCII_PIXELS[*pixel as usize & 0x3]));
+            ascii.push('\n');
+        }
+        ascii
+    }
+
+    /// A plain (P1) PBM, where any lit plane is black
+    pub fn to_pbm(&self) -> Vec<u8> {
+        let mut pbm = format!("P1\n{} {}\n", self.width, self.height);
+        for row in self.pixels.chunks(self.width) {
+            let row: Vec<&str> = row.iter().map(|pixel| if *pixel == 0 { "0" } else { "1" }).collect();
+            writeln!(pbm, "{}", row.join(" ")).unwrap();
+        }
+        pbm.into_bytes()
+    }
+
+    /// An 8-bit greyscale PNG, scaled up so each pixel is scale pixels square. The image data is
+    /// stored without compression.
+    pub fn to_png(&self, scale: usize) -> Vec<u8> {
+        let scale = scale.max(1);
+        let (width, height) = (self.width * scale, self.height * scale);
+
+        let mut raw = Vec::with_capacity((width + 1) * height);
+        for y in 0..height {
+            raw.push(0); // No filter
+            raw.extend((0..width).map(|x| PNG_GREYS[self.pixels[(y / scale) * self.width + x / scale] as usize & 0x3]));
+        }
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&(width as u32).to_be_bytes());
+        header.extend_from_slice(&(height as u32).to_be_bytes());
+        header.extend_from_slice(&[8, 0, 0, 0, 0]); // 8-bit greyscale, no interlacing
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut png, b"IHDR", &header);
+        png_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+        png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    /// Compare against a golden image, reporting the size or pixels that differ. Only whether a
+    /// pixel is lit is compared when the golden image is monochrome.
+    pub fn compare(&self, golden: &Screenshot) -> Result<(), ImageError> {
+        if (self.width, self.height) != (golden.width, golden.height) {
+            return Err(ImageError::SizeMismatch { expected: (golden.width, golden.height), actual: (self.width, self.height) });
+        }
+
+        let mut differences = self.pixels.iter().zip(&golden.pixels).enumerate()
+            .filter(|(_, (actual, expected))| if golden.monochrome { (**actual != 0) != (**expected != 0) } else { actual != expected });
+
+        match differences.next() {
+            None => Ok(()),
+            Some((first, _)) => Err(ImageError::PixelMismatch {
+                count: differences.count() + 1,
+                first: (first % self.width, first / self.width),
+            }),
+        }
+    }
+}
+
+/// Compare the display against a golden image in any format Screenshot::parse reads
+pub fn compare_golden(memory: &Memory, golden: &[u8]) -> Result<(), ImageError> {
+    Screenshot::of_memory(memory).compare(&Screenshot::parse(golden)?)
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wrap data in a zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+
+    while let Some(block) = blocks.next() {
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    zlib.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    zlib
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::Wrapping;
+
+    fn screen() -> Memory {
+        let mut memory = Memory::new();
+        memory.set(0x300, Wrapping(0xC0)).unwrap();
+        memory.draw_sprite(1, 0, 1, 0x300, false).unwrap();
+        memory
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        let screenshot = Screenshot::of_memory(&screen());
+        assert_eq!((screenshot.width, screenshot.height), (64, 32));
+        assert!(screenshot.to_ascii().starts_with(".##....."));
+        assert!(screenshot.to_pbm().starts_with(b"P1\n64 32\n0 1 1 0"));
+
+        assert_eq!(Screenshot::parse(screenshot.to_ascii().as_bytes()), Ok(screenshot.clone()));
+        assert_eq!(Screenshot::parse(&screenshot.to_pbm()), Ok(Screenshot { monochrome: true, ..screenshot.clone() }));
+
+        let mut p4 = b"P4\n# comment\n9 1\n".to_vec();
+        p4.extend_from_slice(&[0x60, 0x80]);
+        assert_eq!(Screenshot::parse(&p4).unwrap().pixels, vec![0, 1, 1, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn png() {
+        let png = Screenshot::of_memory(&screen()).to_png(2);
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x80\0\0\0\x40"));
+        assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+    }
+
+    #[test]
+    fn golden_comparison() {
+        let mut memory = screen();
+        let golden = Screenshot::of_memory(&memory).to_ascii();
+        assert_eq!(compare_golden(&memory, golden.as_bytes()), Ok(()));
+
+        let mut changed = golden.replacen(".##", "#.#", 1);
+        assert_eq!(compare_golden(&memory, changed.as_bytes()), Err(ImageError::PixelMismatch { count: 2, first: (0, 0) }));
+
+        changed = golden.lines().take(10).collect::<Vec<_>>().join("\n");
+        assert_eq!(compare_golden(&memory, changed.as_bytes()), Err(ImageError::SizeMismatch { expected: (64, 10), actual: (64, 32) }));
+
+        // A PBM cannot tell the planes apart
+        let pbm = Screenshot::of_memory(&memory).to_pbm();
+        memory.frame_buffer[1] = 3;
+        assert_eq!(compare_golden(&memory, &pbm), Ok(()));
+        assert_eq!(compare_golden(&memory, golden.as_bytes()), Err(ImageError::PixelMismatch { count: 1, first: (1, 0) }));
+    }
+}
@@ -0,0 +1,201 @@
+use crate::error::StateError;
+use std::convert::TryInto;
+
+/// Every save state starts with these bytes
+pub const STATE_MAGIC: [u8; 8] = *b"CHIP9SAV";
+
+/// The version of the save state format written by this build. Version 2 added the random number
+/// generator, so version 1 states are still read but leave the generator alone. Version 3 replaced
+/// the timer clock with the position within the frame and the frame length, so older states are
+/// read as if at the start of a frame.
+pub const STATE_VERSION: u16 = 3;
+
+/// The size of the header: the magic, the version and the CRC-32 of the payload
+const HEADER_SIZE: usize = STATE_MAGIC.len() + 2 + 4;
+
+/// The CRC-32 (IEEE) of some data
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+/// Builds the payload of a save state. All values are written big-endian.
+#[derive(Default)]
+pub struct StateWriter {
+    payload: Vec<u8>,
+}
+
+impl StateWriter {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.payload.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.payload.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.payload.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.payload.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.payload.extend_from_slice(bytes);
+    }
+
+    /// Prefix the payload with the header
+    pub fn finish(self) -> Vec<u8> {
+        self.finish_version(STATE_VERSION)
+    }
+
+    /// Prefix the payload with a header claiming an older version, so tests can build the states
+    /// older builds wrote
+    #[cfg(test)]
+    pub(crate) fn finish_as(self, version: u16) -> Vec<u8> {
+        self.finish_version(version)
+    }
+
+    fn finish_version(self, version: u16) -> Vec<u8> {
+        let mut state = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        state.extend_from_slice(&STATE_MAGIC);
+        state.extend_from_slice(&version.to_be_bytes());
+        state.extend_from_slice(&crc32(&self.payload).to_be_bytes());
+        state.extend_from_slice(&self.payload);
+        state
+    }
+}
+
+/// Reads back the payload of a save state written by StateWriter
+pub struct StateReader<'a> {
+    version: u16,
+    payload: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+
+    /// Check the header of a save state and return a reader for its payload
+    pub fn new(state: &'a [u8]) -> Result<Self, StateError> {
+        if state.len() < HEADER_SIZE {
+            return Err(if state.starts_with(&STATE_MAGIC) { StateError::Truncated } else { StateError::BadMagic });
+        }
+
+        let (magic, rest) = state.split_at(STATE_MAGIC.len());
+        if magic != STATE_MAGIC {
+            return Err(StateError::BadMagic);
+        }
+
+        let version = u16::from_be_bytes([rest[0], rest[1]]);
+        if version == 0 || version > STATE_VERSION {
+            return Err(StateError::UnsupportedVersion { version });
+        }
+
+        let checksum = u32::from_be_bytes([rest[2], rest[3], rest[4], rest[5]]);
+        let payload = &rest[6..];
+        if crc32(payload) != checksum {
+            return Err(StateError::ChecksumMismatch);
+        }
+
+        Ok(Self { version, payload })
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.payload.len() < len {
+            return Err(StateError::Truncated);
+        }
+
+        let (bytes, rest) = self.payload.split_at(len);
+        self.payload = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self, field: &'static str) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidValue { field }),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let bytes = self.bytes(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// The format version the state was written with
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Check the whole payload has been read
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.payload.is_empty() { Ok(()) } else { Err(StateError::TrailingData) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn header() {
+        let mut writer = StateWriter::new();
+        writer.u16(0x1234);
+        writer.bool(true);
+        let mut state = writer.finish();
+
+        let mut reader = StateReader::new(&state).unwrap();
+        assert_eq!(reader.u16(), Ok(0x1234));
+        assert_eq!(reader.bool("flag"), Ok(true));
+        assert_eq!(reader.u8(), Err(StateError::Truncated));
+
+        assert_eq!(StateReader::new(b"not a state").err(), Some(StateError::BadMagic));
+        assert_eq!(StateReader::new(&state[..10]).err(), Some(StateError::Truncated));
+
+        let last = state.len() - 1;
+        state[last] ^= 1;
+        assert_eq!(StateReader::new(&state).err(), Some(StateError::ChecksumMismatch));
+
+        state[9] = 99;
+        assert_eq!(StateReader::new(&state).err(), Some(StateError::UnsupportedVersion { version: 99 }));
+    }
+}
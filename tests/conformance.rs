@@ -0,0 +1,176 @@
+//! Runs the community test ROMs vendored in tests/roms headlessly under every quirks profile and
+//! compares the final display with the reference images in tests/expected.
+//!
+//! The reference images must come from the ROM authors' screenshots or from a reference
+//! emulator, never from this emulator, or the test would only check the code against itself. A
+//! missing ROM or reference image is a failure. Until they are vendored the test is ignored, so
+//! run it with `cargo test --test conformance -- --ignored`.
+//!
+//! The ROMs draw the name of each sub-test next to its result, so a failure prints the rows of
+//! the display that differ from the reference, which shows the sub-tests that failed.
+
+use chip9::screenshot::Screenshot;
+use chip9::{Backends, Headless, ImageError, KeyEvent, KeyScript, Machine, Quirks, Random, StepOutcome, Timing};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Every run uses the same seed so the expected images never depend on the random numbers drawn
+const SEED: u64 = 0xC8;
+
+/// How long a scripted key is held down for, in frames
+const KEY_HOLD_FRAMES: u64 = 5;
+
+/// A quirks profile the ROMs are run under
+struct Profile {
+    name: &'static str,
+    quirks: Quirks,
+    timing: Timing,
+    /// The keys that pick this platform from the menu of the quirks test, or None if the menu has
+    /// no entry for it. CHIP-48 and the modern profile are not offered.
+    quirks_menu: Option<&'static [u8]>,
+}
+
+/// A test ROM and how to run it
+struct TestRom {
+    file: &'static str,
+    /// How long to run for. The ROMs draw their results and then loop forever.
+    frames: u64,
+    /// The keys to press, one after another, for a profile, or None if the ROM cannot be run
+    /// under it
+    keys: fn(&Profile) -> Option<Vec<u8>>,
+}
+
+fn profiles() -> Vec<Profile> {
+    vec![
+        Profile { name: "vip", quirks: Quirks::cosmac_vip(), timing: Timing::CosmacVip, quirks_menu: Some(&[1]) },
+        Profile { name: "chip48", quirks: Quirks::chip48(), timing: Timing::Instructions, quirks_menu: None },
+        Profile { name: "schip", quirks: Quirks::super_chip(), timing: Timing::Instructions, quirks_menu: Some(&[2, 2]) },
+        Profile { name: "xochip", quirks: Quirks::xo_chip(), timing: Timing::Instructions, quirks_menu: Some(&[3]) },
+        Profile { name: "modern", quirks: Quirks::modern(), timing: Timing::Instructions, quirks_menu: None },
+    ]
+}
+
+fn test_roms() -> Vec<TestRom> {
+    vec![
+        TestRom { file: "3-corax+.ch8", frames: 120, keys: |_| Some(Vec::new()) },
+        TestRom { file: "4-flags.ch8", frames: 120, keys: |_| Some(Vec::new()) },
+        TestRom { file: "5-quirks.ch8", frames: 900, keys: |profile| profile.quirks_menu.map(<[u8]>::to_vec) },
+        TestRom {
+            file: "6-keypad.ch8",
+            frames: 240,
+            // Test EX9E, then hold key 5 and press every other key to check it is ignored
+            keys: |_| Some(vec![1, 5, 0, 1, 2, 3, 4, 6, 7, 8, 9, 0xA, 0xB, 0xC, 0xD, 0xE, 0xF]),
+        },
+        TestRom { file: "BC_test.ch8", frames: 120, keys: |_| Some(Vec::new()) },
+    ]
+}
+
+/// Press each key in turn, a second apart, starting a second in
+fn key_script(keys: &[u8]) -> KeyScript {
+    KeyScript::new(keys.iter().enumerate().flat_map(|(idx, key)| {
+        let frame = (idx as u64 + 1) * 60;
+        vec![
+            KeyEvent { frame, key: *key, pressed: true },
+            KeyEvent { frame: frame + KEY_HOLD_FRAMES, key: *key, pressed: false },
+        ]
+    }).collect())
+}
+
+fn fixture(dir: &str, file: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join(dir).join(file)
+}
+
+/// Run a ROM under a profile with the given keys and return the final display
+fn run(rom: &TestRom, data: &[u8], profile: &Profile, keys: &[u8]) -> Result<Screenshot, String> {
+    let mut machine = Machine::of_bytes_with_random(data.to_vec(), profile.quirks, Random::new(SEED));
+    machine.timing = profile.timing;
+    let mut backends = Backends { display: Headless, input: key_script(keys), audio: Headless };
+
+    for _ in 0..rom.frames {
+        match machine.update(&mut backends) {
+            Ok(StepOutcome::Halted) => break,
+            Ok(_) => {}
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+
+    Ok(Screenshot::of_memory(&machine.memory))
+}
+
+/// Show the rows of the display that differ from the reference and the rows either side, expected
+/// then actual, with the differing rows marked
+fn describe_mismatch(actual: &Screenshot, expected: &Screenshot) -> String {
+    let differs = |x: usize, y: usize| {
+        let (got, want) = (actual.pixels[y * actual.width + x], expected.pixels[y * expected.width + x]);
+        if expected.monochrome { (got != 0) != (want != 0) } else { got != want }
+    };
+    let differing: Vec<bool> = (0..actual.height).map(|y| (0..actual.width).any(|x| differs(x, y))).collect();
+    let row = |image: &Screenshot, y: usize| {
+        (0..image.width).map(|x| if image.pixels[y * image.width + x] != 0 { '#' } else { '.' }).collect::<String>()
+    };
+
+    let mut text = String::new();
+    for y in 0..actual.height {
+        let near = y.saturating_sub(1)..(y + 2).min(actual.height);
+        if differing[near].iter().any(|differs| *differs) {
+            let marker = if differing[y] { '>' } else { ' ' };
+            text += &format!("{} {:2} {}  {}\n", marker, y, row(expected, y), row(actual, y));
+        }
+    }
+    text
+}
+
+/// Run one ROM under every profile, returning a line for each profile that did not match
+fn check_rom(rom: &TestRom) -> Vec<String> {
+    let data = match fs::read(fixture("roms", rom.file)) {
+        Ok(data) => data,
+        Err(err) => return vec![format!("{}: not vendored in tests/roms: {}", rom.file, err)],
+    };
+
+    let stem = rom.file.trim_end_matches(".ch8");
+    let mut failures = Vec::new();
+
+    for profile in profiles() {
+        let keys = match (rom.keys)(&profile) {
+            Some(keys) => keys,
+            None => continue,
+        };
+
+        let context = format!("{} under {}", rom.file, profile.name);
+        let golden_path = fixture("expected", &format!("{}-{}.txt", stem, profile.name));
+
+        let expected = match fs::read(&golden_path).map_err(|err| err.to_string())
+            .and_then(|golden| Screenshot::parse(&golden).map_err(|err| err.to_string())) {
+            Ok(expected) => expected,
+            Err(err) => {
+                failures.push(format!("{}: no reference image {}: {}", context, golden_path.display(), err));
+                continue;
+            }
+        };
+
+        let actual = match run(rom, &data, &profile, &keys) {
+            Ok(actual) => actual,
+            Err(err) => {
+                failures.push(format!("{}: {}", context, err));
+                continue;
+            }
+        };
+
+        match actual.compare(&expected) {
+            Ok(()) => {}
+            Err(err @ ImageError::PixelMismatch { .. }) => {
+                failures.push(format!("{}: {}, expected on the left:\n{}", context, err, describe_mismatch(&actual, &expected)));
+            }
+            Err(err) => failures.push(format!("{}: {}", context, err)),
+        }
+    }
+
+    failures
+}
+
+#[test]
+#[ignore = "needs the test ROMs in tests/roms and reference images in tests/expected, see tests/roms/README.md"]
+fn test_roms_match_expected_displays() {
+    let failures: Vec<String> = test_roms().iter().flat_map(check_rom).collect();
+    assert!(failures.is_empty(), "conformance failures:\n{}", failures.join("\n"));
+}
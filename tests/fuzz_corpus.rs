@@ -0,0 +1,31 @@
+//! Replays the fuzz corpus, including any crashes added to it, so they stay fixed without
+//! needing a nightly compiler and cargo-fuzz.
+
+#[path = "../fuzz/src/lib.rs"]
+mod fuzz;
+
+use std::fs;
+use std::panic;
+use std::path::Path;
+
+/// Run a fuzz target on every input in its corpus, naming the input that made it panic
+fn replay(target: &str, check: fn(&[u8])) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz").join("corpus").join(target);
+    for entry in fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let data = fs::read(&path).unwrap();
+        if panic::catch_unwind(|| check(&data)).is_err() {
+            panic!("{} panicked on {}", target, path.display());
+        }
+    }
+}
+
+#[test]
+fn machine_step_corpus() {
+    replay("machine_step", fuzz::machine_step);
+}
+
+#[test]
+fn load_state_corpus() {
+    replay("load_state", fuzz::load_state);
+}